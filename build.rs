@@ -4,6 +4,8 @@ extern crate cc;
 
 // Note! requires some extra build flags and extra logic to work on windows
 fn compile_soem(){
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=vendor/soem");
     cc::Build::new()
         .files([
             "vendor/soem/src/ec_base.c",
@@ -45,7 +47,21 @@ fn regen_bindings() {
 
 }
 
+/* Embed an ENI file given by SOEM_ENI, see eni::EMBEDDED_ENI */
+fn embed_eni() {
+    println!("cargo:rustc-check-cfg=cfg(soem_eni)");
+    println!("cargo:rerun-if-env-changed=SOEM_ENI");
+    if let Ok(path) = std::env::var("SOEM_ENI") {
+        let path = std::fs::canonicalize(&path).expect("SOEM_ENI does not point to a file");
+        println!("cargo:rerun-if-changed={}", path.display());
+        println!("cargo:rustc-env=SOEM_ENI_PATH={}", path.display());
+        println!("cargo:rustc-cfg=soem_eni");
+    }
+}
+
 fn main() {
+    embed_eni();
+
     let lib_enabled = std::env::var("CARGO_FEATURE_LIB").is_ok();
     let regen_enabled = std::env::var("CARGO_FEATURE_REGEN_BINDINGS").is_ok();
 
@@ -58,6 +74,5 @@ fn main() {
 
     if lib_enabled {
         compile_soem();
-        return;
     }
 }
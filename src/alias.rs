@@ -0,0 +1,194 @@
+//! Station alias addressing. The alias is stored in SII word 4 and loaded into
+//! register 0x0012 at power-up, so unlike the ring position it stays with the
+//! device when terminals are inserted or removed.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::bindings::*;
+use crate::datagram::DatagramError;
+use crate::registers::{SiiStatus, StationAddress, StationAlias};
+use crate::sii::{SII_ALIAS_WORD, SII_CHECKSUM_WORD, config_checksum};
+use crate::simple_ng::Fieldbus;
+
+/// SII control command (0x0502 bits 8-10) reloading the ESC configuration area.
+const SII_RELOAD: u16 = 0x0400;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AliasError {
+    /// Alias 0 means "no alias" and can not be used for addressing.
+    Invalid(u16),
+    /// Writing an SII word failed.
+    Sii {
+        word: u16,
+    },
+    /// An SII word read back differs from what was written.
+    Verify {
+        word: u16,
+        written: u16,
+        read: u16,
+    },
+    Datagram(DatagramError),
+}
+
+impl fmt::Display for AliasError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AliasError::Invalid(alias) => write!(f, "invalid station alias {}", alias),
+            AliasError::Sii { word } => write!(f, "failed to write SII word {:#06x}", word),
+            AliasError::Verify {
+                word,
+                written,
+                read,
+            } => write!(
+                f,
+                "SII word {:#06x} reads back {:#06x} after writing {:#06x}",
+                word, read, written
+            ),
+            AliasError::Datagram(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for AliasError {}
+
+impl From<DatagramError> for AliasError {
+    fn from(e: DatagramError) -> Self {
+        AliasError::Datagram(e)
+    }
+}
+
+impl Fieldbus {
+    /// Station alias of a slave as read during configuration, 0 if none.
+    pub fn alias(&self, slave: u16) -> u16 {
+        self.context.slavelist[slave as usize].aliasadr
+    }
+
+    /// Slave number of the slave with `alias`.
+    pub fn slave_by_alias(&self, alias: u16) -> Option<u16> {
+        if alias == 0 {
+            return None;
+        }
+        (1..=self.context.slavecount.max(0) as u16)
+            .find(|&slave| self.context.slavelist[slave as usize].aliasadr == alias)
+    }
+
+    fn sii_word(&mut self, slave: u16, word: u16) -> u16 {
+        let context = &mut *self.context as *mut ecx_contextt;
+        (unsafe { ecx_readeeprom(context, slave, word, EC_TIMEOUTEEP as i32) } & 0xFFFF) as u16
+    }
+
+    fn write_sii_word(&mut self, slave: u16, word: u16, value: u16) -> Result<(), AliasError> {
+        let context = &mut *self.context as *mut ecx_contextt;
+        if unsafe { ecx_writeeeprom(context, slave, word, value, EC_TIMEOUTEEP as i32) } <= 0 {
+            return Err(AliasError::Sii { word });
+        }
+        let read = self.sii_word(slave, word);
+        if read != value {
+            return Err(AliasError::Verify {
+                word,
+                written: value,
+                read,
+            });
+        }
+        Ok(())
+    }
+
+    /// Store `alias` in the SII of a slave, updating the config area checksum, and
+    /// have the ESC reload its configuration area so the alias register (0x0012,
+    /// read only for the master) takes the new alias. Returns whether it did; not
+    /// every ESC reloads the alias, those need a power cycle before it is used.
+    pub fn program_alias(&mut self, slave: u16, alias: u16) -> Result<bool, AliasError> {
+        if alias == 0 {
+            return Err(AliasError::Invalid(alias));
+        }
+        let pdi = self.context.slavelist[slave as usize].eep_pdi != 0;
+
+        let mut words = [0u16; 7];
+        for (n, word) in words.iter_mut().enumerate() {
+            *word = self.sii_word(slave, n as u16);
+        }
+        words[SII_ALIAS_WORD as usize] = alias;
+        // the checksum is the low byte of word 7, the high byte is reserved
+        let checksum =
+            (self.sii_word(slave, SII_CHECKSUM_WORD) & 0xFF00) | config_checksum(&words) as u16;
+        let result = self
+            .write_sii_word(slave, SII_ALIAS_WORD, alias)
+            .and_then(|_| self.write_sii_word(slave, SII_CHECKSUM_WORD, checksum));
+
+        // the SII holds the alias now, failing to load it is not an error
+        let loaded = result.is_ok() && self.reload_sii(slave, alias);
+        if pdi {
+            unsafe { ecx_eeprom2pdi(&mut *self.context, slave) };
+        }
+        result?;
+
+        if loaded {
+            self.context.slavelist[slave as usize].aliasadr = alias;
+        }
+        Ok(loaded)
+    }
+
+    /// Reload the ESC configuration area from the SII and check that the alias
+    /// register holds `alias` afterwards.
+    fn reload_sii(&mut self, slave: u16, alias: u16) -> bool {
+        let station = self.context.slavelist[slave as usize].configadr;
+        if self
+            .fpwr(station, ECT_REG_EEPCTL as u16, SII_RELOAD)
+            .is_err()
+        {
+            return false;
+        }
+        let deadline = Instant::now() + Duration::from_micros(EC_TIMEOUTEEP as u64);
+        loop {
+            match self.read_register::<SiiStatus>(slave) {
+                Ok(status) if status.command_error || status.checksum_error => return false,
+                Ok(status) if !status.busy => break,
+                _ if Instant::now() > deadline => return false,
+                _ => {}
+            }
+        }
+        matches!(self.read_register(slave), Ok(StationAlias(a)) if a == alias)
+    }
+
+    /// Use the alias of each slave that has a unique one as its configured station
+    /// address, so station addresses stay stable across topology changes. Takes
+    /// effect in `start`.
+    pub fn set_alias_addressing(&mut self, enabled: bool) {
+        self.alias_addressing = enabled;
+    }
+
+    /// Give slaves with a unique alias that alias as station address. Slaves whose
+    /// alias would collide with another station address keep theirs.
+    pub(crate) fn apply_alias_addressing(&mut self) {
+        if !self.alias_addressing {
+            return;
+        }
+        let count = self.context.slavecount.max(0) as u16;
+        for slave in 1..=count {
+            let alias = self.context.slavelist[slave as usize].aliasadr;
+            if alias == 0 || self.context.slavelist[slave as usize].configadr == alias {
+                continue;
+            }
+            let taken = (1..=count).any(|other| {
+                other != slave
+                    && (self.context.slavelist[other as usize].aliasadr == alias
+                        || self.context.slavelist[other as usize].configadr == alias)
+            });
+            if taken {
+                log::warn!(
+                    "Slave {} keeps its station address, alias {} is not unique",
+                    slave,
+                    alias
+                );
+                continue;
+            }
+            match self.write_register(slave, &StationAddress(alias)) {
+                Ok(()) => self.context.slavelist[slave as usize].configadr = alias,
+                Err(e) => log::warn!("Failed to set station address of slave {}: {}", slave, e),
+            }
+        }
+    }
+}
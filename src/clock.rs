@@ -0,0 +1,270 @@
+//! Synchronisation of the master cycle with the distributed clocks. Either the
+//! master shifts its cycle onto the bus time with the PI controller of SOEM's
+//! `ec_sample` (`ec_sync`), or the bus is steered onto the master by writing the
+//! master time to the reference clock, whose drift compensation then follows it.
+
+use std::time::Instant;
+
+use crate::registers::DcSystemTime;
+use crate::simple_ng::Fieldbus;
+
+/// Weight of a new sample in the averaged drift.
+const DRIFT_SMOOTHING: f64 = 1.0 / 16.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSyncMode {
+    /// The master adjusts its cycle start to the bus time (`context.DCtime`).
+    MasterFollowsBus,
+    /// The reference clock is adjusted to the master time every cycle.
+    BusFollowsMaster,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSync {
+    pub mode: ClockSyncMode,
+    /// Where in the bus time cycle frames are sent, in ns. Usually half the SYNC0
+    /// cycle, so outputs arrive well before the sync pulse.
+    pub shift: i64,
+    /// Gains of the PI controller used by `MasterFollowsBus`. In
+    /// `BusFollowsMaster` the slaves' own drift compensation does the control.
+    pub p_gain: f64,
+    pub i_gain: f64,
+}
+
+impl ClockSync {
+    /// Gains and shift as in `ec_sample`, for a 1 ms cycle.
+    pub fn new(mode: ClockSyncMode) -> Self {
+        ClockSync {
+            mode,
+            shift: 500_000,
+            p_gain: 0.01,
+            i_gain: 0.00002,
+        }
+    }
+
+    pub fn shift(mut self, shift: i64) -> Self {
+        self.shift = shift;
+        self
+    }
+
+    pub fn gains(mut self, p_gain: f64, i_gain: f64) -> Self {
+        self.p_gain = p_gain;
+        self.i_gain = i_gain;
+        self
+    }
+}
+
+/// State of the clock synchronisation after the last cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClockTelemetry {
+    /// Bus time at the frame minus where it should be, in ns. With
+    /// `MasterFollowsBus` the phase error in the cycle, with `BusFollowsMaster` the
+    /// difference to the master time.
+    pub offset: i64,
+    /// Rate of the bus time against the master's monotonic clock in ppm, averaged.
+    pub drift: f64,
+    /// Time added to the next cycle period, in ns.
+    pub correction: i64,
+    /// Cycles the controller has run.
+    pub cycles: u64,
+}
+
+pub(crate) struct ClockController {
+    sync: ClockSync,
+    integral: i64,
+    /// Master and bus time of the first cycle, the origin of the master time.
+    origin: Option<(Instant, i64)>,
+    last: Option<(Instant, i64)>,
+    telemetry: ClockTelemetry,
+}
+
+impl ClockController {
+    /// The `ec_sync` controller: the phase error of the bus time within the cycle,
+    /// wrapped to +-cycle/2, drives the correction of the next period.
+    fn follow_bus(&mut self, dc_time: i64, cycle: i64) -> i64 {
+        let mut delta = (dc_time - self.sync.shift) % cycle;
+        if delta > cycle / 2 {
+            delta -= cycle;
+        }
+        let error = -delta;
+        self.integral += error;
+        self.telemetry.offset = delta;
+        (error as f64 * self.sync.p_gain + self.integral as f64 * self.sync.i_gain) as i64
+    }
+
+    fn measure_drift(&mut self, now: Instant, dc_time: i64) {
+        if let Some((instant, time)) = self.last {
+            let master = now.duration_since(instant).as_nanos() as i64;
+            if master > 0 {
+                let ppm = (dc_time - time - master) as f64 / master as f64 * 1e6;
+                self.telemetry.drift += (ppm - self.telemetry.drift) * DRIFT_SMOOTHING;
+            }
+        }
+        self.last = Some((now, dc_time));
+    }
+}
+
+impl Fieldbus {
+    /// Synchronise the cycle of `run_cyclic` with the distributed clocks, or stop
+    /// doing so with `None`. Needs slaves with DC in the process data group.
+    pub fn set_clock_sync(&mut self, sync: Option<ClockSync>) {
+        self.clock = sync.map(|sync| ClockController {
+            sync,
+            integral: 0,
+            origin: None,
+            last: None,
+            telemetry: ClockTelemetry::default(),
+        });
+    }
+
+    pub fn clock_telemetry(&self) -> Option<ClockTelemetry> {
+        self.clock.as_ref().map(|c| c.telemetry)
+    }
+
+    /// Run the clock synchronisation after a process data exchange with a cycle of
+    /// `cycle` ns. Returns the correction to add to the next period.
+    pub(crate) fn sync_clock(&mut self, cycle: i64) -> i64 {
+        let group = &self.context.grouplist[self.group as usize];
+        let reference = group.DCnext;
+        if group.hasdc == 0 || cycle <= 0 {
+            return 0;
+        }
+        let dc_time = self.context.DCtime;
+        let Some(clock) = self.clock.as_mut() else {
+            return 0;
+        };
+        let now = Instant::now();
+        clock.measure_drift(now, dc_time);
+        clock.telemetry.cycles += 1;
+        let correction = match clock.sync.mode {
+            ClockSyncMode::MasterFollowsBus => clock.follow_bus(dc_time, cycle),
+            ClockSyncMode::BusFollowsMaster => {
+                // the master time starts at the bus time, so the bus is not stepped
+                let (instant, time) = *clock.origin.get_or_insert((now, dc_time));
+                let master_time = time + now.duration_since(instant).as_nanos() as i64;
+                clock.telemetry.offset = dc_time - master_time;
+                if let Err(e) = self.write_register(reference, &DcSystemTime(master_time as u64)) {
+                    log::warn!("Failed to adjust the reference clock: {}", e);
+                }
+                0
+            }
+        };
+        if let Some(clock) = self.clock.as_mut() {
+            clock.telemetry.correction = correction;
+        }
+        correction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const CYCLE: i64 = 1_000_000;
+
+    fn controller() -> ClockController {
+        ClockController {
+            sync: ClockSync::new(ClockSyncMode::MasterFollowsBus),
+            integral: 0,
+            origin: None,
+            last: None,
+            telemetry: ClockTelemetry::default(),
+        }
+    }
+
+    /// `ec_sync` of SOEM's `ec_sample`.
+    fn ec_sync(reftime: i64, cycletime: i64, integral: &mut i64) -> i64 {
+        let mut delta = (reftime - 500_000) % cycletime;
+        if delta > cycletime / 2 {
+            delta -= cycletime;
+        }
+        let timeerror = -delta;
+        *integral += timeerror;
+        (timeerror as f64 * 0.01 + *integral as f64 * 0.00002) as i64
+    }
+
+    #[test]
+    fn follow_bus_matches_ec_sync() {
+        let mut clock = controller();
+        let mut integral = 0;
+        let base = 1_000 * CYCLE + 500_000;
+        for delta in [
+            0,
+            1000,
+            -1000,
+            CYCLE / 2 - 1,
+            CYCLE / 2,
+            CYCLE / 2 + 1,
+            -CYCLE / 2,
+            CYCLE - 1,
+            7 * CYCLE + 123_456,
+        ] {
+            let dc_time = base + delta;
+            assert_eq!(
+                clock.follow_bus(dc_time, CYCLE),
+                ec_sync(dc_time, CYCLE, &mut integral),
+                "delta {}",
+                delta
+            );
+        }
+        assert_eq!(clock.integral, integral);
+    }
+
+    #[test]
+    fn follow_bus_wraps_phase() {
+        let base = 1_000 * CYCLE + 500_000;
+        // frames late against the sync point shorten the next period
+        let mut clock = controller();
+        assert_eq!(clock.follow_bus(base + 1000, CYCLE), -10);
+        assert_eq!(clock.telemetry.offset, 1000);
+        // up to half a cycle late counts as late, beyond as early
+        let mut clock = controller();
+        assert!(clock.follow_bus(base + CYCLE / 2, CYCLE) < 0);
+        assert_eq!(clock.telemetry.offset, CYCLE / 2);
+        let mut clock = controller();
+        assert!(clock.follow_bus(base + CYCLE / 2 + 1, CYCLE) > 0);
+        assert_eq!(clock.telemetry.offset, -(CYCLE / 2 - 1));
+        let mut clock = controller();
+        assert_eq!(clock.follow_bus(base + CYCLE - 1000, CYCLE), 10);
+        assert_eq!(clock.telemetry.offset, -1000);
+    }
+
+    #[test]
+    fn integral_accumulates() {
+        let mut clock = controller();
+        let dc_time = 1_000 * CYCLE + 500_000 + 10_000;
+        let first = clock.follow_bus(dc_time, CYCLE);
+        let mut last = first;
+        for _ in 0..100 {
+            last = clock.follow_bus(dc_time, CYCLE);
+        }
+        assert!(last < first);
+        assert_eq!(clock.integral, -101 * 10_000);
+    }
+
+    #[test]
+    fn drift() {
+        let mut clock = controller();
+        let start = Instant::now();
+        clock.measure_drift(start, 0);
+        assert_eq!(clock.telemetry.drift, 0.0);
+        // bus time runs 100 ppm fast
+        let now = start + Duration::from_millis(1);
+        clock.measure_drift(now, 1_000_100);
+        assert!((clock.telemetry.drift - 100.0 * DRIFT_SMOOTHING).abs() < 1e-9);
+        // no master time passed: sample ignored
+        clock.measure_drift(now, 2_000_000);
+        assert!((clock.telemetry.drift - 100.0 * DRIFT_SMOOTHING).abs() < 1e-9);
+        let mut clock = controller();
+        for n in 0..=1000u32 {
+            let master = n as u64 * 1_000_000;
+            clock.measure_drift(
+                start + Duration::from_nanos(master),
+                (master * 999_950 / 1_000_000) as i64,
+            );
+        }
+        assert!((clock.telemetry.drift + 50.0).abs() < 0.01);
+    }
+}
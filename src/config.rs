@@ -0,0 +1,717 @@
+//! The expected bus: an ordered list of slave identities that is compared with
+//! what `ecx_config_init` found, so a swapped or missing terminal stops the bus
+//! before it is mapped.
+
+use std::{ffi::CStr, fmt};
+
+use crate::dc::DcMode;
+use crate::eni::Eni;
+use crate::simple_ng::Fieldbus;
+use crate::watchdog::WatchdogConfig;
+
+/// Process data group of the mandatory slaves taking part when some are excluded
+/// or hot-connect groups are present.
+pub(crate) const ACTIVE_GROUP: u8 = 1;
+/// Group of excluded slaves. Only `ACTIVE_GROUP` and `HOT_CONNECT_GROUP` are
+/// mapped then, so they stay in PRE_OP. SOEM is built with three groups
+/// (`EC_MAXGROUP`).
+pub(crate) const EXCLUDED_GROUP: u8 = 0;
+/// Group shared by the slaves of all hot-connect groups present at startup.
+pub(crate) const HOT_CONNECT_GROUP: u8 = 2;
+
+/// Identity of an expected slave. Revision, serial and alias are only checked if
+/// set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlaveConfig {
+    pub name: Option<String>,
+    pub vendor_id: u32,
+    pub product_code: u32,
+    pub revision: Option<u32>,
+    pub serial: Option<u32>,
+    pub alias: Option<u16>,
+    /// Sync mode applied by `start`.
+    pub dc: Option<DcMode>,
+    /// Watchdogs of this slave, instead of those of the bus.
+    pub watchdog: Option<WatchdogConfig>,
+}
+
+impl SlaveConfig {
+    pub fn new(vendor_id: u32, product_code: u32) -> Self {
+        SlaveConfig {
+            name: None,
+            vendor_id,
+            product_code,
+            revision: None,
+            serial: None,
+            alias: None,
+            dc: None,
+            watchdog: None,
+        }
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn revision(mut self, revision: u32) -> Self {
+        self.revision = Some(revision);
+        self
+    }
+
+    pub fn serial(mut self, serial: u32) -> Self {
+        self.serial = Some(serial);
+        self
+    }
+
+    pub fn alias(mut self, alias: u16) -> Self {
+        self.alias = Some(alias);
+        self
+    }
+
+    pub fn dc(mut self, mode: DcMode) -> Self {
+        self.dc = Some(mode);
+        self
+    }
+
+    pub fn watchdog(mut self, watchdog: WatchdogConfig) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+}
+
+impl fmt::Display for SlaveConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{} ", name)?;
+        }
+        write!(
+            f,
+            "(vendor {:#010x}, product {:#010x})",
+            self.vendor_id, self.product_code
+        )
+    }
+}
+
+/// What to do when the bus does not match the configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    /// Refuse to start.
+    #[default]
+    Abort,
+    /// Run with the slaves before the first mismatch.
+    Subset,
+    /// Run with every slave that matches the expected slave it is paired with.
+    MatchingOnly,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BusConfig {
+    /// Expected slaves in ring order, the first at position 1.
+    pub slaves: Vec<SlaveConfig>,
+    pub policy: MismatchPolicy,
+    /// Watchdogs of all slaves, written in PRE_OP.
+    pub watchdog: Option<WatchdogConfig>,
+}
+
+impl BusConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn slave(mut self, slave: SlaveConfig) -> Self {
+        self.slaves.push(slave);
+        self
+    }
+
+    pub fn on_mismatch(mut self, policy: MismatchPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn watchdog(mut self, watchdog: WatchdogConfig) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Expect the slaves of an ENI, including their revisions and any serial
+    /// numbers and aliases it lists.
+    pub fn from_eni(eni: &Eni) -> Self {
+        let slaves = eni
+            .slaves
+            .iter()
+            .map(|s| SlaveConfig {
+                name: s.name.clone(),
+                vendor_id: s.vendor_id,
+                product_code: s.product_code,
+                revision: Some(s.revision),
+                serial: s.serial,
+                alias: s.alias,
+                dc: None,
+                watchdog: None,
+            })
+            .collect();
+        BusConfig {
+            slaves,
+            policy: MismatchPolicy::Abort,
+            watchdog: None,
+        }
+    }
+}
+
+/// Identity of a slave found on the bus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlaveIdentity {
+    pub name: String,
+    pub vendor_id: u32,
+    pub product_code: u32,
+    pub revision: u32,
+    pub serial: u32,
+    pub alias: u16,
+}
+
+impl fmt::Display for SlaveIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (vendor {:#010x}, product {:#010x})",
+            self.name, self.vendor_id, self.product_code
+        )
+    }
+}
+
+/// A difference between the configured and the found bus. Positions are 1 based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Missing {
+        position: u16,
+        expected: SlaveConfig,
+    },
+    Extra {
+        position: u16,
+        found: SlaveIdentity,
+    },
+    WrongProduct {
+        position: u16,
+        expected: SlaveConfig,
+        found: SlaveIdentity,
+    },
+    WrongRevision {
+        position: u16,
+        expected: u32,
+        found: u32,
+    },
+    WrongSerial {
+        position: u16,
+        expected: u32,
+        found: u32,
+    },
+    WrongAlias {
+        position: u16,
+        expected: u16,
+        found: u16,
+    },
+}
+
+impl Mismatch {
+    pub fn position(&self) -> u16 {
+        match self {
+            Mismatch::Missing { position, .. }
+            | Mismatch::Extra { position, .. }
+            | Mismatch::WrongProduct { position, .. }
+            | Mismatch::WrongRevision { position, .. }
+            | Mismatch::WrongSerial { position, .. }
+            | Mismatch::WrongAlias { position, .. } => *position,
+        }
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Missing { position, expected } => {
+                write!(f, "position {}: missing {}", position, expected)
+            }
+            Mismatch::Extra { position, found } => {
+                write!(f, "position {}: unexpected {}", position, found)
+            }
+            Mismatch::WrongProduct {
+                position,
+                expected,
+                found,
+            } => write!(
+                f,
+                "position {}: expected {}, found {}",
+                position, expected, found
+            ),
+            Mismatch::WrongRevision {
+                position,
+                expected,
+                found,
+            } => write!(
+                f,
+                "position {}: expected revision {:#010x}, found {:#010x}",
+                position, expected, found
+            ),
+            Mismatch::WrongSerial {
+                position,
+                expected,
+                found,
+            } => write!(
+                f,
+                "position {}: expected serial {}, found {}",
+                position, expected, found
+            ),
+            Mismatch::WrongAlias {
+                position,
+                expected,
+                found,
+            } => write!(
+                f,
+                "position {}: expected alias {}, found {}",
+                position, expected, found
+            ),
+        }
+    }
+}
+
+fn same_product(expected: &SlaveConfig, found: &SlaveIdentity) -> bool {
+    expected.vendor_id == found.vendor_id && expected.product_code == found.product_code
+}
+
+/// Pair the expected and the found slaves in order with the fewest slaves missing,
+/// extra or of another product (an edit distance on vendor and product). Returns
+/// indices into both lists; a slave of another product in the place of an
+/// expected one is paired with it.
+fn align(expected: &[SlaveConfig], found: &[SlaveIdentity]) -> Vec<(Option<usize>, Option<usize>)> {
+    let (n, m) = (expected.len(), found.len());
+    // cost[i][j]: edits to turn expected[i..] into found[j..]
+    let mut cost = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..=n).rev() {
+        for j in (0..=m).rev() {
+            cost[i][j] = if i == n {
+                m - j
+            } else if j == m {
+                n - i
+            } else {
+                let pair = !same_product(&expected[i], &found[j]) as usize;
+                (cost[i + 1][j + 1] + pair)
+                    .min(cost[i + 1][j] + 1)
+                    .min(cost[i][j + 1] + 1)
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut steps = Vec::new();
+    while i < n || j < m {
+        if i < n && j < m {
+            let pair = !same_product(&expected[i], &found[j]) as usize;
+            if cost[i][j] == cost[i + 1][j + 1] + pair {
+                steps.push((Some(i), Some(j)));
+                i += 1;
+                j += 1;
+                continue;
+            }
+        }
+        if i < n && (j == m || cost[i][j] == cost[i + 1][j] + 1) {
+            steps.push((Some(i), None));
+            i += 1;
+        } else {
+            steps.push((None, Some(j)));
+            j += 1;
+        }
+    }
+    steps
+}
+
+pub(crate) fn compare(
+    position: u16,
+    expected: &SlaveConfig,
+    found: &SlaveIdentity,
+) -> Vec<Mismatch> {
+    if !same_product(expected, found) {
+        return vec![Mismatch::WrongProduct {
+            position,
+            expected: expected.clone(),
+            found: found.clone(),
+        }];
+    }
+    let mut mismatches = Vec::new();
+    if let Some(revision) = expected.revision.filter(|&r| r != found.revision) {
+        mismatches.push(Mismatch::WrongRevision {
+            position,
+            expected: revision,
+            found: found.revision,
+        });
+    }
+    if let Some(serial) = expected.serial.filter(|&s| s != found.serial) {
+        mismatches.push(Mismatch::WrongSerial {
+            position,
+            expected: serial,
+            found: found.serial,
+        });
+    }
+    if let Some(alias) = expected.alias.filter(|&a| a != found.alias) {
+        mismatches.push(Mismatch::WrongAlias {
+            position,
+            expected: alias,
+            found: found.alias,
+        });
+    }
+    mismatches
+}
+
+impl Fieldbus {
+    /// Check the bus against `config` in `start`, right after the slaves are
+    /// found and before anything is mapped.
+    pub fn set_bus_config(&mut self, config: BusConfig) {
+        self.bus_config = Some(config);
+    }
+
+    /// Slaves left out of process data by the mismatch policy. They stay in
+    /// PRE_OP.
+    pub fn excluded_slaves(&self) -> &[u16] {
+        &self.excluded
+    }
+
+    /// Differences between the bus and the configuration found by the last
+    /// `start`.
+    pub fn bus_mismatches(&self) -> &[Mismatch] {
+        &self.mismatches
+    }
+
+    pub fn slave_identity(&self, slave: u16) -> SlaveIdentity {
+        let s = &self.context.slavelist[slave as usize];
+        SlaveIdentity {
+            name: unsafe { CStr::from_ptr(s.name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned(),
+            vendor_id: s.eep_man,
+            product_code: s.eep_id,
+            revision: s.eep_rev,
+            serial: s.eep_ser,
+            alias: s.aliasadr,
+        }
+    }
+
+    /// Compare the slaves found by `ecx_config_init` with `config`. The lists are
+    /// aligned first, so a slave missing from or added to the middle of the bus
+    /// is reported as such and the slaves after it still match. A missing slave
+    /// gets the position after the found slave before it. Slaves of hot-connect
+    /// groups are skipped, so `config` lists the mandatory part of the bus only.
+    pub fn verify_bus(&self, config: &BusConfig) -> Vec<Mismatch> {
+        let (found, identities) = self.mandatory_identities();
+        let mut mismatches = Vec::new();
+        let mut next = 1;
+        for step in align(&config.slaves, &identities) {
+            match step {
+                (Some(e), Some(f)) => {
+                    mismatches.extend(compare(found[f], &config.slaves[e], &identities[f]));
+                    next = found[f] + 1;
+                }
+                (Some(e), None) => {
+                    mismatches.push(Mismatch::Missing {
+                        position: next,
+                        expected: config.slaves[e].clone(),
+                    });
+                    next += 1;
+                }
+                (None, Some(f)) => {
+                    mismatches.push(Mismatch::Extra {
+                        position: found[f],
+                        found: identities[f].clone(),
+                    });
+                    next = found[f] + 1;
+                }
+                (None, None) => unreachable!(),
+            }
+        }
+        mismatches
+    }
+
+    pub(crate) fn mandatory_slaves(&self) -> Vec<u16> {
+        (1..=self.context.slavecount.max(0) as u16)
+            .filter(|&slave| !self.is_hot_connect_slave(slave))
+            .collect()
+    }
+
+    fn mandatory_identities(&self) -> (Vec<u16>, Vec<SlaveIdentity>) {
+        let found = self.mandatory_slaves();
+        let identities = found
+            .iter()
+            .map(|&slave| self.slave_identity(slave))
+            .collect();
+        (found, identities)
+    }
+
+    /// The configuration of each slave: the bus configuration for the mandatory
+    /// slaves of the expected product and the groups for hot-connect slaves.
+    pub(crate) fn slave_configs(&self) -> Vec<(u16, &SlaveConfig)> {
+        let mut configs: Vec<(u16, &SlaveConfig)> = match &self.bus_config {
+            Some(config) => {
+                let (found, identities) = self.mandatory_identities();
+                align(&config.slaves, &identities)
+                    .into_iter()
+                    .filter_map(|step| match step {
+                        (Some(e), Some(f)) if same_product(&config.slaves[e], &identities[f]) => {
+                            Some((found[f], &config.slaves[e]))
+                        }
+                        _ => None,
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        configs.extend(self.hot_connect_configs());
+        configs
+    }
+
+    /// Verify the bus and apply the mismatch policy. Returns false if the bus must
+    /// not start.
+    pub(crate) fn apply_bus_config(&mut self) -> bool {
+        self.mismatches.clear();
+        let Some(config) = &self.bus_config else {
+            return true;
+        };
+        let mismatches = self.verify_bus(config);
+        for mismatch in &mismatches {
+            log::warn!("Bus mismatch at {}", mismatch);
+        }
+        let found = self.mandatory_slaves();
+        // a missing slave takes the position of no found one
+        let matches = |slave: &u16| {
+            mismatches
+                .iter()
+                .all(|m| matches!(m, Mismatch::Missing { .. }) || m.position() != *slave)
+        };
+        let first = mismatches.first().map(Mismatch::position);
+        let included: Vec<u16> = match config.policy {
+            MismatchPolicy::Abort if mismatches.is_empty() => found.clone(),
+            MismatchPolicy::Abort => Vec::new(),
+            MismatchPolicy::Subset => found
+                .iter()
+                .copied()
+                .filter(|&slave| first.is_none_or(|first| slave < first))
+                .collect(),
+            MismatchPolicy::MatchingOnly => found.iter().copied().filter(matches).collect(),
+        };
+        self.mismatches = mismatches;
+        if included.is_empty() {
+            log::error!("Bus does not match the configuration");
+            return false;
+        }
+        self.excluded
+            .extend(found.into_iter().filter(|p| !included.contains(p)));
+        true
+    }
+
+    /// With slaves excluded or hot-connect groups present, put the mandatory slaves
+    /// into `ACTIVE_GROUP` and the hot-connect slaves into `HOT_CONNECT_GROUP`.
+    pub(crate) fn assign_groups(&mut self) {
+        let hot_connect: Vec<u16> = self
+            .hot_connect_configs()
+            .into_iter()
+            .map(|(slave, _)| slave)
+            .collect();
+        if self.excluded.is_empty() && hot_connect.is_empty() {
+            self.group = 0;
+            return;
+        }
+        if !self.excluded.is_empty() {
+            self.excluded.sort_unstable();
+            log::warn!("Excluding slaves {:?} from process data", self.excluded);
+        }
+        for slave in 1..=self.context.slavecount.max(0) as u16 {
+            self.context.slavelist[slave as usize].group = if self.excluded.contains(&slave) {
+                EXCLUDED_GROUP
+            } else if hot_connect.contains(&slave) {
+                HOT_CONNECT_GROUP
+            } else {
+                ACTIVE_GROUP
+            };
+        }
+        self.group = ACTIVE_GROUP;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    const EK1100: u32 = 0x044c2c52;
+    const EL1008: u32 = 0x03f03052;
+    const EL2008: u32 = 0x07d83052;
+    const EL3102: u32 = 0x0c1e3052;
+
+    fn bus(products: &[u32]) -> Fieldbus {
+        let mut bus = Fieldbus::new(CString::new("test").unwrap());
+        bus.context.slavecount = products.len() as i32;
+        for (n, &product) in products.iter().enumerate() {
+            let s = &mut bus.context.slavelist[n + 1];
+            s.eep_man = 2;
+            s.eep_id = product;
+        }
+        bus
+    }
+
+    fn config(products: &[u32], policy: MismatchPolicy) -> BusConfig {
+        products
+            .iter()
+            .fold(BusConfig::new().on_mismatch(policy), |config, &product| {
+                config.slave(SlaveConfig::new(2, product))
+            })
+    }
+
+    fn kinds(mismatches: &[Mismatch]) -> Vec<(&'static str, u16)> {
+        mismatches
+            .iter()
+            .map(|m| {
+                let kind = match m {
+                    Mismatch::Missing { .. } => "missing",
+                    Mismatch::Extra { .. } => "extra",
+                    Mismatch::WrongProduct { .. } => "product",
+                    Mismatch::WrongRevision { .. } => "revision",
+                    Mismatch::WrongSerial { .. } => "serial",
+                    Mismatch::WrongAlias { .. } => "alias",
+                };
+                (kind, m.position())
+            })
+            .collect()
+    }
+
+    /// Apply `policy` and return the included slaves, or `None` if the bus must
+    /// not start.
+    fn apply(bus: &mut Fieldbus, expected: &[u32], policy: MismatchPolicy) -> Option<Vec<u16>> {
+        bus.excluded.clear();
+        bus.set_bus_config(config(expected, policy));
+        if !bus.apply_bus_config() {
+            return None;
+        }
+        let included = bus
+            .mandatory_slaves()
+            .into_iter()
+            .filter(|slave| !bus.excluded.contains(slave))
+            .collect();
+        Some(included)
+    }
+
+    #[test]
+    fn matching_bus() {
+        let products = [EK1100, EL1008, EL2008];
+        let bus = bus(&products);
+        assert!(
+            bus.verify_bus(&config(&products, MismatchPolicy::Abort))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn missing_in_the_middle() {
+        let bus = bus(&[EK1100, EL2008, EL3102]);
+        let mismatches = bus.verify_bus(&config(
+            &[EK1100, EL1008, EL2008, EL3102],
+            MismatchPolicy::Abort,
+        ));
+        assert_eq!(kinds(&mismatches), [("missing", 2)]);
+        assert!(matches!(
+            &mismatches[0],
+            Mismatch::Missing { expected, .. } if expected.product_code == EL1008
+        ));
+    }
+
+    #[test]
+    fn extra_in_the_middle() {
+        let bus = bus(&[EK1100, EL3102, EL1008, EL2008]);
+        let mismatches = bus.verify_bus(&config(&[EK1100, EL1008, EL2008], MismatchPolicy::Abort));
+        assert_eq!(kinds(&mismatches), [("extra", 2)]);
+    }
+
+    #[test]
+    fn replaced_and_tail() {
+        let bus = bus(&[EK1100, EL3102, EL2008]);
+        let expected = [EK1100, EL1008, EL2008, EL1008, EL1008];
+        let mismatches = bus.verify_bus(&config(&expected, MismatchPolicy::Abort));
+        assert_eq!(
+            kinds(&mismatches),
+            [("product", 2), ("missing", 4), ("missing", 5)]
+        );
+
+        let bus = self::bus(&[EK1100, EL1008, EL2008, EL3102]);
+        let mismatches = bus.verify_bus(&config(&[EK1100, EL1008], MismatchPolicy::Abort));
+        assert_eq!(kinds(&mismatches), [("extra", 3), ("extra", 4)]);
+    }
+
+    #[test]
+    fn identity_details() {
+        let mut bus = bus(&[EK1100, EL1008]);
+        bus.context.slavelist[2].eep_rev = 0x00110000;
+        bus.context.slavelist[2].aliasadr = 3;
+        let config = BusConfig::new().slave(SlaveConfig::new(2, EK1100)).slave(
+            SlaveConfig::new(2, EL1008)
+                .revision(0x00120000)
+                .serial(0)
+                .alias(4),
+        );
+        assert_eq!(
+            kinds(&bus.verify_bus(&config)),
+            [("revision", 2), ("alias", 2)]
+        );
+    }
+
+    #[test]
+    fn abort_policy() {
+        let mut bus = bus(&[EK1100, EL2008, EL3102]);
+        let expected = [EK1100, EL1008, EL2008, EL3102];
+        assert_eq!(apply(&mut bus, &expected, MismatchPolicy::Abort), None);
+        assert_eq!(kinds(bus.bus_mismatches()), [("missing", 2)]);
+        let expected = [EK1100, EL2008, EL3102];
+        assert_eq!(
+            apply(&mut bus, &expected, MismatchPolicy::Abort),
+            Some(vec![1, 2, 3])
+        );
+        assert!(bus.bus_mismatches().is_empty());
+    }
+
+    #[test]
+    fn subset_policy() {
+        let mut bus = bus(&[EK1100, EL2008, EL3102]);
+        let expected = [EK1100, EL1008, EL2008, EL3102];
+        assert_eq!(
+            apply(&mut bus, &expected, MismatchPolicy::Subset),
+            Some(vec![1])
+        );
+        assert_eq!(bus.excluded_slaves(), [2, 3]);
+        // slaves missing at the end leave the rest running
+        let expected = [EK1100, EL2008, EL3102, EL1008];
+        assert_eq!(
+            apply(&mut bus, &expected, MismatchPolicy::Subset),
+            Some(vec![1, 2, 3])
+        );
+        let expected = [EL1008, EL2008, EL3102];
+        assert_eq!(apply(&mut bus, &expected, MismatchPolicy::Subset), None);
+    }
+
+    #[test]
+    fn matching_only_policy() {
+        let mut bus = bus(&[EK1100, EL2008, EL1008, EL3102]);
+        let expected = [EK1100, EL1008, EL2008, EL2008, EL3102];
+        // EL1008 missing, EL2008 replaced by an EL1008
+        assert_eq!(
+            kinds(&bus.verify_bus(&config(&expected, MismatchPolicy::MatchingOnly))),
+            [("missing", 2), ("product", 3)]
+        );
+        assert_eq!(
+            apply(&mut bus, &expected, MismatchPolicy::MatchingOnly),
+            Some(vec![1, 2, 4])
+        );
+        assert_eq!(bus.excluded_slaves(), [3]);
+        let configs: Vec<(u16, u32)> = bus
+            .slave_configs()
+            .into_iter()
+            .map(|(slave, config)| (slave, config.product_code))
+            .collect();
+        assert_eq!(configs, [(1, EK1100), (2, EL2008), (4, EL3102)]);
+    }
+}
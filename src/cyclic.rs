@@ -0,0 +1,162 @@
+//! Cyclic process data exchange, optionally servicing slave mailboxes in the same
+//! loop through SOEM's mailbox handler.
+
+use std::{
+    ptr, thread,
+    time::{Duration, Instant},
+};
+
+use crate::bindings::*;
+use crate::config::HOT_CONNECT_GROUP;
+use crate::shutdown::shutdown_requested;
+use crate::simple_ng::Fieldbus;
+
+/// State of the cyclic mailbox handler.
+///
+/// `ecx_mbxhandler` does blocking transfers and can only be told how many to do,
+/// so the time budget is kept by adapting that limit: it is halved when a cycle
+/// overruns the budget and raised again while there is time to spare.
+pub(crate) struct CyclicMailbox {
+    budget: Duration,
+    max_transfers: i32,
+    limit: i32,
+}
+
+/// `EC_MBXINENABLE`: address 1, marks an in-mailbox slot the handler may fill.
+pub(crate) const MBX_IN_ENABLE: *mut u8 = ptr::without_provenance_mut(1);
+
+impl Fieldbus {
+    /// Hand the mailboxes of all slaves with a mapped mailbox status over to the
+    /// cyclic handler, which then runs from `cycle` for at most `budget` and
+    /// `max_transfers` mailbox transfers per cycle.
+    ///
+    /// Call after `start`. While enabled, mailbox frames are queued and only make
+    /// progress when `cycle` runs, so use `SdoRequest` and the other polled requests
+    /// or a `MailboxClient` rather than blocking SOEM calls. Returns the number of
+    /// slaves handed over.
+    pub fn enable_cyclic_mailbox(&mut self, budget: Duration, max_transfers: u32) -> usize {
+        let context = &mut *self.context as *mut ecx_contextt;
+        let mut count = 0;
+        for slave in 1..=self.context.slavecount as u16 {
+            if unsafe { ecx_slavembxcyclic(context, slave) } == 0 {
+                continue;
+            }
+            let s = &mut self.context.slavelist[slave as usize];
+            let proto = s.mbx_proto as u32;
+            if proto & ECT_MBXPROT_SOE != 0 {
+                s.soembxin = MBX_IN_ENABLE;
+            }
+            if proto & ECT_MBXPROT_FOE != 0 {
+                s.foembxin = MBX_IN_ENABLE;
+            }
+            if proto & ECT_MBXPROT_EOE != 0 {
+                s.eoembxin = MBX_IN_ENABLE;
+            }
+            count += 1;
+        }
+        let max_transfers = max_transfers.max(1) as i32;
+        self.mailbox = Some(CyclicMailbox {
+            budget,
+            max_transfers,
+            limit: max_transfers,
+        });
+        count
+    }
+
+    /// Return the mailboxes to direct (blocking) access.
+    pub fn disable_cyclic_mailbox(&mut self) {
+        if self.mailbox.take().is_none() {
+            return;
+        }
+        let context = &mut *self.context as *mut ecx_contextt;
+        for slave in 1..=self.context.slavecount as usize {
+            let s = unsafe { &mut (*context).slavelist[slave] };
+            if s.mbxhandlerstate == ECT_MBXH_NONE as i32 {
+                continue;
+            }
+            s.mbxhandlerstate = ECT_MBXH_NONE as i32;
+            for (mbx, full) in [
+                (&mut s.coembxin, &mut s.coembxinfull),
+                (&mut s.soembxin, &mut s.soembxinfull),
+                (&mut s.foembxin, &mut s.foembxinfull),
+                (&mut s.eoembxin, &mut s.eoembxinfull),
+            ] {
+                if *full != 0 {
+                    unsafe { ecx_dropmbx(context, *mbx as *mut ec_mbxbuft) };
+                }
+                *mbx = ptr::null_mut();
+                *full = 0;
+            }
+        }
+    }
+
+    /// Run the mailbox handler if cyclic mailbox handling is enabled. Must follow a
+    /// process data exchange so the mailbox status bits in the IOmap are current.
+    pub fn service_mailboxes(&mut self) {
+        let Some(limit) = self.mailbox.as_ref().map(|m| m.limit) else {
+            return;
+        };
+        let context = &mut *self.context as *mut ecx_contextt;
+        let start = Instant::now();
+        unsafe { ecx_mbxhandler(context, self.group, limit) };
+        if self.hot_connect_active() {
+            unsafe { ecx_mbxhandler(context, HOT_CONNECT_GROUP, limit) };
+        }
+        let elapsed = start.elapsed();
+        let mailbox = self.mailbox.as_mut().unwrap();
+        if elapsed > mailbox.budget {
+            mailbox.limit = (mailbox.limit / 2).max(1);
+        } else if elapsed < mailbox.budget / 2 {
+            mailbox.limit = (mailbox.limit + 1).min(mailbox.max_transfers);
+        }
+    }
+
+    /// One cycle: exchange process data, deliver emergencies and latch events,
+    /// service mailboxes and advance queued mailbox requests. Returns the working
+    /// counter.
+    pub fn cycle(&mut self) -> i32 {
+        let wkc = self.roundtrip();
+        if !self.latch_handlers.is_empty() {
+            self.dispatch_latches();
+        }
+        self.service_mailboxes();
+        self.service_mailbox_requests();
+        if !self.emcy_handlers.is_empty() {
+            self.dispatch_emergencies();
+        }
+        wkc
+    }
+
+    /// Call `cycle` every `period` and then `body` with the working counter, until
+    /// `body` returns false. Cycles that overrun are not made up for; the next one
+    /// starts at the following period boundary. With clock synchronisation set, the
+    /// period is corrected by it every cycle. A shutdown request also ends the loop.
+    /// Returns the number of cycles run.
+    pub fn run_cyclic(
+        &mut self,
+        period: Duration,
+        mut body: impl FnMut(&mut Fieldbus, i32) -> bool,
+    ) -> u64 {
+        let mut cycles = 0;
+        let mut next = Instant::now();
+        loop {
+            let wkc = self.cycle();
+            cycles += 1;
+            if !body(self, wkc) || shutdown_requested() {
+                return cycles;
+            }
+            let correction = self.sync_clock(period.as_nanos() as i64);
+            next += period;
+            if correction >= 0 {
+                next += Duration::from_nanos(correction as u64);
+            } else {
+                next -= Duration::from_nanos(correction.unsigned_abs());
+            }
+            let now = Instant::now();
+            while next <= now && !period.is_zero() {
+                next += period;
+            }
+            thread::sleep(next.saturating_duration_since(now));
+        }
+    }
+}
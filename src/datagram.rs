@@ -0,0 +1,312 @@
+//! Safe single-datagram access to slave registers and the logical address space,
+//! wrapping `ecx_BRD`, `ecx_FPRD`, `ecx_LRW` and friends.
+
+use std::{
+    ffi::{c_int, c_void},
+    fmt,
+};
+
+use crate::bindings::*;
+use crate::simple_ng::Fieldbus;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DatagramError {
+    /// The frame did not come back: link down or timeout.
+    NoFrame,
+    /// The frame came back but no slave processed the datagram.
+    NoResponse,
+    /// The datagram was processed by an unexpected number of slaves.
+    WorkingCounter { expected: u16, actual: u16 },
+    /// The data does not fit into one datagram.
+    TooLong(usize),
+}
+
+impl fmt::Display for DatagramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatagramError::NoFrame => write!(f, "no frame received"),
+            DatagramError::NoResponse => write!(f, "no slave responded"),
+            DatagramError::WorkingCounter { expected, actual } => {
+                write!(f, "working counter {} (expected {})", actual, expected)
+            }
+            DatagramError::TooLong(len) => {
+                write!(f, "{} bytes do not fit into a datagram", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DatagramError {}
+
+/// A value that can be read from or written to a register, in EtherCAT (little
+/// endian) byte order.
+pub trait RegisterValue: Sized {
+    const SIZE: usize;
+
+    fn from_le(bytes: &[u8]) -> Self;
+
+    fn to_le(&self, bytes: &mut [u8]);
+}
+
+macro_rules! register_value {
+    ($($ty:ty),*) => {
+        $(
+            impl RegisterValue for $ty {
+                const SIZE: usize = size_of::<$ty>();
+
+                fn from_le(bytes: &[u8]) -> Self {
+                    <$ty>::from_le_bytes(bytes.try_into().unwrap())
+                }
+
+                fn to_le(&self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+register_value!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl<const N: usize> RegisterValue for [u8; N] {
+    const SIZE: usize = N;
+
+    fn from_le(bytes: &[u8]) -> Self {
+        bytes.try_into().unwrap()
+    }
+
+    fn to_le(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(self);
+    }
+}
+
+type AddressedFn = unsafe extern "C" fn(*mut ecx_portt, u16, u16, u16, *mut c_void, c_int) -> c_int;
+type LogicalFn = unsafe extern "C" fn(*mut ecx_portt, u32, u16, *mut c_void, c_int) -> c_int;
+
+fn check(wkc: c_int, expected: Option<u16>) -> Result<u16, DatagramError> {
+    match wkc {
+        ..0 => Err(DatagramError::NoFrame),
+        0 => Err(DatagramError::NoResponse),
+        wkc => match expected {
+            Some(expected) if expected != wkc as u16 => Err(DatagramError::WorkingCounter {
+                expected,
+                actual: wkc as u16,
+            }),
+            _ => Ok(wkc as u16),
+        },
+    }
+}
+
+fn check_length(len: usize) -> Result<u16, DatagramError> {
+    if len > EC_MAXLRWDATA as usize {
+        return Err(DatagramError::TooLong(len));
+    }
+    Ok(len as u16)
+}
+
+/// Auto-increment address of `slave` (1 based, as everywhere else), computed as
+/// SOEM does: `1 - slave`.
+fn auto_increment(slave: u16) -> u16 {
+    1u16.wrapping_sub(slave)
+}
+
+/// Datagram methods. Slaves are addressed by slave number (1 based, auto-increment
+/// addressing), by configured station address or, for broadcasts, not at all.
+impl Fieldbus {
+    pub(crate) fn addressed(
+        &mut self,
+        command: AddressedFn,
+        adp: u16,
+        ado: u16,
+        data: &mut [u8],
+        expected: Option<u16>,
+    ) -> Result<u16, DatagramError> {
+        let length = check_length(data.len())?;
+        let wkc = unsafe {
+            command(
+                &mut self.context.port,
+                adp,
+                ado,
+                length,
+                data.as_mut_ptr() as *mut c_void,
+                EC_TIMEOUTRET as c_int,
+            )
+        };
+        self.statistics.record_datagram(wkc);
+        check(wkc, expected)
+    }
+
+    fn logical(
+        &mut self,
+        command: LogicalFn,
+        address: u32,
+        data: &mut [u8],
+        expected: Option<u16>,
+    ) -> Result<u16, DatagramError> {
+        let length = check_length(data.len())?;
+        let wkc = unsafe {
+            command(
+                &mut self.context.port,
+                address,
+                length,
+                data.as_mut_ptr() as *mut c_void,
+                EC_TIMEOUTRET as c_int,
+            )
+        };
+        self.statistics.record_datagram(wkc);
+        check(wkc, expected)
+    }
+
+    fn addressed_read<T: RegisterValue>(
+        &mut self,
+        command: AddressedFn,
+        adp: u16,
+        ado: u16,
+        expected: Option<u16>,
+    ) -> Result<(T, u16), DatagramError> {
+        let mut data = vec![0u8; T::SIZE];
+        let wkc = self.addressed(command, adp, ado, &mut data, expected)?;
+        Ok((T::from_le(&data), wkc))
+    }
+
+    fn addressed_write<T: RegisterValue>(
+        &mut self,
+        command: AddressedFn,
+        adp: u16,
+        ado: u16,
+        value: &T,
+        expected: Option<u16>,
+    ) -> Result<u16, DatagramError> {
+        let mut data = vec![0u8; T::SIZE];
+        value.to_le(&mut data);
+        self.addressed(command, adp, ado, &mut data, expected)
+    }
+
+    /// Broadcast read. Returns the values of all slaves OR-ed together and the
+    /// number of slaves that answered.
+    pub fn brd<T: RegisterValue>(&mut self, register: u16) -> Result<(T, u16), DatagramError> {
+        self.addressed_read(ecx_BRD, 0, register, None)
+    }
+
+    /// Broadcast write. Returns the number of slaves written.
+    pub fn bwr<T: RegisterValue>(&mut self, register: u16, value: T) -> Result<u16, DatagramError> {
+        self.addressed_write(ecx_BWR, 0, register, &value, None)
+    }
+
+    pub fn aprd<T: RegisterValue>(
+        &mut self,
+        slave: u16,
+        register: u16,
+    ) -> Result<T, DatagramError> {
+        self.addressed_read(ecx_APRD, auto_increment(slave), register, Some(1))
+            .map(|(value, _)| value)
+    }
+
+    pub fn apwr<T: RegisterValue>(
+        &mut self,
+        slave: u16,
+        register: u16,
+        value: T,
+    ) -> Result<(), DatagramError> {
+        self.addressed_write(ecx_APWR, auto_increment(slave), register, &value, Some(1))
+            .map(|_| ())
+    }
+
+    pub fn fprd<T: RegisterValue>(
+        &mut self,
+        station: u16,
+        register: u16,
+    ) -> Result<T, DatagramError> {
+        self.addressed_read(ecx_FPRD, station, register, Some(1))
+            .map(|(value, _)| value)
+    }
+
+    pub fn fpwr<T: RegisterValue>(
+        &mut self,
+        station: u16,
+        register: u16,
+        value: T,
+    ) -> Result<(), DatagramError> {
+        self.addressed_write(ecx_FPWR, station, register, &value, Some(1))
+            .map(|_| ())
+    }
+
+    /// Read the register of `slave` and write the value to the same register of
+    /// all following slaves. Returns the value and the working counter.
+    pub fn armw<T: RegisterValue>(
+        &mut self,
+        slave: u16,
+        register: u16,
+    ) -> Result<(T, u16), DatagramError> {
+        self.addressed_read(ecx_ARMW, auto_increment(slave), register, None)
+    }
+
+    /// Like `armw`, with the source slave given by station address.
+    pub fn frmw<T: RegisterValue>(
+        &mut self,
+        station: u16,
+        register: u16,
+    ) -> Result<(T, u16), DatagramError> {
+        self.addressed_read(ecx_FRMW, station, register, None)
+    }
+
+    /// Logical read into `data`. Returns the working counter, which must equal
+    /// `expected_wkc` if given.
+    pub fn lrd(
+        &mut self,
+        address: u32,
+        data: &mut [u8],
+        expected_wkc: Option<u16>,
+    ) -> Result<u16, DatagramError> {
+        self.logical(ecx_LRD, address, data, expected_wkc)
+    }
+
+    /// Logical write of `data`. Returns the working counter, which must equal
+    /// `expected_wkc` if given.
+    pub fn lwr(
+        &mut self,
+        address: u32,
+        data: &[u8],
+        expected_wkc: Option<u16>,
+    ) -> Result<u16, DatagramError> {
+        self.logical(ecx_LWR, address, &mut data.to_vec(), expected_wkc)
+    }
+
+    /// Logical read/write: `data` is sent and replaced by what the slaves return.
+    /// Returns the working counter, which must equal `expected_wkc` if given.
+    pub fn lrw(
+        &mut self,
+        address: u32,
+        data: &mut [u8],
+        expected_wkc: Option<u16>,
+    ) -> Result<u16, DatagramError> {
+        self.logical(ecx_LRW, address, data, expected_wkc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_increment_addresses() {
+        assert_eq!(auto_increment(1), 0);
+        assert_eq!(auto_increment(2), 0xFFFF);
+        assert_eq!(auto_increment(3), 0xFFFE);
+    }
+
+    #[test]
+    fn working_counter() {
+        assert_eq!(check(-1, None), Err(DatagramError::NoFrame));
+        assert_eq!(check(0, Some(0)), Err(DatagramError::NoResponse));
+        assert_eq!(check(3, None), Ok(3));
+        assert_eq!(check(3, Some(3)), Ok(3));
+        assert_eq!(
+            check(2, Some(3)),
+            Err(DatagramError::WorkingCounter {
+                expected: 3,
+                actual: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,420 @@
+//! Distributed clock setup: the reference clock, the origin of the system time,
+//! propagation delays and the SYNC0/SYNC1 configuration. Modes come from
+//! `set_dc_mode`, the bus configuration (`SlaveConfig::dc`) or an ESI op mode and
+//! are applied in `start` between mapping and the PRE_OP->SAFE_OP transition, where
+//! slaves check their sync settings.
+
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::bindings::*;
+use crate::datagram::DatagramError;
+use crate::esi::DcOpMode;
+use crate::registers::{DcSystemTimeDelay, DcSystemTimeOffset};
+use crate::simple_ng::Fieldbus;
+
+/// Seconds from 1970-01-01 to 2000-01-01, the start of the EtherCAT system time.
+const EPOCH_2000: u64 = 946_684_800;
+
+/// Sync activation bits of an ESI `AssignActivate` value (0x0981 in the high byte).
+const ACTIVATE_SYNC0: u16 = 0x0200;
+const ACTIVATE_SYNC1: u16 = 0x0400;
+
+/// Sync signal generation of a slave. Times are in ns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DcMode {
+    /// No sync signals; the slave runs on frame arrival (SM synchronous) or free.
+    #[default]
+    FreeRun,
+    Sync0 {
+        cycle: u32,
+        /// Offset of the SYNC0 pulses from the cycle start of the system time.
+        shift: i32,
+    },
+    Sync01 {
+        cycle0: u32,
+        /// Time from a SYNC0 pulse to the following SYNC1 pulse (0x09A4).
+        cycle1: u32,
+        shift: i32,
+    },
+}
+
+impl DcMode {
+    /// The mode an ESI op mode describes for a bus cycle of `bus_cycle` ns. Cycle
+    /// times of 0 in the ESI follow the bus cycle times the factor, a negative
+    /// factor divides.
+    pub fn from_esi(mode: &DcOpMode, bus_cycle: u32) -> DcMode {
+        let scaled = |cycle: u32, base: u32, factor: i32| {
+            if cycle != 0 {
+                cycle
+            } else if factor < 0 {
+                base / factor.unsigned_abs()
+            } else {
+                base * factor.max(1) as u32
+            }
+        };
+        let cycle0 = scaled(mode.cycle_time_sync0, bus_cycle, mode.sync0_factor);
+        if mode.assign_activate & ACTIVATE_SYNC1 != 0 {
+            let cycle1 = scaled(mode.cycle_time_sync1, cycle0, mode.sync1_factor);
+            DcMode::Sync01 {
+                cycle0,
+                cycle1: cycle1.saturating_add_signed(mode.shift_time_sync1),
+                shift: mode.shift_time_sync0,
+            }
+        } else if mode.assign_activate & ACTIVATE_SYNC0 != 0 {
+            DcMode::Sync0 {
+                cycle: cycle0,
+                shift: mode.shift_time_sync0,
+            }
+        } else {
+            DcMode::FreeRun
+        }
+    }
+
+    /// SYNC0 cycle time, 0 in free run.
+    pub fn cycle(&self) -> u32 {
+        match *self {
+            DcMode::FreeRun => 0,
+            DcMode::Sync0 { cycle, .. } => cycle,
+            DcMode::Sync01 { cycle0, .. } => cycle0,
+        }
+    }
+}
+
+/// Clock the system time is set from, in ns since 2000-01-01.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum DcTimeOrigin {
+    /// The realtime clock (UTC), as `ecx_configdc` uses.
+    #[default]
+    Utc,
+    /// TAI, ahead of UTC by `utc_offset` seconds (37 since 2017).
+    Tai { utc_offset: u32 },
+    /// A clock of the application, such as a PTP-disciplined one.
+    Custom(fn() -> u64),
+}
+
+impl DcTimeOrigin {
+    fn utc() -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        (now.as_nanos() as u64).saturating_sub(EPOCH_2000 * 1_000_000_000)
+    }
+
+    /// Current time of the clock in ns since 2000-01-01.
+    pub fn now(&self) -> u64 {
+        match *self {
+            DcTimeOrigin::Utc => Self::utc(),
+            DcTimeOrigin::Tai { utc_offset } => Self::utc() + utc_offset as u64 * 1_000_000_000,
+            DcTimeOrigin::Custom(clock) => clock(),
+        }
+    }
+}
+
+/// Propagation delay measurement of a DC slave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DcDelay {
+    pub slave: u16,
+    /// Local receive times of the measurement frame on ports 0-3 (`DCrtA..D`),
+    /// 0 for inactive ports.
+    pub receive_times: [i32; 4],
+    /// Delay from the reference clock in ns (`pdelay`). Negative for slaves the
+    /// frame passes before the reference clock.
+    pub delay: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DcError {
+    /// The slave has no distributed clock.
+    NotSupported(u16),
+    /// Sync signals need a cycle time above 0.
+    InvalidCycle(u16),
+    /// A slave with sync signals comes before the reference clock in the ring. The
+    /// reference time is distributed with one FRMW, so such a slave only gets the
+    /// previous cycle's time.
+    AheadOfReference {
+        slave: u16,
+        reference: u16,
+    },
+    Datagram(DatagramError),
+}
+
+impl fmt::Display for DcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DcError::NotSupported(slave) => {
+                write!(f, "slave {} does not support distributed clocks", slave)
+            }
+            DcError::InvalidCycle(slave) => {
+                write!(f, "invalid sync cycle time for slave {}", slave)
+            }
+            DcError::AheadOfReference { slave, reference } => write!(
+                f,
+                "slave {} uses sync signals but is ahead of reference clock {}",
+                slave, reference
+            ),
+            DcError::Datagram(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DcError {}
+
+impl From<DatagramError> for DcError {
+    fn from(e: DatagramError) -> Self {
+        DcError::Datagram(e)
+    }
+}
+
+impl Fieldbus {
+    /// Use `slave` as reference clock instead of the first DC slave, or go back to
+    /// that with `None`. Applied by `start` and `measure_dc_delays`.
+    pub fn set_reference_clock(&mut self, slave: Option<u16>) {
+        self.reference_clock = slave;
+    }
+
+    /// The reference clock of the process data, if any slave has DC.
+    pub fn reference_clock(&self) -> Option<u16> {
+        let group = &self.context.grouplist[self.group as usize];
+        (group.hasdc != 0).then_some(group.DCnext)
+    }
+
+    /// Set the system time from `origin` instead of UTC. Applied by `start` and
+    /// `measure_dc_delays`.
+    pub fn set_dc_time_origin(&mut self, origin: DcTimeOrigin) {
+        self.dc_time_origin = origin;
+    }
+
+    /// Propagation delays of the DC slaves as last measured.
+    pub fn dc_delays(&self) -> Vec<DcDelay> {
+        (1..=self.context.slavecount.max(0) as u16)
+            .filter(|&slave| self.context.slavelist[slave as usize].hasdc != 0)
+            .map(|slave| {
+                let s = &self.context.slavelist[slave as usize];
+                DcDelay {
+                    slave,
+                    receive_times: [s.DCrtA, s.DCrtB, s.DCrtC, s.DCrtD],
+                    delay: s.pdelay,
+                }
+            })
+            .collect()
+    }
+
+    /// Measure the propagation delays again, e.g. after slaves were added or
+    /// removed. This also sets the system time offsets again, so the clocks of
+    /// running slaves step and sync signals should be set up afresh.
+    pub fn measure_dc_delays(&mut self) -> Result<(), DcError> {
+        let context = &mut *self.context as *mut ecx_contextt;
+        unsafe { ecx_configdc(context) };
+        self.configure_dc_clocks()
+    }
+
+    /// After `ecx_configdc`: move the reference clock and the time origin where
+    /// they were asked to be.
+    pub(crate) fn configure_dc_clocks(&mut self) -> Result<(), DcError> {
+        if let Some(reference) = self.reference_clock {
+            self.move_reference_clock(reference)?;
+        }
+        if !matches!(self.dc_time_origin, DcTimeOrigin::Utc) {
+            // ecx_configdc set the slaves to UTC, so shift all offsets by the
+            // difference
+            let shift = self
+                .dc_time_origin
+                .now()
+                .wrapping_sub(DcTimeOrigin::Utc.now());
+            for delay in self.dc_delays() {
+                let offset = self.read_register::<DcSystemTimeOffset>(delay.slave)?.0;
+                self.write_register(delay.slave, &DcSystemTimeOffset(offset.wrapping_add(shift)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Make `reference` the clock distributed with the process data and rebase the
+    /// propagation delays on it.
+    fn move_reference_clock(&mut self, reference: u16) -> Result<(), DcError> {
+        let count = self.context.slavecount.max(0) as u16;
+        if reference == 0
+            || reference > count
+            || self.context.slavelist[reference as usize].hasdc == 0
+        {
+            return Err(DcError::NotSupported(reference));
+        }
+        if let Some(&(slave, _)) = self
+            .dc_plan()
+            .iter()
+            .find(|&&(slave, mode)| slave < reference && mode != DcMode::FreeRun)
+        {
+            return Err(DcError::AheadOfReference { slave, reference });
+        }
+        let base = self.context.slavelist[reference as usize].pdelay;
+        for delay in self.dc_delays() {
+            let pdelay = delay.delay - base;
+            self.context.slavelist[delay.slave as usize].pdelay = pdelay;
+            if delay.slave >= reference {
+                self.write_register(delay.slave, &DcSystemTimeDelay(pdelay as u32))?;
+            }
+        }
+        let group = &mut self.context.grouplist[self.group as usize];
+        group.hasdc = 1;
+        group.DCnext = reference;
+        log::info!("Reference clock is slave {}", reference);
+        Ok(())
+    }
+
+    /// Set the DC mode of a slave, applied by `start`. Overrides the bus
+    /// configuration.
+    pub fn set_dc_mode(&mut self, slave: u16, mode: DcMode) {
+        self.dc_modes.retain(|&(s, _)| s != slave);
+        self.dc_modes.push((slave, mode));
+    }
+
+    /// Program the sync signals of a slave now. Needs `ecx_configdc` to have run,
+    /// which `start` does.
+    pub fn apply_dc_mode(&mut self, slave: u16, mode: DcMode) -> Result<(), DcError> {
+        let context = &mut *self.context as *mut ecx_contextt;
+        if mode != DcMode::FreeRun {
+            if self.context.slavelist[slave as usize].hasdc == 0 {
+                return Err(DcError::NotSupported(slave));
+            }
+            if mode.cycle() == 0 {
+                return Err(DcError::InvalidCycle(slave));
+            }
+        }
+        // SOEM stops cyclic operation, sets the start time to a whole number of
+        // cycles ahead and then activates the sync signals
+        match mode {
+            DcMode::FreeRun => {
+                if self.context.slavelist[slave as usize].hasdc != 0 {
+                    unsafe { ecx_dcsync0(context, slave, 0, 0, 0) }
+                }
+            }
+            DcMode::Sync0 { cycle, shift } => unsafe {
+                ecx_dcsync0(context, slave, 1, cycle, shift)
+            },
+            DcMode::Sync01 {
+                cycle0,
+                cycle1,
+                shift,
+            } => unsafe { ecx_dcsync01(context, slave, 1, cycle0, cycle1, shift) },
+        }
+        Ok(())
+    }
+
+    /// DC modes for `start`: the bus configuration, overridden by `set_dc_mode`.
+    fn dc_plan(&self) -> Vec<(u16, DcMode)> {
+        let mut plan: Vec<(u16, DcMode)> = self
+            .slave_configs()
+            .into_iter()
+            .filter_map(|(slave, config)| config.dc.map(|mode| (slave, mode)))
+            .collect();
+        for &(slave, mode) in &self.dc_modes {
+            plan.retain(|&(s, _)| s != slave);
+            plan.push((slave, mode));
+        }
+        let count = self.context.slavecount.max(0) as u16;
+        plan.retain(|(slave, _)| *slave <= count && !self.excluded.contains(slave));
+        plan
+    }
+
+    /// Apply the planned DC modes. Returns false if one is not valid for its slave.
+    pub(crate) fn apply_dc_modes(&mut self) -> bool {
+        let mut ok = true;
+        for (slave, mode) in self.dc_plan() {
+            if let Err(e) = self.apply_dc_mode(slave, mode) {
+                log::warn!("DC configuration failed: {}", e);
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    /// Whether `start` has DC modes to apply before SAFE_OP.
+    pub(crate) fn has_dc_modes(&self) -> bool {
+        !self.dc_plan().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op_mode(assign_activate: u16) -> DcOpMode {
+        DcOpMode {
+            name: "DC".to_string(),
+            assign_activate,
+            sync0_factor: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sync0() {
+        let mut mode = op_mode(0x0300);
+        mode.shift_time_sync0 = 2500;
+        let dc = DcMode::from_esi(&mode, 1_000_000);
+        assert_eq!(
+            dc,
+            DcMode::Sync0 {
+                cycle: 1_000_000,
+                shift: 2500
+            }
+        );
+        assert_eq!(dc.cycle(), 1_000_000);
+        mode.sync0_factor = 2;
+        assert_eq!(DcMode::from_esi(&mode, 1_000_000).cycle(), 2_000_000);
+        mode.cycle_time_sync0 = 250_000;
+        assert_eq!(DcMode::from_esi(&mode, 1_000_000).cycle(), 250_000);
+    }
+
+    #[test]
+    fn sync01() {
+        let mut mode = op_mode(0x0700);
+        mode.shift_time_sync0 = 1000;
+        mode.shift_time_sync1 = 20_000;
+        assert_eq!(
+            DcMode::from_esi(&mode, 500_000),
+            DcMode::Sync01 {
+                cycle0: 500_000,
+                cycle1: 520_000,
+                shift: 1000
+            }
+        );
+        // SYNC1 scales with the SYNC0 cycle, not the bus cycle.
+        mode.cycle_time_sync0 = 100_000;
+        mode.sync1_factor = 3;
+        mode.shift_time_sync1 = 0;
+        assert_eq!(
+            DcMode::from_esi(&mode, 500_000),
+            DcMode::Sync01 {
+                cycle0: 100_000,
+                cycle1: 300_000,
+                shift: 1000
+            }
+        );
+        mode.cycle_time_sync1 = 50_000;
+        mode.shift_time_sync1 = -10_000;
+        assert!(matches!(
+            DcMode::from_esi(&mode, 500_000),
+            DcMode::Sync01 { cycle1: 40_000, .. }
+        ));
+    }
+
+    #[test]
+    fn negative_factor_divides() {
+        let mut mode = op_mode(0x0300);
+        mode.sync0_factor = -4;
+        assert_eq!(DcMode::from_esi(&mode, 1_000_000).cycle(), 250_000);
+        mode.sync0_factor = 0;
+        assert_eq!(DcMode::from_esi(&mode, 1_000_000).cycle(), 1_000_000);
+    }
+
+    #[test]
+    fn free_run() {
+        let mode = op_mode(0x0000);
+        assert_eq!(DcMode::from_esi(&mode, 1_000_000), DcMode::FreeRun);
+        assert_eq!(DcMode::FreeRun.cycle(), 0);
+    }
+}
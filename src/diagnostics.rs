@@ -0,0 +1,551 @@
+//! Bus-wide error counter diagnostics. The ESC error counters (0x0300-0x0313) of
+//! all slaves are sampled periodically and the deltas are mapped onto the cable
+//! segments of the topology found by SOEM (`parent`, `parentport`, `entryport`) to
+//! point at the most likely faulty segment.
+//!
+//! A slave that receives a corrupted frame counts an RX error on that port and
+//! marks the frame, so every slave behind it counts a forwarded RX error instead.
+//! RX errors not explained by forwarded errors therefore originate on the cable
+//! attached to that port.
+//!
+//! The synchronisation of the distributed clocks is watched the same way, through
+//! the system time difference (0x092C) of every DC slave.
+
+use std::{
+    cmp::Reverse,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::bindings::ECT_REG_RXERR;
+use crate::datagram::DatagramError;
+use crate::registers::{DcSystemTimeDifference, ErrorCounters, PortErrorCounters};
+use crate::simple_ng::Fieldbus;
+
+/// Weight of a lost link against a single RX error.
+const LOST_LINK_WEIGHT: u32 = 16;
+
+/// The cable between a slave and the one before it (or the master).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Slave number of the parent, 0 for the master.
+    pub parent: u16,
+    pub parent_port: u8,
+    pub slave: u16,
+    pub entry_port: u8,
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.parent == 0 {
+            write!(f, "master")?;
+        } else {
+            write!(f, "slave {} port {}", self.parent, self.parent_port)?;
+        }
+        write!(f, " -> slave {} port {}", self.slave, self.entry_port)
+    }
+}
+
+/// Errors attributed to one segment in a sampling interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentFault {
+    pub segment: Segment,
+    /// RX and invalid frame errors originating on this segment, both directions.
+    pub rx_errors: u32,
+    pub lost_links: u32,
+    pub score: u32,
+}
+
+impl fmt::Display for SegmentFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} rx errors, {} lost links",
+            self.segment, self.rx_errors, self.lost_links
+        )
+    }
+}
+
+/// Counters of one slave and their change since the previous sample.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlaveErrors {
+    pub slave: u16,
+    pub counters: ErrorCounters,
+    pub delta: ErrorCounters,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiagnosticsReport {
+    pub slaves: Vec<SlaveErrors>,
+    /// Slaves whose counters could not be read.
+    pub unreachable: Vec<(u16, DatagramError)>,
+    /// Segments with errors in this interval, most likely fault first.
+    pub faults: Vec<SegmentFault>,
+}
+
+impl DiagnosticsReport {
+    pub fn likely_fault(&self) -> Option<&SegmentFault> {
+        self.faults.first()
+    }
+
+    /// No new errors in this interval and all slaves reachable.
+    pub fn is_clean(&self) -> bool {
+        self.faults.is_empty() && self.unreachable.is_empty()
+    }
+}
+
+/// The counters saturate at 255 and may be cleared by anyone, so a counter below
+/// its previous value has been cleared and counts from zero.
+fn counter_delta(current: u8, previous: u8) -> u8 {
+    if current >= previous {
+        current - previous
+    } else {
+        current
+    }
+}
+
+fn delta(current: &ErrorCounters, previous: &ErrorCounters) -> ErrorCounters {
+    let mut delta = ErrorCounters::default();
+    for (d, (c, p)) in delta
+        .ports
+        .iter_mut()
+        .zip(current.ports.iter().zip(&previous.ports))
+    {
+        *d = PortErrorCounters {
+            invalid_frame: counter_delta(c.invalid_frame, p.invalid_frame),
+            rx_error: counter_delta(c.rx_error, p.rx_error),
+            forwarded_rx_error: counter_delta(c.forwarded_rx_error, p.forwarded_rx_error),
+            lost_link: counter_delta(c.lost_link, p.lost_link),
+        };
+    }
+    delta.processing_unit = counter_delta(current.processing_unit, previous.processing_unit);
+    delta.pdi = counter_delta(current.pdi, previous.pdi);
+    delta.pdi_error_code = current.pdi_error_code;
+    delta
+}
+
+/// Errors that originated on the cable at this port.
+fn originated(port: &PortErrorCounters) -> u32 {
+    (port.rx_error as u32 + port.invalid_frame as u32)
+        .saturating_sub(port.forwarded_rx_error as u32)
+}
+
+/// Periodic sampler of the error counters of all slaves.
+pub struct ErrorCounterMonitor {
+    interval: Duration,
+    last: Option<Instant>,
+    /// Previous counters, indexed by slave number.
+    previous: Vec<Option<ErrorCounters>>,
+}
+
+impl ErrorCounterMonitor {
+    pub fn new(interval: Duration) -> Self {
+        ErrorCounterMonitor {
+            interval,
+            last: None,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Sample if the interval has elapsed. Meant to be called from the cyclic loop;
+    /// each sample costs one frame per slave.
+    pub fn poll(&mut self, bus: &mut Fieldbus) -> Option<DiagnosticsReport> {
+        match self.last {
+            Some(last) if last.elapsed() < self.interval => None,
+            _ => Some(self.sample(bus)),
+        }
+    }
+
+    /// Read the counters of all slaves now. The first sample reports the counts
+    /// since power-up or the last clear.
+    pub fn sample(&mut self, bus: &mut Fieldbus) -> DiagnosticsReport {
+        self.last = Some(Instant::now());
+        let count = bus.context.slavecount.max(0) as usize;
+        self.previous.resize(count + 1, None);
+        let mut report = DiagnosticsReport::default();
+        for slave in 1..=count as u16 {
+            match bus.read_register::<ErrorCounters>(slave) {
+                Ok(counters) => {
+                    let previous = self.previous[slave as usize].unwrap_or_default();
+                    report.slaves.push(SlaveErrors {
+                        slave,
+                        counters,
+                        delta: delta(&counters, &previous),
+                    });
+                    self.previous[slave as usize] = Some(counters);
+                }
+                Err(e) => report.unreachable.push((slave, e)),
+            }
+        }
+        report.faults = localise(bus, &report.slaves);
+        report
+    }
+
+    /// Forget the previous counters, e.g. after `clear_error_counters`.
+    pub fn reset(&mut self) {
+        self.previous.clear();
+        self.last = None;
+    }
+}
+
+/// Attribute the deltas to the segments between each slave and its parent.
+fn localise(bus: &Fieldbus, slaves: &[SlaveErrors]) -> Vec<SegmentFault> {
+    let delta_of = |slave: u16| {
+        slaves
+            .iter()
+            .find(|s| s.slave == slave)
+            .map(|s| s.delta)
+            .unwrap_or_default()
+    };
+    let mut faults = Vec::new();
+    for s in slaves {
+        let slave = &bus.context.slavelist[s.slave as usize];
+        let segment = Segment {
+            parent: slave.parent,
+            parent_port: slave.parentport,
+            slave: s.slave,
+            entry_port: slave.entryport,
+        };
+        let downstream = &s.delta.ports[segment.entry_port as usize & 3];
+        let mut rx_errors = originated(downstream);
+        let mut lost_links = downstream.lost_link as u32;
+        if segment.parent != 0 {
+            let upstream = delta_of(segment.parent).ports[segment.parent_port as usize & 3];
+            rx_errors += originated(&upstream);
+            lost_links += upstream.lost_link as u32;
+        }
+        let score = rx_errors + lost_links * LOST_LINK_WEIGHT;
+        if score > 0 {
+            faults.push(SegmentFault {
+                segment,
+                rx_errors,
+                lost_links,
+                score,
+            });
+        }
+    }
+    faults.sort_by_key(|f| Reverse(f.score));
+    faults
+}
+
+/// Deviation statistics of one DC slave since the monitor started.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SyncStatistics {
+    pub samples: u64,
+    /// Last system time difference in ns.
+    pub last: i32,
+    pub min: i32,
+    pub max: i32,
+    pub mean: f64,
+    /// Samples beyond the limit.
+    pub breaches: u64,
+    /// Sum of squared differences from the mean (Welford).
+    m2: f64,
+}
+
+impl SyncStatistics {
+    fn add(&mut self, deviation: i32) {
+        if self.samples == 0 {
+            self.min = deviation;
+            self.max = deviation;
+        }
+        self.samples += 1;
+        self.last = deviation;
+        self.min = self.min.min(deviation);
+        self.max = self.max.max(deviation);
+        let d = deviation as f64 - self.mean;
+        self.mean += d / self.samples as f64;
+        self.m2 += d * (deviation as f64 - self.mean);
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        if self.samples < 2 {
+            return 0.0;
+        }
+        (self.m2 / (self.samples - 1) as f64).sqrt()
+    }
+
+    /// Largest deviation seen in either direction.
+    pub fn peak(&self) -> u32 {
+        self.min.unsigned_abs().max(self.max.unsigned_abs())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncEvent {
+    /// The deviation of a slave went beyond the limit.
+    OutOfSync { slave: u16, deviation: i32 },
+    /// It is back within the limit.
+    InSync { slave: u16, deviation: i32 },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    /// System time difference of each DC slave in ns.
+    pub deviations: Vec<(u16, i32)>,
+    pub unreachable: Vec<(u16, DatagramError)>,
+    pub events: Vec<SyncEvent>,
+}
+
+impl SyncReport {
+    /// All DC slaves read and within the limit.
+    pub fn is_synchronised(&self, limit: u32) -> bool {
+        self.unreachable.is_empty()
+            && self
+                .deviations
+                .iter()
+                .all(|(_, d)| d.unsigned_abs() <= limit)
+    }
+}
+
+/// Periodic sampler of the system time difference of all DC slaves but the
+/// reference clock.
+pub struct SyncMonitor {
+    interval: Duration,
+    /// Largest allowed deviation in ns.
+    limit: u32,
+    last: Option<Instant>,
+    /// Statistics and whether the slave is beyond the limit, by slave number.
+    slaves: Vec<Option<(SyncStatistics, bool)>>,
+}
+
+impl SyncMonitor {
+    pub fn new(interval: Duration, limit: u32) -> Self {
+        SyncMonitor {
+            interval,
+            limit,
+            last: None,
+            slaves: Vec::new(),
+        }
+    }
+
+    /// Sample if the interval has elapsed. Each sample costs one frame per DC
+    /// slave.
+    pub fn poll(&mut self, bus: &mut Fieldbus) -> Option<SyncReport> {
+        match self.last {
+            Some(last) if last.elapsed() < self.interval => None,
+            _ => Some(self.sample(bus)),
+        }
+    }
+
+    /// Read the system time differences now. Events are raised when a slave
+    /// crosses the limit, not for every sample beyond it.
+    pub fn sample(&mut self, bus: &mut Fieldbus) -> SyncReport {
+        self.last = Some(Instant::now());
+        let count = bus.context.slavecount.max(0) as usize;
+        self.slaves.resize(count + 1, None);
+        let reference = bus.reference_clock();
+        let mut report = SyncReport::default();
+        for slave in 1..=count as u16 {
+            if bus.context.slavelist[slave as usize].hasdc == 0 || Some(slave) == reference {
+                continue;
+            }
+            let deviation = match bus.read_register::<DcSystemTimeDifference>(slave) {
+                Ok(DcSystemTimeDifference(deviation)) => deviation,
+                Err(e) => {
+                    report.unreachable.push((slave, e));
+                    continue;
+                }
+            };
+            let (statistics, out) = self.slaves[slave as usize].get_or_insert_default();
+            statistics.add(deviation);
+            let beyond = deviation.unsigned_abs() > self.limit;
+            if beyond {
+                statistics.breaches += 1;
+            }
+            if beyond != *out {
+                *out = beyond;
+                report.events.push(if beyond {
+                    SyncEvent::OutOfSync { slave, deviation }
+                } else {
+                    SyncEvent::InSync { slave, deviation }
+                });
+            }
+            report.deviations.push((slave, deviation));
+        }
+        report
+    }
+
+    pub fn statistics(&self, slave: u16) -> Option<&SyncStatistics> {
+        self.slaves
+            .get(slave as usize)
+            .and_then(|s| s.as_ref())
+            .map(|(statistics, _)| statistics)
+    }
+
+    /// Start the statistics over.
+    pub fn reset(&mut self) {
+        self.slaves.clear();
+        self.last = None;
+    }
+}
+
+impl Fieldbus {
+    /// Clear the error counters of one slave, or of all slaves with a broadcast.
+    /// Returns the number of slaves cleared.
+    pub fn clear_error_counters(&mut self, slave: Option<u16>) -> Result<u16, DatagramError> {
+        // writing any value clears the counters
+        match slave {
+            Some(slave) => self
+                .write_register(slave, &ErrorCounters::default())
+                .map(|_| 1),
+            None => self.bwr(ECT_REG_RXERR as u16, [0u8; 0x14]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    fn port(rx_error: u8, forwarded_rx_error: u8, lost_link: u8) -> PortErrorCounters {
+        PortErrorCounters {
+            invalid_frame: 0,
+            rx_error,
+            forwarded_rx_error,
+            lost_link,
+        }
+    }
+
+    fn errors(slave: u16, ports: [PortErrorCounters; 4]) -> SlaveErrors {
+        let delta = ErrorCounters {
+            ports,
+            ..Default::default()
+        };
+        SlaveErrors {
+            slave,
+            counters: delta,
+            delta,
+        }
+    }
+
+    /// Master -> 1 -> 2 -> 3, each slave entered on port 0 and left on port 1.
+    fn line() -> Fieldbus {
+        let mut bus = Fieldbus::new(CString::new("test").unwrap());
+        bus.context.slavecount = 3;
+        for slave in 1..=3 {
+            let s = &mut bus.context.slavelist[slave];
+            s.parent = slave as u16 - 1;
+            s.parentport = 1;
+            s.entryport = 0;
+        }
+        bus
+    }
+
+    #[test]
+    fn counter_deltas() {
+        assert_eq!(counter_delta(10, 4), 6);
+        assert_eq!(counter_delta(255, 250), 5);
+        // cleared since the previous sample
+        assert_eq!(counter_delta(3, 200), 3);
+
+        let mut previous = ErrorCounters::default();
+        previous.ports[1] = port(4, 1, 0);
+        previous.pdi = 9;
+        previous.pdi_error_code = 1;
+        let mut current = previous;
+        current.ports[1] = port(6, 1, 2);
+        current.pdi = 2;
+        current.pdi_error_code = 7;
+        let d = delta(&current, &previous);
+        assert_eq!(d.ports[1], port(2, 0, 2));
+        assert_eq!(d.ports[0], PortErrorCounters::default());
+        assert_eq!(d.pdi, 2);
+        assert_eq!(d.pdi_error_code, 7);
+    }
+
+    #[test]
+    fn originated_errors() {
+        assert_eq!(originated(&port(5, 0, 0)), 5);
+        assert_eq!(originated(&port(5, 5, 0)), 0);
+        assert_eq!(originated(&port(0, 3, 0)), 0);
+        let invalid = PortErrorCounters {
+            invalid_frame: 2,
+            ..port(1, 0, 0)
+        };
+        assert_eq!(originated(&invalid), 3);
+    }
+
+    #[test]
+    fn fault_between_slaves() {
+        let bus = line();
+        let none = PortErrorCounters::default();
+        let slaves = [
+            // frames coming back from slave 2 are corrupted
+            errors(1, [none, port(2, 0, 0), none, none]),
+            // frames going out to slave 2 are corrupted
+            errors(2, [port(5, 0, 0), none, none, none]),
+            // slave 3 only sees frames already marked by slave 2
+            errors(3, [port(0, 5, 0), none, none, none]),
+        ];
+        let faults = localise(&bus, &slaves);
+        assert_eq!(faults.len(), 1);
+        let fault = faults[0];
+        assert_eq!(
+            fault.segment,
+            Segment {
+                parent: 1,
+                parent_port: 1,
+                slave: 2,
+                entry_port: 0,
+            }
+        );
+        assert_eq!((fault.rx_errors, fault.lost_links, fault.score), (7, 0, 7));
+        assert_eq!(
+            fault.to_string(),
+            "slave 1 port 1 -> slave 2 port 0: 7 rx errors, 0 lost links"
+        );
+    }
+
+    #[test]
+    fn lost_links_rank_first() {
+        let bus = line();
+        let none = PortErrorCounters::default();
+        let slaves = [
+            errors(1, [port(10, 0, 0), none, none, none]),
+            errors(2, [none, none, none, none]),
+            errors(3, [port(0, 0, 1), none, none, none]),
+        ];
+        let faults = localise(&bus, &slaves);
+        assert_eq!(faults.len(), 2);
+        assert_eq!(faults[0].segment.slave, 3);
+        assert_eq!(faults[0].score, LOST_LINK_WEIGHT);
+        assert_eq!(faults[1].segment.parent, 0);
+        assert_eq!(faults[1].segment.to_string(), "master -> slave 1 port 0");
+    }
+
+    #[test]
+    fn sync_statistics() {
+        let mut statistics = SyncStatistics::default();
+        assert_eq!(statistics.std_dev(), 0.0);
+        for deviation in [10, -30, 20, 40] {
+            statistics.add(deviation);
+        }
+        assert_eq!(statistics.samples, 4);
+        assert_eq!(statistics.last, 40);
+        assert_eq!((statistics.min, statistics.max), (-30, 40));
+        assert_eq!(statistics.peak(), 40);
+        assert!((statistics.mean - 10.0).abs() < 1e-9);
+        // sample variance (0 + 1600 + 100 + 900) / 3
+        assert!((statistics.std_dev() - (2600.0f64 / 3.0).sqrt()).abs() < 1e-9);
+
+        let mut statistics = SyncStatistics::default();
+        statistics.add(-50);
+        statistics.add(-70);
+        assert_eq!(statistics.peak(), 70);
+    }
+
+    #[test]
+    fn synchronised() {
+        let mut report = SyncReport {
+            deviations: vec![(2, 100), (3, -250)],
+            ..Default::default()
+        };
+        assert!(report.is_synchronised(250));
+        assert!(!report.is_synchronised(249));
+        report.unreachable.push((4, DatagramError::NoFrame));
+        assert!(!report.is_synchronised(1000));
+    }
+}
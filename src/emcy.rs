@@ -0,0 +1,274 @@
+//! CoE emergency (EMCY) messages. Emergencies read by the mailbox transport are
+//! decoded where the frame is received; the ones SOEM reads itself are found in the
+//! context error ring, which is only looked at, never drained. Both are handed to
+//! the registered handlers.
+
+use std::{
+    fmt,
+    sync::mpsc,
+    time::{Duration, SystemTime},
+};
+
+use crate::bindings::*;
+use crate::simple_ng::Fieldbus;
+
+pub(crate) type EmergencyHandler = Box<dyn FnMut(&Emergency) + Send>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Emergency {
+    pub slave: u16,
+    pub error_code: u16,
+    /// Value of the slave's error register (object 0x1001) when the error occurred.
+    pub error_register: u8,
+    /// Manufacturer specific error field.
+    pub data: [u8; 5],
+    /// Time the master received the message.
+    pub timestamp: SystemTime,
+}
+
+impl Emergency {
+    fn from_error(e: &ec_errort) -> Emergency {
+        let emcy = unsafe { e.__bindgen_anon_1.__bindgen_anon_1 };
+        let mut data = [0u8; 5];
+        data[0] = emcy.b1;
+        data[1..3].copy_from_slice(&emcy.w1.to_le_bytes());
+        data[3..5].copy_from_slice(&emcy.w2.to_le_bytes());
+        Emergency {
+            slave: e.Slave,
+            error_code: emcy.ErrorCode,
+            error_register: emcy.ErrorReg,
+            data,
+            timestamp: SystemTime::UNIX_EPOCH
+                + Duration::new(e.Time.tv_sec as u64, e.Time.tv_nsec as u32),
+        }
+    }
+
+    /// Decode a CoE emergency frame, mailbox header included, read from `slave`.
+    pub(crate) fn decode(slave: u16, frame: &[u8]) -> Option<Emergency> {
+        let frame = frame.get(..16)?;
+        let mut data = [0u8; 5];
+        data.copy_from_slice(&frame[11..16]);
+        Some(Emergency {
+            slave,
+            error_code: u16::from_le_bytes([frame[8], frame[9]]),
+            error_register: frame[10],
+            data,
+            timestamp: SystemTime::now(),
+        })
+    }
+
+    pub fn description(&self) -> &'static str {
+        error_code_description(self.error_code)
+    }
+
+    pub fn error_register_names(&self) -> Vec<&'static str> {
+        error_register_names(self.error_register)
+    }
+}
+
+impl fmt::Display for Emergency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "slave {} EMCY {:04x} ({}) register {:02x}",
+            self.slave,
+            self.error_code,
+            self.description(),
+            self.error_register
+        )?;
+        if self.error_register != 0 {
+            write!(f, " [{}]", self.error_register_names().join(", "))?;
+        }
+        write!(f, " data")?;
+        for b in self.data {
+            write!(f, " {:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error codes from CiA 301 and the drive profile CiA 402, plus the EtherCAT
+/// state machine codes. Looked up most specific first.
+const ERROR_CODES: &[(u16, &str)] = &[
+    (0x0000, "Error reset or no error"),
+    (0x1000, "Generic error"),
+    (0x2000, "Current"),
+    (0x2100, "Current, device input side"),
+    (0x2110, "Short circuit/earth leakage (input)"),
+    (0x2120, "Earth leakage (input)"),
+    (0x2130, "Short circuit (input)"),
+    (0x2200, "Current inside the device"),
+    (0x2300, "Current, device output side"),
+    (0x2310, "Continuous over current"),
+    (0x2320, "Short circuit/earth leakage (motor side)"),
+    (0x2330, "Earth leakage (motor side)"),
+    (0x2340, "Short circuit (motor side)"),
+    (0x3000, "Voltage"),
+    (0x3100, "Mains voltage"),
+    (0x3110, "Mains over-voltage"),
+    (0x3120, "Mains under-voltage"),
+    (0x3130, "Phase failure"),
+    (0x3200, "Voltage inside the device"),
+    (0x3210, "DC link over-voltage"),
+    (0x3220, "DC link under-voltage"),
+    (0x3230, "Load error"),
+    (0x3300, "Output voltage"),
+    (0x3310, "Output over-voltage"),
+    (0x4000, "Temperature"),
+    (0x4100, "Ambient temperature"),
+    (0x4110, "Excess ambient temperature"),
+    (0x4120, "Too low ambient temperature"),
+    (0x4200, "Device temperature"),
+    (0x4210, "Excess temperature device"),
+    (0x4220, "Too low temperature device"),
+    (0x4300, "Drive temperature"),
+    (0x4310, "Excess temperature drive"),
+    (0x4400, "Supply temperature"),
+    (0x5000, "Device hardware"),
+    (0x5100, "Supply"),
+    (0x5200, "Control"),
+    (0x5300, "Operating unit"),
+    (0x5400, "Power section"),
+    (0x5410, "Output stages"),
+    (0x5420, "Chopper"),
+    (0x5430, "Input stages"),
+    (0x5500, "Data storage"),
+    (0x6000, "Device software"),
+    (0x6010, "Software reset (watchdog)"),
+    (0x6100, "Internal software"),
+    (0x6200, "User software"),
+    (0x6300, "Data set"),
+    (0x6310, "Loss of parameters"),
+    (0x6320, "Parameter error"),
+    (0x7000, "Additional modules"),
+    (0x7100, "Power"),
+    (0x7110, "Brake chopper"),
+    (0x7120, "Motor"),
+    (0x7200, "Measurement circuit"),
+    (0x7300, "Sensor"),
+    (0x7303, "Resolver 1 fault"),
+    (0x7304, "Resolver 2 fault"),
+    (0x7305, "Incremental sensor 1 fault"),
+    (0x7306, "Incremental sensor 2 fault"),
+    (0x7400, "Computation circuit"),
+    (0x7500, "Communication"),
+    (0x7600, "Data storage"),
+    (0x8000, "Monitoring"),
+    (0x8100, "Communication"),
+    (0x8110, "CAN overrun (objects lost)"),
+    (0x8120, "CAN in error passive mode"),
+    (0x8130, "Life guard error or heartbeat error"),
+    (0x8140, "Recovered from bus off"),
+    (0x8150, "CAN-ID collision"),
+    (0x8200, "Protocol error"),
+    (0x8210, "PDO not processed due to length error"),
+    (0x8220, "PDO length exceeded"),
+    (
+        0x8230,
+        "DAM MPDO not processed, destination object not available",
+    ),
+    (0x8240, "Unexpected SYNC data length"),
+    (0x8250, "RPDO timeout"),
+    (0x8300, "Torque control"),
+    (0x8311, "Excess torque"),
+    (0x8312, "Difficult start up"),
+    (0x8313, "Standstill torque"),
+    (0x8400, "Velocity speed controller"),
+    (0x8500, "Position controller"),
+    (0x8600, "Positioning controller"),
+    (0x8611, "Following error"),
+    (0x8612, "Reference limit"),
+    (0x8700, "Sync controller"),
+    (0x8800, "Winding controller"),
+    (0x9000, "External error"),
+    (
+        0xA000,
+        "Transition from PRE-OP to SAFE-OP was not successful",
+    ),
+    (0xA001, "Transition from SAFE-OP to OP was not successful"),
+    (0xF000, "Additional functions"),
+    (0xF001, "Deceleration"),
+    (0xF002, "Sub-synchronous run"),
+    (0xF003, "Stroke operation"),
+    (0xF004, "Control"),
+    (0xFF00, "Device specific"),
+];
+
+/// Describe an emergency error code. Codes without an exact entry fall back to the
+/// enclosing group, e.g. `0x2351` is reported as "Current, device output side".
+pub fn error_code_description(code: u16) -> &'static str {
+    let lookup = |code: u16| {
+        ERROR_CODES
+            .binary_search_by_key(&code, |&(c, _)| c)
+            .ok()
+            .map(|i| ERROR_CODES[i].1)
+    };
+    lookup(code)
+        .or_else(|| lookup(code & 0xFFF0))
+        .or_else(|| lookup(code & 0xFF00))
+        .or_else(|| lookup(code & 0xF000))
+        .unwrap_or("Unknown error code")
+}
+
+/// Names of the bits set in an error register (object 0x1001).
+pub fn error_register_names(register: u8) -> Vec<&'static str> {
+    [
+        "generic",
+        "current",
+        "voltage",
+        "temperature",
+        "communication",
+        "device profile specific",
+        "reserved",
+        "manufacturer specific",
+    ]
+    .into_iter()
+    .enumerate()
+    .filter(|(bit, _)| register & (1 << bit) != 0)
+    .map(|(_, name)| name)
+    .collect()
+}
+
+impl Fieldbus {
+    /// Call `handler` for every emergency received from now on.
+    pub fn on_emergency(&mut self, handler: impl FnMut(&Emergency) + Send + 'static) {
+        if self.emcy_handlers.is_empty() {
+            self.emcy_cursor = self.context.elist.head;
+        }
+        self.emcy_handlers.push(Box::new(handler));
+    }
+
+    /// Receive emergencies through a channel instead of a callback.
+    pub fn emergency_channel(&mut self) -> mpsc::Receiver<Emergency> {
+        let (tx, rx) = mpsc::channel();
+        self.on_emergency(move |e| {
+            let _ = tx.send(e.clone());
+        });
+        rx
+    }
+
+    pub(crate) fn deliver_emergency(&mut self, emcy: &Emergency) {
+        for handler in &mut self.emcy_handlers {
+            handler(emcy);
+        }
+    }
+
+    /// Pass the emergencies SOEM has added to its error ring since the last call to
+    /// the handlers. The ring is left as it is, so `ecx_poperror` users still see
+    /// every entry; emergencies overwritten before this runs are lost. Called from
+    /// `cycle` while handlers are registered. Returns the number of emergencies
+    /// delivered.
+    pub fn dispatch_emergencies(&mut self) -> usize {
+        let ring = EC_MAXELIST as i16 + 1;
+        let mut count = 0;
+        while self.emcy_cursor != self.context.elist.head {
+            let error = self.context.elist.Error[self.emcy_cursor as usize];
+            self.emcy_cursor = (self.emcy_cursor + 1) % ring;
+            if error.Etype == ec_err_type_EC_ERR_TYPE_EMERGENCY {
+                self.deliver_emergency(&Emergency::from_error(&error));
+                count += 1;
+            }
+        }
+        count
+    }
+}
@@ -0,0 +1,1258 @@
+//! EtherCAT Network Information (ENI) import.
+//!
+//! This replaces `scripts/eniconv.py`: the ENI XML is parsed at runtime and turned into
+//! the `ec_enit` tables SOEM walks in `ecx_mbxENIinitcmds`.
+
+use std::{ffi::c_void, fmt, fs, io, path::Path, ptr};
+
+use roxmltree::{Document, Node};
+
+use crate::bindings::*;
+use crate::esi::{EsiPdo, EsiPdoEntry, SyncManagerKind, parse_transitions, transition_names};
+use crate::simple_ng::Fieldbus;
+use crate::xml::{self, XmlError, XmlWriter};
+
+/// ENI file embedded at build time by setting `SOEM_ENI` for `build.rs`.
+#[cfg(soem_eni)]
+pub const EMBEDDED_ENI: Option<&str> = Some(include_str!(env!("SOEM_ENI_PATH")));
+#[cfg(not(soem_eni))]
+pub const EMBEDDED_ENI: Option<&str> = None;
+
+#[derive(Debug)]
+pub enum EniError {
+    Io(io::Error),
+    Xml(XmlError),
+}
+
+impl fmt::Display for EniError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EniError::Io(e) => write!(f, "{}", e),
+            EniError::Xml(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for EniError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EniError::Io(e) => Some(e),
+            EniError::Xml(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for EniError {
+    fn from(e: io::Error) -> Self {
+        EniError::Io(e)
+    }
+}
+
+impl From<XmlError> for EniError {
+    fn from(e: XmlError) -> Self {
+        EniError::Xml(e)
+    }
+}
+
+impl From<roxmltree::Error> for EniError {
+    fn from(e: roxmltree::Error) -> Self {
+        EniError::Xml(e.into())
+    }
+}
+
+/// CoE init command from `Slave/Mailbox/CoE/InitCmds`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EniCoeCmd {
+    /// Bitmask of `ECT_ESMTRANS_*` transitions.
+    pub transitions: u16,
+    pub complete_access: bool,
+    /// 1 = upload (read), 2 = download (write).
+    pub ccs: u8,
+    pub index: u16,
+    pub subindex: u8,
+    /// Timeout in ms, as stored in the ENI.
+    pub timeout_ms: u32,
+    pub data: Vec<u8>,
+    pub comment: Option<String>,
+}
+
+impl EniCoeCmd {
+    fn parse(node: Node) -> Result<EniCoeCmd, XmlError> {
+        let data = match xml::child_text(node, "Data") {
+            Some(d) => xml::parse_hex_binary("Data", d)?,
+            None => Vec::new(),
+        };
+        Ok(EniCoeCmd {
+            transitions: parse_transitions(node)?,
+            complete_access: xml::attr_bool(node, "CompleteAccess"),
+            ccs: xml::required_number(node, "Ccs")? as u8,
+            index: xml::required_number(node, "Index")? as u16,
+            subindex: xml::required_number(node, "SubIndex")? as u8,
+            timeout_ms: xml::required_number(node, "Timeout")? as u32,
+            data,
+            comment: xml::child_text(node, "Comment")
+                .filter(|c| !c.is_empty())
+                .map(str::to_string),
+        })
+    }
+}
+
+/// A bit range in the process image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BitRange {
+    pub start: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EniMailbox {
+    /// Master to slave mailbox (SM0).
+    pub send_start: u16,
+    pub send_length: u16,
+    /// Slave to master mailbox (SM1).
+    pub recv_start: u16,
+    pub recv_length: u16,
+    /// Protocol names such as `CoE` or `FoE`.
+    pub protocols: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EniSyncManager {
+    pub index: u8,
+    pub kind: SyncManagerKind,
+    pub start_address: u16,
+    pub length: u16,
+    pub control: u8,
+    pub enable: bool,
+    /// PDOs assigned to this SyncManager.
+    pub pdos: Vec<u16>,
+}
+
+/// FMMU register contents (0x0600 + 16 * n), in the layout of `ec_fmmut`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EniFmmu {
+    pub logical_start: u32,
+    pub length: u16,
+    pub logical_start_bit: u8,
+    pub logical_end_bit: u8,
+    pub physical_start: u16,
+    pub physical_start_bit: u8,
+    /// 1 = read (inputs), 2 = write (outputs).
+    pub fmmu_type: u8,
+    pub active: bool,
+}
+
+impl EniFmmu {
+    fn to_bytes(self) -> [u8; 16] {
+        let mut b = [0u8; 16];
+        b[0..4].copy_from_slice(&self.logical_start.to_le_bytes());
+        b[4..6].copy_from_slice(&self.length.to_le_bytes());
+        b[6] = self.logical_start_bit;
+        b[7] = self.logical_end_bit;
+        b[8..10].copy_from_slice(&self.physical_start.to_le_bytes());
+        b[10] = self.physical_start_bit;
+        b[11] = self.fmmu_type;
+        b[12] = self.active as u8;
+        b
+    }
+
+    fn from_bytes(b: &[u8]) -> Option<EniFmmu> {
+        if b.len() < 13 {
+            return None;
+        }
+        Some(EniFmmu {
+            logical_start: u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            length: u16::from_le_bytes([b[4], b[5]]),
+            logical_start_bit: b[6],
+            logical_end_bit: b[7],
+            physical_start: u16::from_le_bytes([b[8], b[9]]),
+            physical_start_bit: b[10],
+            fmmu_type: b[11],
+            active: b[12] != 0,
+        })
+    }
+}
+
+/// A named variable of the process image.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EniVariable {
+    pub name: String,
+    pub data_type: Option<String>,
+    pub bit_size: u32,
+    pub bit_offset: u32,
+}
+
+impl EniVariable {
+    fn parse(node: Node) -> Result<EniVariable, XmlError> {
+        Ok(EniVariable {
+            name: xml::child_text(node, "Name")
+                .unwrap_or_default()
+                .to_string(),
+            data_type: xml::child_text(node, "DataType").map(str::to_string),
+            bit_size: xml::required_number(node, "BitSize")? as u32,
+            bit_offset: xml::required_number(node, "BitOffs")? as u32,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EniSlave {
+    /// Ring position, 1 based like `slavelist`.
+    pub position: u16,
+    pub name: Option<String>,
+    /// Configured station address.
+    pub phys_addr: Option<u16>,
+    pub alias: Option<u16>,
+    pub vendor_id: u32,
+    pub product_code: u32,
+    pub revision: u32,
+    pub serial: Option<u32>,
+    pub mailbox: Option<EniMailbox>,
+    pub sync_managers: Vec<EniSyncManager>,
+    pub fmmus: Vec<EniFmmu>,
+    pub rx_pdos: Vec<EsiPdo>,
+    pub tx_pdos: Vec<EsiPdo>,
+    /// Output bits in the process image.
+    pub outputs: Option<BitRange>,
+    /// Input bits in the process image.
+    pub inputs: Option<BitRange>,
+    pub coe_cmds: Vec<EniCoeCmd>,
+}
+
+impl EniSlave {
+    fn parse(node: Node, position: u16) -> Result<EniSlave, XmlError> {
+        let info = xml::required(node, "Info")?;
+        // AutoIncAddr counts down from 0 for the first slave
+        let position = match xml::child_number(info, "AutoIncAddr")? {
+            Some(addr) => (1 - addr) as u16,
+            None => position,
+        };
+        let mut coe_cmds = Vec::new();
+        if let Some(cmds) = xml::child(node, "Mailbox")
+            .and_then(|m| xml::child(m, "CoE"))
+            .and_then(|c| xml::child(c, "InitCmds"))
+        {
+            for cmd in xml::children(cmds, "InitCmd") {
+                if xml::child_text(cmd, "Disabled") != Some("1") {
+                    coe_cmds.push(EniCoeCmd::parse(cmd)?);
+                }
+            }
+        }
+        let mailbox = match xml::child(node, "Mailbox") {
+            Some(mbx) => {
+                let area = |name: &str| -> Result<(u16, u16), XmlError> {
+                    match xml::child(mbx, name) {
+                        Some(n) => Ok((
+                            xml::required_number(n, "Start")? as u16,
+                            xml::required_number(n, "Length")? as u16,
+                        )),
+                        None => Ok((0, 0)),
+                    }
+                };
+                let (send_start, send_length) = area("Send")?;
+                let (recv_start, recv_length) = area("Recv")?;
+                Some(EniMailbox {
+                    send_start,
+                    send_length,
+                    recv_start,
+                    recv_length,
+                    protocols: xml::children(mbx, "Protocol")
+                        .map(|p| xml::text(p).to_string())
+                        .collect(),
+                })
+            }
+            None => None,
+        };
+
+        let mut sync_managers = Vec::new();
+        let mut rx_pdos = Vec::new();
+        let mut tx_pdos = Vec::new();
+        let mut outputs = None;
+        let mut inputs = None;
+        if let Some(pd) = xml::child(node, "ProcessData") {
+            let range = |name: &str| -> Result<Option<BitRange>, XmlError> {
+                match xml::child(pd, name) {
+                    Some(n) => Ok(Some(BitRange {
+                        start: xml::required_number(n, "BitStart")? as u32,
+                        length: xml::required_number(n, "BitLength")? as u32,
+                    })),
+                    None => Ok(None),
+                }
+            };
+            outputs = range("Send")?;
+            inputs = range("Recv")?;
+            for index in 0..EC_MAXSM as u8 {
+                let Some(sm) = xml::child(pd, &format!("Sm{}", index)) else {
+                    continue;
+                };
+                sync_managers.push(EniSyncManager {
+                    index,
+                    kind: SyncManagerKind::parse(xml::child_text(sm, "Type").unwrap_or_default()),
+                    start_address: xml::required_number(sm, "StartAddress")? as u16,
+                    length: xml::child_number(sm, "DefaultSize")?.unwrap_or(0) as u16,
+                    control: xml::child_number(sm, "ControlByte")?.unwrap_or(0) as u8,
+                    enable: matches!(xml::child_text(sm, "Enable"), Some("1") | Some("true")),
+                    pdos: xml::children(sm, "Pdo")
+                        .map(|p| xml::parse_number("Pdo", xml::text(p)).map(|v| v as u16))
+                        .collect::<Result<_, _>>()?,
+                });
+            }
+            rx_pdos = xml::children(pd, "RxPdo")
+                .map(EsiPdo::parse)
+                .collect::<Result<_, _>>()?;
+            tx_pdos = xml::children(pd, "TxPdo")
+                .map(EsiPdo::parse)
+                .collect::<Result<_, _>>()?;
+        }
+
+        // FMMUs are configured through register init commands
+        let mut fmmus = Vec::new();
+        if let Some(cmds) = xml::child(node, "InitCmds") {
+            for cmd in xml::children(cmds, "InitCmd") {
+                let ado = xml::required_number(cmd, "Ado")? as u32;
+                if !(ECT_REG_FMMU0..ECT_REG_FMMU0 + 0x100).contains(&ado) {
+                    continue;
+                }
+                let data =
+                    xml::parse_hex_binary("Data", xml::child_text(cmd, "Data").unwrap_or(""))?;
+                if let Some(fmmu) = EniFmmu::from_bytes(&data) {
+                    fmmus.push(fmmu);
+                }
+            }
+        }
+
+        Ok(EniSlave {
+            position,
+            name: xml::child_text(info, "Name").map(str::to_string),
+            phys_addr: xml::child_number(info, "PhysAddr")?.map(|v| v as u16),
+            alias: xml::child(info, "Identification")
+                .map(|id| xml::attr_number(id, "Value"))
+                .transpose()?
+                .flatten()
+                .map(|v| v as u16),
+            vendor_id: xml::required_number(info, "VendorId")? as u32,
+            product_code: xml::required_number(info, "ProductCode")? as u32,
+            revision: xml::required_number(info, "RevisionNo")? as u32,
+            serial: xml::child_number(info, "SerialNo")?.map(|v| v as u32),
+            mailbox,
+            sync_managers,
+            fmmus,
+            rx_pdos,
+            tx_pdos,
+            outputs,
+            inputs,
+            coe_cmds,
+        })
+    }
+
+    fn write(&self, w: &mut XmlWriter) {
+        w.open("Slave", &[]);
+        w.open("Info", &[]);
+        if let Some(name) = &self.name {
+            w.text("Name", name);
+        }
+        if let Some(addr) = self.phys_addr {
+            w.text("PhysAddr", addr);
+        }
+        w.text("AutoIncAddr", 1 - self.position as i32);
+        if let Some(alias) = self.alias {
+            w.element("Identification", &[("Value", alias.to_string())], "");
+        }
+        w.text("VendorId", format!("#x{:08x}", self.vendor_id));
+        w.text("ProductCode", format!("#x{:08x}", self.product_code));
+        w.text("RevisionNo", format!("#x{:08x}", self.revision));
+        if let Some(serial) = self.serial {
+            w.text("SerialNo", format!("#x{:08x}", serial));
+        }
+        w.close();
+
+        w.open("ProcessData", &[]);
+        for (name, range) in [("Send", self.outputs), ("Recv", self.inputs)] {
+            if let Some(range) = range {
+                w.open(name, &[]);
+                w.text("BitStart", range.start);
+                w.text("BitLength", range.length);
+                w.close();
+            }
+        }
+        for sm in &self.sync_managers {
+            w.open(&format!("Sm{}", sm.index), &[]);
+            w.text("Type", sm.kind.name());
+            w.text("DefaultSize", sm.length);
+            w.text("StartAddress", format!("#x{:04x}", sm.start_address));
+            w.text("ControlByte", format!("#x{:02x}", sm.control));
+            w.text("Enable", sm.enable);
+            for pdo in &sm.pdos {
+                w.text("Pdo", format!("#x{:04x}", pdo));
+            }
+            w.close();
+        }
+        for (name, pdos) in [("RxPdo", &self.rx_pdos), ("TxPdo", &self.tx_pdos)] {
+            for pdo in pdos {
+                let mut attrs = Vec::new();
+                if pdo.fixed {
+                    attrs.push(("Fixed", "1".to_string()));
+                }
+                if let Some(sm) = pdo.sync_manager {
+                    attrs.push(("Sm", sm.to_string()));
+                }
+                w.open(name, &attrs);
+                w.text("Index", format!("#x{:04x}", pdo.index));
+                if let Some(n) = &pdo.name {
+                    w.text("Name", n);
+                }
+                for e in &pdo.entries {
+                    w.open("Entry", &[]);
+                    w.text("Index", format!("#x{:04x}", e.index));
+                    w.text("SubIndex", e.subindex);
+                    w.text("BitLen", e.bit_length);
+                    if let Some(n) = &e.name {
+                        w.text("Name", n);
+                    }
+                    if let Some(t) = &e.data_type {
+                        w.text("DataType", t);
+                    }
+                    w.close();
+                }
+                w.close();
+            }
+        }
+        w.close();
+
+        if let Some(mbx) = &self.mailbox {
+            w.open("Mailbox", &[("DataLinkLayer", "true".to_string())]);
+            for (name, start, length) in [
+                ("Send", mbx.send_start, mbx.send_length),
+                ("Recv", mbx.recv_start, mbx.recv_length),
+            ] {
+                w.open(name, &[]);
+                w.text("Start", format!("#x{:04x}", start));
+                w.text("Length", length);
+                w.close();
+            }
+            for p in &mbx.protocols {
+                w.text("Protocol", p);
+            }
+            if !self.coe_cmds.is_empty() {
+                w.open("CoE", &[]);
+                w.open("InitCmds", &[]);
+                for cmd in &self.coe_cmds {
+                    let mut attrs = vec![("Fixed", "1".to_string())];
+                    if cmd.complete_access {
+                        attrs.push(("CompleteAccess", "1".to_string()));
+                    }
+                    w.open("InitCmd", &attrs);
+                    for t in transition_names(cmd.transitions) {
+                        w.text("Transition", t);
+                    }
+                    if let Some(c) = &cmd.comment {
+                        w.text("Comment", c);
+                    }
+                    w.text("Timeout", cmd.timeout_ms);
+                    w.text("Ccs", cmd.ccs);
+                    w.text("Index", format!("#x{:04x}", cmd.index));
+                    w.text("SubIndex", cmd.subindex);
+                    if !cmd.data.is_empty() {
+                        w.text("Data", xml::hex_binary(&cmd.data));
+                    }
+                    w.close();
+                }
+                w.close();
+                w.close();
+            }
+            w.close();
+        }
+
+        if !self.fmmus.is_empty() {
+            w.open("InitCmds", &[]);
+            for (n, fmmu) in self.fmmus.iter().enumerate() {
+                w.open("InitCmd", &[]);
+                w.text("Transition", "PS");
+                w.text("Comment", format!("set FMMU {}", n));
+                // FPWR
+                w.text("Cmd", ec_cmdtype_EC_CMD_FPWR);
+                w.text("Adp", self.phys_addr.unwrap_or(0));
+                w.text("Ado", format!("#x{:04x}", ECT_REG_FMMU0 as usize + 16 * n));
+                w.text("Data", xml::hex_binary(&fmmu.to_bytes()));
+                w.text("Cnt", 1);
+                w.close();
+            }
+            w.close();
+        }
+        w.close();
+    }
+}
+
+/// Parsed network configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Eni {
+    pub slaves: Vec<EniSlave>,
+    pub input_bytes: u32,
+    pub output_bytes: u32,
+    pub inputs: Vec<EniVariable>,
+    pub outputs: Vec<EniVariable>,
+}
+
+impl Eni {
+    pub fn parse(text: &str) -> Result<Eni, EniError> {
+        let doc = Document::parse(text)?;
+        let root = doc.root_element();
+        let root = if root.has_tag_name("EtherCATConfig") {
+            root
+        } else {
+            xml::required(root, "EtherCATConfig")?
+        };
+        let config = xml::required(root, "Config")?;
+        let mut slaves = Vec::new();
+        for (i, slave) in xml::children(config, "Slave").enumerate() {
+            slaves.push(EniSlave::parse(slave, i as u16 + 1)?);
+        }
+        slaves.sort_by_key(|s| s.position);
+
+        let mut eni = Eni {
+            slaves,
+            ..Default::default()
+        };
+        if let Some(image) = xml::child(config, "ProcessImage") {
+            if let Some(inputs) = xml::child(image, "Inputs") {
+                eni.input_bytes = xml::child_number(inputs, "ByteSize")?.unwrap_or(0) as u32;
+                eni.inputs = xml::children(inputs, "Variable")
+                    .map(EniVariable::parse)
+                    .collect::<Result<_, _>>()?;
+            }
+            if let Some(outputs) = xml::child(image, "Outputs") {
+                eni.output_bytes = xml::child_number(outputs, "ByteSize")?.unwrap_or(0) as u32;
+                eni.outputs = xml::children(outputs, "Variable")
+                    .map(EniVariable::parse)
+                    .collect::<Result<_, _>>()?;
+            }
+        }
+        Ok(eni)
+    }
+
+    /// Serialise to ENI XML that [`Eni::parse`] reads back.
+    pub fn to_xml(&self) -> String {
+        let mut w = XmlWriter::new();
+        w.open("EtherCATConfig", &[("Version", "1.3".to_string())]);
+        w.open("Config", &[]);
+        w.open("Master", &[]);
+        w.open("Info", &[]);
+        w.text("Name", "SOEM");
+        w.close();
+        w.close();
+        for slave in &self.slaves {
+            slave.write(&mut w);
+        }
+        w.open("ProcessImage", &[]);
+        for (name, bytes, vars) in [
+            ("Inputs", self.input_bytes, &self.inputs),
+            ("Outputs", self.output_bytes, &self.outputs),
+        ] {
+            w.open(name, &[]);
+            w.text("ByteSize", bytes);
+            for var in vars {
+                w.open("Variable", &[]);
+                w.text("Name", &var.name);
+                if let Some(t) = &var.data_type {
+                    w.text("DataType", t);
+                }
+                w.text("BitSize", var.bit_size);
+                w.text("BitOffs", var.bit_offset);
+                w.close();
+            }
+            w.close();
+        }
+        w.finish()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), EniError> {
+        Ok(fs::write(path, self.to_xml())?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Eni, EniError> {
+        Eni::parse(&xml::decode(fs::read(path)?))
+    }
+
+    /// Parse the ENI embedded at build time, if any.
+    pub fn embedded() -> Option<Result<Eni, EniError>> {
+        EMBEDDED_ENI.map(Eni::parse)
+    }
+
+    pub fn slave(&self, position: u16) -> Option<&EniSlave> {
+        self.slaves.iter().find(|s| s.position == position)
+    }
+}
+
+/// Owned `ec_enit` tables built from an [`Eni`]. The raw pointers handed to SOEM
+/// point into the heap buffers held here, so the table must outlive its use by the
+/// context.
+pub struct EniTable {
+    source: Eni,
+    eni: Box<ec_enit>,
+    slaves: Vec<ec_enislavet>,
+    _cmds: Vec<Vec<ec_enicoecmdt>>,
+    _data: Vec<Vec<u8>>,
+}
+
+impl EniTable {
+    pub fn new(eni: &Eni) -> Self {
+        let mut data: Vec<Vec<u8>> = Vec::new();
+        let mut cmds: Vec<Vec<ec_enicoecmdt>> = Vec::new();
+        let mut slaves = Vec::new();
+
+        // like eniconv.py only slaves with CoE commands are listed, sorted by position
+        for slave in eni.slaves.iter().filter(|s| !s.coe_cmds.is_empty()) {
+            let mut slave_cmds = Vec::with_capacity(slave.coe_cmds.len());
+            for cmd in &slave.coe_cmds {
+                let mut buf = cmd.data.clone();
+                let data_ptr = if buf.is_empty() {
+                    ptr::null_mut()
+                } else {
+                    buf.as_mut_ptr()
+                };
+                data.push(buf);
+                slave_cmds.push(ec_enicoecmdt {
+                    Transition: cmd.transitions,
+                    CA: cmd.complete_access as boolean,
+                    Ccs: cmd.ccs,
+                    Index: cmd.index,
+                    SubIdx: cmd.subindex,
+                    Timeout: (cmd.timeout_ms as i32).saturating_mul(1000),
+                    DataSize: cmd.data.len() as i32,
+                    Data: data_ptr as *mut c_void,
+                });
+            }
+            slaves.push(ec_enislavet {
+                Slave: slave.position,
+                VendorId: slave.vendor_id,
+                ProductCode: slave.product_code,
+                RevisionNo: slave.revision,
+                CoECmds: slave_cmds.as_mut_ptr(),
+                CoECmdCount: slave_cmds.len() as i32,
+            });
+            cmds.push(slave_cmds);
+        }
+
+        let table = Box::new(ec_enit {
+            slave: if slaves.is_empty() {
+                ptr::null_mut()
+            } else {
+                slaves.as_mut_ptr()
+            },
+            slavecount: slaves.len() as i32,
+        });
+        EniTable {
+            source: eni.clone(),
+            eni: table,
+            slaves,
+            _cmds: cmds,
+            _data: data,
+        }
+    }
+
+    /// The configuration this table was built from.
+    pub fn eni(&self) -> &Eni {
+        &self.source
+    }
+
+    pub fn slavecount(&self) -> usize {
+        self.slaves.len()
+    }
+
+    /// Pointer suitable for `ecx_contextt::ENI`.
+    pub fn as_mut_ptr(&mut self) -> *mut ec_enit {
+        &mut *self.eni
+    }
+}
+
+fn sdo_read<const N: usize>(
+    context: *mut ecx_contextt,
+    slave: u16,
+    index: u16,
+    subindex: u8,
+) -> Option<[u8; N]> {
+    let mut buf = [0u8; N];
+    let mut size = N as i32;
+    let wkc = unsafe {
+        ecx_SDOread(
+            context,
+            slave,
+            index,
+            subindex,
+            0,
+            &mut size,
+            buf.as_mut_ptr() as *mut c_void,
+            EC_TIMEOUTRXM as i32,
+        )
+    };
+    (wkc > 0).then_some(buf)
+}
+
+/// Object access bit for writing in PRE_OP, ETG.1000.6 SDO information.
+const OBJ_ACCESS_WRITE_PRE_OP: u16 = 0x0008;
+
+/// Read the PDOs assigned to SyncManager `sm` through objects 0x1C1x and their
+/// mapping objects.
+fn read_pdo_assign(context: *mut ecx_contextt, slave: u16, sm: u8) -> Option<Vec<EsiPdo>> {
+    let assign = ECT_SDO_PDOASSIGN as u16 + sm as u16;
+    let count = sdo_read::<1>(context, slave, assign, 0)?[0];
+    let mut pdos = Vec::with_capacity(count as usize);
+    for i in 1..=count {
+        let index = u16::from_le_bytes(sdo_read::<2>(context, slave, assign, i)?);
+        let entries = sdo_read::<1>(context, slave, index, 0)?[0];
+        let mut pdo = EsiPdo {
+            index,
+            sync_manager: Some(sm),
+            fixed: !mapping_writable(context, slave, index),
+            ..Default::default()
+        };
+        for j in 1..=entries {
+            let mapping = u32::from_le_bytes(sdo_read::<4>(context, slave, index, j)?);
+            pdo.entries.push(EsiPdoEntry {
+                index: (mapping >> 16) as u16,
+                subindex: (mapping >> 8) as u8,
+                bit_length: (mapping & 0xFF) as u16,
+                ..Default::default()
+            });
+        }
+        pdos.push(pdo);
+    }
+    Some(pdos)
+}
+
+/// Whether the mapping object `index` can be written in PRE_OP, going by the SDO
+/// information of the slave. Without SDO information the mapping is taken as fixed.
+fn mapping_writable(context: *mut ecx_contextt, slave: u16, index: u16) -> bool {
+    let s = unsafe { &(*context).slavelist[slave as usize] };
+    if s.CoEdetails as u32 & ECT_COEDET_SDOINFO == 0 {
+        return false;
+    }
+    // the lists are large, keep them off the stack
+    let mut od: Box<ec_ODlistt> = unsafe { Box::new_zeroed().assume_init() };
+    let mut oe: Box<ec_OElistt> = unsafe { Box::new_zeroed().assume_init() };
+    od.Slave = slave;
+    od.Entries = 1;
+    od.Index[0] = index;
+    let wkc = unsafe { ecx_readOEsingle(context, 0, 0, &mut *od, &mut *oe) };
+    wkc > 0 && oe.ObjAccess[0] & OBJ_ACCESS_WRITE_PRE_OP != 0
+}
+
+/// CoE commands that reproduce the PDO assignment of SyncManager `sm` in
+/// PRE_OP->SAFE_OP, writing the mapping of each PDO that is not fixed in between
+/// clearing and filling in the assignment.
+fn pdo_assign_cmds(sm: u8, pdos: &[&EsiPdo]) -> Vec<EniCoeCmd> {
+    let assign = ECT_SDO_PDOASSIGN as u16 + sm as u16;
+    let cmd = |index: u16, subindex: u8, data: Vec<u8>, comment: String| EniCoeCmd {
+        transitions: ECT_ESMTRANS_PS as u16,
+        ccs: 2,
+        index,
+        subindex,
+        timeout_ms: 1000,
+        data,
+        comment: Some(comment),
+        ..Default::default()
+    };
+    let mut cmds = vec![cmd(
+        assign,
+        0,
+        vec![0],
+        format!("clear SM{} PDO assignment", sm),
+    )];
+    for pdo in pdos.iter().filter(|p| !p.fixed) {
+        cmds.push(cmd(
+            pdo.index,
+            0,
+            vec![0],
+            format!("clear PDO #x{:04x} mapping", pdo.index),
+        ));
+        for (i, e) in pdo.entries.iter().enumerate() {
+            let mapping = (e.index as u32) << 16 | (e.subindex as u32) << 8 | e.bit_length as u32;
+            cmds.push(cmd(
+                pdo.index,
+                i as u8 + 1,
+                mapping.to_le_bytes().to_vec(),
+                format!(
+                    "map #x{:04x}:{:02x} to PDO #x{:04x}",
+                    e.index, e.subindex, pdo.index
+                ),
+            ));
+        }
+        cmds.push(cmd(
+            pdo.index,
+            0,
+            vec![pdo.entries.len() as u8],
+            format!("PDO #x{:04x} entry count", pdo.index),
+        ));
+    }
+    for (i, pdo) in pdos.iter().enumerate() {
+        cmds.push(cmd(
+            assign,
+            i as u8 + 1,
+            pdo.index.to_le_bytes().to_vec(),
+            format!("assign PDO #x{:04x} to SM{}", pdo.index, sm),
+        ));
+    }
+    cmds.push(cmd(
+        assign,
+        0,
+        vec![pdos.len() as u8],
+        format!("SM{} PDO count", sm),
+    ));
+    cmds
+}
+
+fn protocol_names(mbx_proto: u16) -> Vec<String> {
+    [
+        (ECT_MBXPROT_AOE, "AoE"),
+        (ECT_MBXPROT_EOE, "EoE"),
+        (ECT_MBXPROT_COE, "CoE"),
+        (ECT_MBXPROT_FOE, "FoE"),
+        (ECT_MBXPROT_SOE, "SoE"),
+        (ECT_MBXPROT_VOE, "VoE"),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| mbx_proto as u32 & bit != 0)
+    .map(|(_, name)| name.to_string())
+    .collect()
+}
+
+impl Fieldbus {
+    /// Describe the bus as configured by `start`: identities, addresses,
+    /// SyncManager/FMMU setup, PDO assignment, process image layout and the CoE init
+    /// commands of an attached ENI. PDO assignments, and the mapping of PDOs that are
+    /// not fixed, are read back over CoE and turned into init commands, so
+    /// re-importing the result reproduces the mapping.
+    ///
+    /// Bit offsets are relative to the start of the IOmap.
+    pub fn export_eni(&mut self) -> Eni {
+        let context = &mut *self.context as *mut ecx_contextt;
+        let map = self.map.as_ptr() as usize;
+        let applied = self
+            .eni
+            .as_ref()
+            .map(|t| t.eni().clone())
+            .unwrap_or_default();
+        let group = self.context.grouplist[self.group as usize];
+        let mut eni = Eni {
+            input_bytes: group.Ibytes,
+            output_bytes: group.Obytes,
+            ..Default::default()
+        };
+
+        for position in 1..=self.context.slavecount as u16 {
+            let s = self.context.slavelist[position as usize];
+            let name = unsafe { std::ffi::CStr::from_ptr(s.name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned();
+            let mut slave = EniSlave {
+                position,
+                name: Some(name.clone()),
+                phys_addr: Some(s.configadr),
+                alias: (s.aliasadr != 0).then_some(s.aliasadr),
+                vendor_id: s.eep_man,
+                product_code: s.eep_id,
+                revision: s.eep_rev,
+                serial: (s.eep_ser != 0).then_some(s.eep_ser),
+                ..Default::default()
+            };
+
+            if s.mbx_l > 0 {
+                slave.mailbox = Some(EniMailbox {
+                    send_start: s.mbx_wo,
+                    send_length: s.mbx_l,
+                    recv_start: s.mbx_ro,
+                    recv_length: s.mbx_rl,
+                    protocols: protocol_names(s.mbx_proto),
+                });
+            }
+
+            let has_coe = s.mbx_proto as u32 & ECT_MBXPROT_COE != 0;
+            for (i, sm) in s.SM.iter().enumerate() {
+                if sm.StartAddr == 0 {
+                    continue;
+                }
+                let kind = SyncManagerKind::from_sm_type(s.SMtype[i]);
+                let mut pdos = Vec::new();
+                if has_coe && matches!(kind, SyncManagerKind::Outputs | SyncManagerKind::Inputs) {
+                    let assigned = read_pdo_assign(context, position, i as u8).unwrap_or_default();
+                    pdos = assigned.iter().map(|p| p.index).collect();
+                    if kind == SyncManagerKind::Outputs {
+                        slave.rx_pdos.extend(assigned);
+                    } else {
+                        slave.tx_pdos.extend(assigned);
+                    }
+                }
+                slave.sync_managers.push(EniSyncManager {
+                    index: i as u8,
+                    kind,
+                    start_address: sm.StartAddr,
+                    length: sm.SMlength,
+                    control: sm.SMflags as u8,
+                    enable: (sm.SMflags >> 16) & 0x01 != 0,
+                    pdos,
+                });
+            }
+
+            for fmmu in s.FMMU.iter().filter(|f| f.FMMUactive != 0) {
+                slave.fmmus.push(EniFmmu {
+                    logical_start: fmmu.LogStart,
+                    length: fmmu.LogLength,
+                    logical_start_bit: fmmu.LogStartbit,
+                    logical_end_bit: fmmu.LogEndbit,
+                    physical_start: fmmu.PhysStart,
+                    physical_start_bit: fmmu.PhysStartBit,
+                    fmmu_type: fmmu.FMMUtype,
+                    active: true,
+                });
+            }
+
+            if s.Obits > 0 && !s.outputs.is_null() {
+                let start = (s.outputs as usize - map) as u32 * 8 + s.Ostartbit as u32;
+                slave.outputs = Some(BitRange {
+                    start,
+                    length: s.Obits as u32,
+                });
+            }
+            if s.Ibits > 0 && !s.inputs.is_null() {
+                let start = (s.inputs as usize - map) as u32 * 8 + s.Istartbit as u32;
+                slave.inputs = Some(BitRange {
+                    start,
+                    length: s.Ibits as u32,
+                });
+            }
+
+            // commands applied from an attached ENI, then the PDO assignment unless the
+            // ENI already takes care of it
+            if let Some(previous) = applied.slave(position).filter(|p| {
+                p.vendor_id == s.eep_man && p.product_code == s.eep_id && p.revision == s.eep_rev
+            }) {
+                slave.coe_cmds = previous.coe_cmds.clone();
+            }
+            for sm in &slave.sync_managers {
+                let assign = ECT_SDO_PDOASSIGN as u16 + sm.index as u16;
+                if sm.pdos.is_empty() || slave.coe_cmds.iter().any(|c| c.index == assign) {
+                    continue;
+                }
+                let pdos: Vec<&EsiPdo> = slave
+                    .rx_pdos
+                    .iter()
+                    .chain(&slave.tx_pdos)
+                    .filter(|p| p.sync_manager == Some(sm.index))
+                    .collect();
+                slave.coe_cmds.extend(pdo_assign_cmds(sm.index, &pdos));
+            }
+
+            let prefix = format!("Slave_{} ({})", position, name);
+            for (range, pdos, vars) in [
+                (slave.outputs, &slave.rx_pdos, &mut eni.outputs),
+                (slave.inputs, &slave.tx_pdos, &mut eni.inputs),
+            ] {
+                let Some(range) = range else { continue };
+                let mut offset = range.start;
+                for pdo in pdos {
+                    let pdo_name = pdo
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| format!("#x{:04x}", pdo.index));
+                    for e in &pdo.entries {
+                        if e.index != 0 {
+                            vars.push(EniVariable {
+                                name: format!(
+                                    "{}.{}.#x{:04x}:{:02x}",
+                                    prefix, pdo_name, e.index, e.subindex
+                                ),
+                                data_type: e.data_type.clone(),
+                                bit_size: e.bit_length as u32,
+                                bit_offset: offset,
+                            });
+                        }
+                        offset += e.bit_length as u32;
+                    }
+                }
+            }
+
+            eni.slaves.push(slave);
+        }
+        eni
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENI: &str = r##"<?xml version="1.0" encoding="utf-8"?>
+<EtherCATConfig Version="1.3">
+  <Config>
+    <Slave>
+      <Info>
+        <Name>Term 2 (EL7031)</Name>
+        <PhysAddr>1002</PhysAddr>
+        <AutoIncAddr>-1</AutoIncAddr>
+        <VendorId>2</VendorId>
+        <ProductCode>#x1b773052</ProductCode>
+        <RevisionNo>#x00190000</RevisionNo>
+      </Info>
+      <ProcessData>
+        <Send>
+          <BitStart>0</BitStart>
+          <BitLength>64</BitLength>
+        </Send>
+        <Sm2>
+          <Type>Outputs</Type>
+          <DefaultSize>8</DefaultSize>
+          <StartAddress>#x1100</StartAddress>
+          <ControlByte>#x64</ControlByte>
+          <Enable>1</Enable>
+          <Pdo>#x1602</Pdo>
+        </Sm2>
+      </ProcessData>
+      <Mailbox DataLinkLayer="true">
+        <Send>
+          <Start>#x1000</Start>
+          <Length>128</Length>
+        </Send>
+        <Recv>
+          <Start>#x1080</Start>
+          <Length>128</Length>
+        </Recv>
+        <Protocol>CoE</Protocol>
+        <CoE>
+          <InitCmds>
+            <InitCmd Fixed="1" CompleteAccess="1">
+              <Transition>PS</Transition>
+              <Comment>download pdo 0x1C12 index</Comment>
+              <Timeout>0</Timeout>
+              <Ccs>2</Ccs>
+              <Index>#x1c12</Index>
+              <SubIndex>0</SubIndex>
+              <Data>01000216</Data>
+            </InitCmd>
+            <InitCmd>
+              <Transition>PS</Transition>
+              <Timeout>0</Timeout>
+              <Ccs>2</Ccs>
+              <Index>#x8010</Index>
+              <SubIndex>1</SubIndex>
+              <Data>c409</Data>
+              <Disabled>1</Disabled>
+            </InitCmd>
+            <InitCmd>
+              <Transition>IP</Transition>
+              <Transition>SO</Transition>
+              <Timeout>100</Timeout>
+              <Ccs>1</Ccs>
+              <Index>#x1018</Index>
+              <SubIndex>1</SubIndex>
+            </InitCmd>
+          </InitCmds>
+        </CoE>
+      </Mailbox>
+      <InitCmds>
+        <InitCmd>
+          <Transition>PS</Transition>
+          <Cmd>5</Cmd>
+          <Adp>1002</Adp>
+          <Ado>#x0600</Ado>
+          <Data>00000000080000070011000201000000</Data>
+          <Cnt>1</Cnt>
+        </InitCmd>
+        <InitCmd>
+          <Transition>PS</Transition>
+          <Cmd>5</Cmd>
+          <Adp>1002</Adp>
+          <Ado>#x0800</Ado>
+          <Data>0011080064000100</Data>
+          <Cnt>1</Cnt>
+        </InitCmd>
+      </InitCmds>
+    </Slave>
+    <Slave>
+      <Info>
+        <Name>Term 1 (EK1100)</Name>
+        <AutoIncAddr>0</AutoIncAddr>
+        <VendorId>2</VendorId>
+        <ProductCode>#x044c2c52</ProductCode>
+        <RevisionNo>#x00120000</RevisionNo>
+      </Info>
+    </Slave>
+    <ProcessImage>
+      <Outputs>
+        <ByteSize>8</ByteSize>
+        <Variable>
+          <Name>Term 2 (EL7031).Control</Name>
+          <DataType>UINT</DataType>
+          <BitSize>16</BitSize>
+          <BitOffs>0</BitOffs>
+        </Variable>
+      </Outputs>
+    </ProcessImage>
+  </Config>
+</EtherCATConfig>"##;
+
+    #[test]
+    fn parse_slaves() {
+        let eni = Eni::parse(ENI).unwrap();
+        assert_eq!(eni.slaves.len(), 2);
+        // sorted by AutoIncAddr, not document order
+        assert_eq!(eni.slaves[0].position, 1);
+        assert_eq!(eni.slaves[0].product_code, 0x044c2c52);
+        let drive = eni.slave(2).unwrap();
+        assert_eq!(drive.phys_addr, Some(1002));
+        assert_eq!(drive.revision, 0x00190000);
+        assert_eq!(
+            drive.outputs,
+            Some(BitRange {
+                start: 0,
+                length: 64
+            })
+        );
+        assert_eq!(drive.sync_managers.len(), 1);
+        assert_eq!(drive.sync_managers[0].index, 2);
+        assert_eq!(drive.sync_managers[0].pdos, [0x1602]);
+        let mbx = drive.mailbox.as_ref().unwrap();
+        assert_eq!((mbx.send_start, mbx.recv_length), (0x1000, 128));
+        assert_eq!(mbx.protocols, ["CoE"]);
+        assert_eq!(eni.output_bytes, 8);
+        assert_eq!(eni.outputs[0].bit_size, 16);
+    }
+
+    #[test]
+    fn coe_init_commands() {
+        let eni = Eni::parse(ENI).unwrap();
+        let cmds = &eni.slave(2).unwrap().coe_cmds;
+        // the disabled command is dropped
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(cmds[0].transitions, ECT_ESMTRANS_PS as u16);
+        assert!(cmds[0].complete_access);
+        assert_eq!((cmds[0].ccs, cmds[0].index), (2, 0x1c12));
+        assert_eq!(cmds[0].data, [0x01, 0x00, 0x02, 0x16]);
+        assert_eq!(
+            cmds[1].transitions,
+            (ECT_ESMTRANS_IP | ECT_ESMTRANS_SO) as u16
+        );
+        assert_eq!(cmds[1].timeout_ms, 100);
+        assert!(cmds[1].data.is_empty());
+    }
+
+    #[test]
+    fn fmmus_from_register_commands() {
+        let eni = Eni::parse(ENI).unwrap();
+        // the SyncManager command at 0x0800 is not an FMMU
+        let fmmus = &eni.slave(2).unwrap().fmmus;
+        assert_eq!(fmmus.len(), 1);
+        assert_eq!(fmmus[0].length, 8);
+        assert_eq!(fmmus[0].logical_end_bit, 7);
+        assert_eq!(fmmus[0].physical_start, 0x1100);
+        assert_eq!(fmmus[0].fmmu_type, 2);
+        assert!(fmmus[0].active);
+        assert_eq!(EniFmmu::from_bytes(&fmmus[0].to_bytes()), Some(fmmus[0]));
+    }
+
+    #[test]
+    fn table_lists_slaves_with_commands() {
+        let eni = Eni::parse(ENI).unwrap();
+        let mut table = EniTable::new(&eni);
+        assert_eq!(table.slavecount(), 1);
+        let raw = unsafe { &*table.as_mut_ptr() };
+        assert_eq!(raw.slavecount, 1);
+        let slave = unsafe { &*raw.slave };
+        assert_eq!(slave.Slave, 2);
+        assert_eq!(slave.CoECmdCount, 2);
+        let cmds = unsafe { std::slice::from_raw_parts(slave.CoECmds, 2) };
+        assert_eq!(cmds[0].DataSize, 4);
+        let data = unsafe { std::slice::from_raw_parts(cmds[0].Data as *const u8, 4) };
+        assert_eq!(data, [0x01, 0x00, 0x02, 0x16]);
+        assert_eq!(cmds[1].Timeout, 100_000);
+        assert!(cmds[1].Data.is_null());
+    }
+
+    #[test]
+    fn pdo_commands() {
+        let mapped = EsiPdo {
+            index: 0x1600,
+            sync_manager: Some(2),
+            entries: vec![
+                EsiPdoEntry {
+                    index: 0x7000,
+                    subindex: 1,
+                    bit_length: 16,
+                    ..Default::default()
+                },
+                EsiPdoEntry {
+                    index: 0,
+                    subindex: 0,
+                    bit_length: 8,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let fixed = EsiPdo {
+            index: 0x1602,
+            sync_manager: Some(2),
+            fixed: true,
+            ..Default::default()
+        };
+        let cmds = pdo_assign_cmds(2, &[&mapped, &fixed]);
+        let writes: Vec<(u16, u8, &[u8])> = cmds
+            .iter()
+            .map(|c| (c.index, c.subindex, c.data.as_slice()))
+            .collect();
+        assert_eq!(
+            writes,
+            [
+                (0x1c12, 0, &[0][..]),
+                (0x1600, 0, &[0]),
+                (0x1600, 1, &[0x10, 0x01, 0x00, 0x70]),
+                (0x1600, 2, &[0x08, 0x00, 0x00, 0x00]),
+                (0x1600, 0, &[2]),
+                (0x1c12, 1, &[0x00, 0x16]),
+                (0x1c12, 2, &[0x02, 0x16]),
+                (0x1c12, 0, &[2]),
+            ]
+        );
+        assert!(
+            cmds.iter()
+                .all(|c| c.ccs == 2 && c.transitions == ECT_ESMTRANS_PS as u16)
+        );
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut eni = Eni::parse(ENI).unwrap();
+        let drive = &mut eni.slaves[1];
+        drive.serial = Some(1234);
+        drive.alias = Some(7);
+        drive.rx_pdos.push(EsiPdo {
+            index: 0x1602,
+            name: Some("DRV RxPDO-Map Outputs".to_string()),
+            sync_manager: Some(2),
+            entries: vec![EsiPdoEntry {
+                index: 0x7010,
+                subindex: 1,
+                bit_length: 16,
+                name: Some("Control".to_string()),
+                data_type: Some("UINT".to_string()),
+            }],
+            ..Default::default()
+        });
+        let pdos: Vec<&EsiPdo> = drive.rx_pdos.iter().collect();
+        let cmds = pdo_assign_cmds(2, &pdos);
+        drive.coe_cmds.extend(cmds);
+        assert_eq!(Eni::parse(&eni.to_xml()).unwrap(), eni);
+    }
+
+    #[test]
+    fn missing_config() {
+        assert!(Eni::parse("<EtherCATConfig/>").is_err());
+        assert!(Eni::parse("<EtherCATConfig>").is_err());
+    }
+
+    #[test]
+    fn error_source() {
+        use std::error::Error;
+        let e = Eni::parse("<EtherCATConfig>").unwrap_err();
+        assert!(e.source().is_some());
+    }
+}
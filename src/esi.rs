@@ -0,0 +1,685 @@
+//! Reader for EtherCAT Slave Information (ESI) XML files and a catalogue of the
+//! devices they describe, keyed by vendor ID, product code and revision.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use roxmltree::{Document, Node};
+
+use crate::bindings::*;
+use crate::xml::{self, XmlError};
+
+#[derive(Debug)]
+pub enum EsiError {
+    Io(io::Error),
+    Xml(XmlError),
+    /// An error while reading a specific file of a catalogue directory.
+    File {
+        path: PathBuf,
+        error: Box<EsiError>,
+    },
+}
+
+impl fmt::Display for EsiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EsiError::Io(e) => write!(f, "{}", e),
+            EsiError::Xml(e) => write!(f, "{}", e),
+            EsiError::File { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for EsiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EsiError::Io(e) => Some(e),
+            EsiError::Xml(e) => Some(e),
+            EsiError::File { error, .. } => Some(error.as_ref()),
+        }
+    }
+}
+
+impl From<io::Error> for EsiError {
+    fn from(e: io::Error) -> Self {
+        EsiError::Io(e)
+    }
+}
+
+impl From<XmlError> for EsiError {
+    fn from(e: XmlError) -> Self {
+        EsiError::Xml(e)
+    }
+}
+
+impl From<roxmltree::Error> for EsiError {
+    fn from(e: roxmltree::Error) -> Self {
+        EsiError::Xml(e.into())
+    }
+}
+
+/// Map an ESM transition name such as `"PS"` to its `ECT_ESMTRANS_*` bit.
+pub fn transition_mask(name: &str) -> Option<u16> {
+    let bit = match name.trim() {
+        "IP" => ECT_ESMTRANS_IP,
+        "PS" => ECT_ESMTRANS_PS,
+        "PI" => ECT_ESMTRANS_PI,
+        "SP" => ECT_ESMTRANS_SP,
+        "SO" => ECT_ESMTRANS_SO,
+        "SI" => ECT_ESMTRANS_SI,
+        "OS" => ECT_ESMTRANS_OS,
+        "OP" => ECT_ESMTRANS_OP,
+        "OI" => ECT_ESMTRANS_OI,
+        "IB" => ECT_ESMTRANS_IB,
+        "BI" => ECT_ESMTRANS_BI,
+        "II" => ECT_ESMTRANS_II,
+        "PP" => ECT_ESMTRANS_PP,
+        _ => return None,
+    };
+    Some(bit as u16)
+}
+
+/// Names of all transitions set in an `ECT_ESMTRANS_*` mask.
+pub fn transition_names(mask: u16) -> Vec<&'static str> {
+    [
+        "IP", "PS", "PI", "SP", "SO", "SI", "OS", "OP", "OI", "IB", "BI", "II", "PP",
+    ]
+    .into_iter()
+    .filter(|n| transition_mask(n).is_some_and(|bit| mask & bit != 0))
+    .collect()
+}
+
+pub(crate) fn parse_transitions(node: Node) -> Result<u16, XmlError> {
+    let mut mask = 0;
+    for t in xml::children(node, "Transition") {
+        mask |= transition_mask(xml::text(t)).ok_or_else(|| XmlError::InvalidValue {
+            name: "Transition".to_string(),
+            value: xml::text(t).to_string(),
+        })?;
+    }
+    Ok(mask)
+}
+
+/// Pick the English name if several localised `<Name>` elements are present.
+fn localised_name(node: Node) -> Option<String> {
+    let names: Vec<Node> = xml::children(node, "Name").collect();
+    names
+        .iter()
+        .find(|n| n.attribute("LcId") == Some("1033"))
+        .or(names.first())
+        .map(|n| xml::text(*n).to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceKey {
+    pub vendor_id: u32,
+    pub product_code: u32,
+    pub revision: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EsiPdoEntry {
+    /// Object index, 0 for padding entries.
+    pub index: u16,
+    pub subindex: u8,
+    pub bit_length: u16,
+    pub name: Option<String>,
+    pub data_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EsiPdo {
+    pub index: u16,
+    pub name: Option<String>,
+    /// SyncManager the PDO is assigned to by default, if any.
+    pub sync_manager: Option<u8>,
+    pub fixed: bool,
+    pub mandatory: bool,
+    pub entries: Vec<EsiPdoEntry>,
+}
+
+impl EsiPdo {
+    pub(crate) fn parse(node: Node) -> Result<EsiPdo, XmlError> {
+        let mut entries = Vec::new();
+        for e in xml::children(node, "Entry") {
+            entries.push(EsiPdoEntry {
+                index: xml::required_number(e, "Index")? as u16,
+                subindex: xml::child_number(e, "SubIndex")?.unwrap_or(0) as u8,
+                bit_length: xml::required_number(e, "BitLen")? as u16,
+                name: xml::child_text(e, "Name").map(str::to_string),
+                data_type: xml::child_text(e, "DataType").map(str::to_string),
+            });
+        }
+        Ok(EsiPdo {
+            index: xml::required_number(node, "Index")? as u16,
+            name: localised_name(node),
+            sync_manager: xml::attr_number(node, "Sm")?.map(|v| v as u8),
+            fixed: xml::attr_bool(node, "Fixed"),
+            mandatory: xml::attr_bool(node, "Mandatory"),
+            entries,
+        })
+    }
+
+    pub fn bit_length(&self) -> u32 {
+        self.entries.iter().map(|e| e.bit_length as u32).sum()
+    }
+}
+
+/// A CoE init command, sent by the master during the given state transitions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InitCmd {
+    /// Bitmask of `ECT_ESMTRANS_*` transitions.
+    pub transitions: u16,
+    pub index: u16,
+    pub subindex: u8,
+    pub complete_access: bool,
+    pub data: Vec<u8>,
+    pub comment: Option<String>,
+}
+
+impl InitCmd {
+    pub(crate) fn parse(node: Node) -> Result<InitCmd, XmlError> {
+        let data = match xml::child_text(node, "Data") {
+            Some(d) => xml::parse_hex_binary("Data", d)?,
+            None => Vec::new(),
+        };
+        Ok(InitCmd {
+            transitions: parse_transitions(node)?,
+            index: xml::required_number(node, "Index")? as u16,
+            subindex: xml::child_number(node, "SubIndex")?.unwrap_or(0) as u8,
+            complete_access: xml::attr_bool(node, "CompleteAccess"),
+            data,
+            comment: xml::child_text(node, "Comment").map(str::to_string),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EsiSyncManager {
+    pub start_address: u16,
+    pub default_size: u16,
+    pub control_byte: u8,
+    pub enable: bool,
+    /// `MBoxOut`, `MBoxIn`, `Outputs` or `Inputs`.
+    pub kind: SyncManagerKind,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncManagerKind {
+    #[default]
+    Unused,
+    MailboxOut,
+    MailboxIn,
+    Outputs,
+    Inputs,
+}
+
+impl SyncManagerKind {
+    pub(crate) fn parse(s: &str) -> SyncManagerKind {
+        match s {
+            "MBoxOut" => SyncManagerKind::MailboxOut,
+            "MBoxIn" => SyncManagerKind::MailboxIn,
+            "Outputs" => SyncManagerKind::Outputs,
+            "Inputs" => SyncManagerKind::Inputs,
+            _ => SyncManagerKind::Unused,
+        }
+    }
+
+    /// Name as used in ESI and ENI files.
+    pub fn name(self) -> &'static str {
+        match self {
+            SyncManagerKind::Unused => "Unused",
+            SyncManagerKind::MailboxOut => "MBoxOut",
+            SyncManagerKind::MailboxIn => "MBoxIn",
+            SyncManagerKind::Outputs => "Outputs",
+            SyncManagerKind::Inputs => "Inputs",
+        }
+    }
+
+    pub fn from_sm_type(sm_type: u8) -> SyncManagerKind {
+        match sm_type {
+            1 => SyncManagerKind::MailboxOut,
+            2 => SyncManagerKind::MailboxIn,
+            3 => SyncManagerKind::Outputs,
+            4 => SyncManagerKind::Inputs,
+            _ => SyncManagerKind::Unused,
+        }
+    }
+
+    /// SM type as used in `ec_slave::SMtype`.
+    pub fn sm_type(self) -> u8 {
+        match self {
+            SyncManagerKind::Unused => 0,
+            SyncManagerKind::MailboxOut => 1,
+            SyncManagerKind::MailboxIn => 2,
+            SyncManagerKind::Outputs => 3,
+            SyncManagerKind::Inputs => 4,
+        }
+    }
+}
+
+/// A distributed clock operation mode from the `<Dc>` element.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DcOpMode {
+    pub name: String,
+    pub description: Option<String>,
+    /// Value for the sync activation register pair 0x0980/0x0981.
+    pub assign_activate: u16,
+    /// SYNC0 cycle time in ns; 0 means the bus cycle time multiplied by `sync0_factor`.
+    pub cycle_time_sync0: u32,
+    pub sync0_factor: i32,
+    pub shift_time_sync0: i32,
+    pub cycle_time_sync1: u32,
+    pub sync1_factor: i32,
+    pub shift_time_sync1: i32,
+}
+
+impl DcOpMode {
+    fn parse(node: Node) -> Result<DcOpMode, XmlError> {
+        let factor = |name: &str| -> Result<i32, XmlError> {
+            match xml::child(node, name) {
+                Some(n) => Ok(xml::attr_number(n, "Factor")?.unwrap_or(1) as i32),
+                None => Ok(0),
+            }
+        };
+        Ok(DcOpMode {
+            name: xml::child_text(node, "Name")
+                .unwrap_or_default()
+                .to_string(),
+            description: xml::child_text(node, "Desc").map(str::to_string),
+            assign_activate: xml::child_number(node, "AssignActivate")?.unwrap_or(0) as u16,
+            cycle_time_sync0: xml::child_number(node, "CycleTimeSync0")?.unwrap_or(0) as u32,
+            sync0_factor: factor("CycleTimeSync0")?,
+            shift_time_sync0: xml::child_number(node, "ShiftTimeSync0")?.unwrap_or(0) as i32,
+            cycle_time_sync1: xml::child_number(node, "CycleTimeSync1")?.unwrap_or(0) as u32,
+            sync1_factor: factor("CycleTimeSync1")?,
+            shift_time_sync1: xml::child_number(node, "ShiftTimeSync1")?.unwrap_or(0) as i32,
+        })
+    }
+}
+
+/// One `<Device>` of an ESI file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EsiDevice {
+    pub vendor_id: u32,
+    pub product_code: u32,
+    /// Revision, or `None` if the description applies to all revisions.
+    pub revision: Option<u32>,
+    /// Order number / type name, e.g. `EL1008`.
+    pub type_name: String,
+    pub name: Option<String>,
+    pub group: Option<String>,
+    pub sync_managers: Vec<EsiSyncManager>,
+    pub rx_pdos: Vec<EsiPdo>,
+    pub tx_pdos: Vec<EsiPdo>,
+    pub init_cmds: Vec<InitCmd>,
+    pub dc_modes: Vec<DcOpMode>,
+}
+
+impl EsiDevice {
+    fn parse(node: Node, vendor_id: u32) -> Result<EsiDevice, XmlError> {
+        let ty = xml::required(node, "Type")?;
+        let product_code = xml::attr_number(ty, "ProductCode")?.ok_or(XmlError::Missing {
+            parent: "Type".to_string(),
+            name: "ProductCode".to_string(),
+        })?;
+
+        let mut sync_managers = Vec::new();
+        for sm in xml::children(node, "Sm") {
+            sync_managers.push(EsiSyncManager {
+                start_address: xml::attr_number(sm, "StartAddress")?.unwrap_or(0) as u16,
+                default_size: xml::attr_number(sm, "DefaultSize")?.unwrap_or(0) as u16,
+                control_byte: xml::attr_number(sm, "ControlByte")?.unwrap_or(0) as u8,
+                enable: xml::attr_bool(sm, "Enable"),
+                kind: SyncManagerKind::parse(xml::text(sm)),
+            });
+        }
+
+        let mut init_cmds = Vec::new();
+        if let Some(coe) = xml::child(node, "Mailbox").and_then(|m| xml::child(m, "CoE")) {
+            for cmd in xml::children(coe, "InitCmd") {
+                init_cmds.push(InitCmd::parse(cmd)?);
+            }
+        }
+
+        let mut dc_modes = Vec::new();
+        if let Some(dc) = xml::child(node, "Dc") {
+            for mode in xml::children(dc, "OpMode") {
+                dc_modes.push(DcOpMode::parse(mode)?);
+            }
+        }
+
+        Ok(EsiDevice {
+            vendor_id,
+            product_code: product_code as u32,
+            revision: xml::attr_number(ty, "RevisionNo")?.map(|v| v as u32),
+            type_name: xml::text(ty).to_string(),
+            name: localised_name(node),
+            group: xml::child_text(node, "GroupType").map(str::to_string),
+            sync_managers,
+            rx_pdos: xml::children(node, "RxPdo")
+                .map(EsiPdo::parse)
+                .collect::<Result<_, _>>()?,
+            tx_pdos: xml::children(node, "TxPdo")
+                .map(EsiPdo::parse)
+                .collect::<Result<_, _>>()?,
+            init_cmds,
+            dc_modes,
+        })
+    }
+
+    /// PDOs assigned to a SyncManager by default, i.e. the default process data mapping.
+    pub fn default_rx_pdos(&self) -> impl Iterator<Item = &EsiPdo> {
+        self.rx_pdos.iter().filter(|p| p.sync_manager.is_some())
+    }
+
+    pub fn default_tx_pdos(&self) -> impl Iterator<Item = &EsiPdo> {
+        self.tx_pdos.iter().filter(|p| p.sync_manager.is_some())
+    }
+
+    pub fn output_bits(&self) -> u32 {
+        self.default_rx_pdos().map(EsiPdo::bit_length).sum()
+    }
+
+    pub fn input_bits(&self) -> u32 {
+        self.default_tx_pdos().map(EsiPdo::bit_length).sum()
+    }
+
+    pub fn dc_mode(&self, name: &str) -> Option<&DcOpMode> {
+        self.dc_modes.iter().find(|m| m.name == name)
+    }
+}
+
+/// Parse all devices of a single ESI document.
+pub fn parse_esi(text: &str) -> Result<(Option<String>, Vec<EsiDevice>), EsiError> {
+    let doc = Document::parse(text)?;
+    let root = doc.root_element();
+    let vendor = xml::required(root, "Vendor")?;
+    let vendor_id = xml::required_number(vendor, "Id")? as u32;
+    let vendor_name = xml::child_text(vendor, "Name").map(str::to_string);
+
+    let mut devices = Vec::new();
+    if let Some(list) = xml::child(root, "Descriptions").and_then(|d| xml::child(d, "Devices")) {
+        for device in xml::children(list, "Device") {
+            devices.push(EsiDevice::parse(device, vendor_id)?);
+        }
+    }
+    Ok((vendor_name, devices))
+}
+
+/// Device descriptions loaded from one or more ESI files.
+#[derive(Debug, Clone, Default)]
+pub struct EsiCatalogue {
+    vendors: HashMap<u32, String>,
+    /// Devices without a revision are stored under revision `u32::MAX` so that they
+    /// sort after all concrete revisions of the same product.
+    devices: BTreeMap<DeviceKey, EsiDevice>,
+}
+
+impl EsiCatalogue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load every `*.xml` file in `dir` (not recursive).
+    pub fn load_dir(dir: impl AsRef<Path>) -> Result<Self, EsiError> {
+        let mut catalogue = Self::new();
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| {
+                p.extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| e.eq_ignore_ascii_case("xml"))
+            })
+            .collect();
+        paths.sort();
+        for path in paths {
+            catalogue.load_file(&path)?;
+        }
+        Ok(catalogue)
+    }
+
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> Result<usize, EsiError> {
+        let path = path.as_ref();
+        let wrap = |error: EsiError| EsiError::File {
+            path: path.to_path_buf(),
+            error: Box::new(error),
+        };
+        let text = xml::decode(fs::read(path).map_err(|e| wrap(e.into()))?);
+        self.add_xml(&text).map_err(wrap)
+    }
+
+    /// Add the devices of an ESI document, returning how many were added. A device
+    /// already in the catalogue under the same vendor, product and revision is
+    /// replaced and not counted.
+    pub fn add_xml(&mut self, text: &str) -> Result<usize, EsiError> {
+        let (vendor_name, devices) = parse_esi(text)?;
+        let mut count = 0;
+        for device in devices {
+            if let Some(name) = &vendor_name {
+                self.vendors
+                    .entry(device.vendor_id)
+                    .or_insert_with(|| name.clone());
+            }
+            if self.insert(device).is_none() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Add a device, returning the description it replaced if the catalogue already
+    /// had one for the same vendor, product and revision.
+    pub fn insert(&mut self, device: EsiDevice) -> Option<EsiDevice> {
+        let key = DeviceKey {
+            vendor_id: device.vendor_id,
+            product_code: device.product_code,
+            revision: device.revision.unwrap_or(u32::MAX),
+        };
+        self.devices.insert(key, device)
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &EsiDevice> {
+        self.devices.values()
+    }
+
+    pub fn vendor_name(&self, vendor_id: u32) -> Option<&str> {
+        self.vendors.get(&vendor_id).map(String::as_str)
+    }
+
+    /// Exact lookup of a vendor/product/revision triple.
+    pub fn get(&self, vendor_id: u32, product_code: u32, revision: u32) -> Option<&EsiDevice> {
+        self.devices.get(&DeviceKey {
+            vendor_id,
+            product_code,
+            revision,
+        })
+    }
+
+    /// Find the best description for a device: the exact revision if known, else a
+    /// revision independent description, else the highest known revision.
+    pub fn lookup(&self, vendor_id: u32, product_code: u32, revision: u32) -> Option<&EsiDevice> {
+        self.get(vendor_id, product_code, revision).or_else(|| {
+            let from = DeviceKey {
+                vendor_id,
+                product_code,
+                revision: 0,
+            };
+            let to = DeviceKey {
+                vendor_id,
+                product_code,
+                revision: u32::MAX,
+            };
+            self.devices.range(from..=to).next_back().map(|(_, d)| d)
+        })
+    }
+
+    /// Find the description matching a scanned slave's SII identity.
+    pub fn lookup_slave(&self, slave: &ec_slavet) -> Option<&EsiDevice> {
+        self.lookup(slave.eep_man, slave.eep_id, slave.eep_rev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    const ESI: &str = r##"<?xml version="1.0" encoding="utf-8"?>
+<EtherCATInfo>
+  <Vendor>
+    <Id>#x00000002</Id>
+    <Name>Beckhoff Automation GmbH</Name>
+  </Vendor>
+  <Descriptions>
+    <Devices>
+      <Device>
+        <Type ProductCode="#x03f03052" RevisionNo="#x00110000">EL1008</Type>
+        <Name LcId="1031">EL1008 8K. Dig. Eingang</Name>
+        <Name LcId="1033">EL1008 8Ch. Dig. Input</Name>
+        <GroupType>DigIn</GroupType>
+        <Sm StartAddress="#x1000" ControlByte="#x00" Enable="1">Inputs</Sm>
+        <TxPdo Fixed="1" Mandatory="1" Sm="0">
+          <Index>#x1a00</Index>
+          <Name>Channel 1</Name>
+          <Entry>
+            <Index>#x6000</Index>
+            <SubIndex>1</SubIndex>
+            <BitLen>1</BitLen>
+            <Name>Input</Name>
+            <DataType>BOOL</DataType>
+          </Entry>
+          <Entry>
+            <Index>0</Index>
+            <BitLen>7</BitLen>
+          </Entry>
+        </TxPdo>
+        <TxPdo>
+          <Index>#x1a01</Index>
+          <Entry>
+            <Index>#x6010</Index>
+            <SubIndex>1</SubIndex>
+            <BitLen>8</BitLen>
+          </Entry>
+        </TxPdo>
+      </Device>
+      <Device>
+        <Type ProductCode="#x1b773052">EL7031</Type>
+        <Mailbox>
+          <CoE>
+            <InitCmd>
+              <Transition>PS</Transition>
+              <Transition>SO</Transition>
+              <Index>#x8010</Index>
+              <SubIndex>1</SubIndex>
+              <Data>e803</Data>
+              <Comment>max current</Comment>
+            </InitCmd>
+          </CoE>
+        </Mailbox>
+        <Dc>
+          <OpMode>
+            <Name>DC</Name>
+            <Desc>DC-Synchron</Desc>
+            <AssignActivate>#x0300</AssignActivate>
+            <CycleTimeSync0 Factor="1">0</CycleTimeSync0>
+            <ShiftTimeSync0>0</ShiftTimeSync0>
+          </OpMode>
+        </Dc>
+      </Device>
+    </Devices>
+  </Descriptions>
+</EtherCATInfo>"##;
+
+    #[test]
+    fn parse_devices() {
+        let (vendor, devices) = parse_esi(ESI).unwrap();
+        assert_eq!(vendor.as_deref(), Some("Beckhoff Automation GmbH"));
+        assert_eq!(devices.len(), 2);
+
+        let el1008 = &devices[0];
+        assert_eq!(el1008.vendor_id, 2);
+        assert_eq!(el1008.product_code, 0x03f0_3052);
+        assert_eq!(el1008.revision, Some(0x0011_0000));
+        assert_eq!(el1008.type_name, "EL1008");
+        assert_eq!(el1008.name.as_deref(), Some("EL1008 8Ch. Dig. Input"));
+        assert_eq!(el1008.sync_managers[0].kind, SyncManagerKind::Inputs);
+        assert_eq!(el1008.tx_pdos.len(), 2);
+        assert!(el1008.tx_pdos[0].fixed && el1008.tx_pdos[0].mandatory);
+        assert_eq!(el1008.input_bits(), 8);
+        assert_eq!(el1008.default_tx_pdos().count(), 1);
+
+        let el7031 = &devices[1];
+        assert_eq!(el7031.revision, None);
+        let cmd = &el7031.init_cmds[0];
+        assert_eq!(cmd.transitions, (ECT_ESMTRANS_PS | ECT_ESMTRANS_SO) as u16);
+        assert_eq!((cmd.index, cmd.subindex), (0x8010, 1));
+        assert_eq!(cmd.data, [0xe8, 0x03]);
+        let dc = el7031.dc_mode("DC").unwrap();
+        assert_eq!(dc.assign_activate, 0x0300);
+        assert_eq!(dc.sync0_factor, 1);
+        assert_eq!(dc.sync1_factor, 0);
+    }
+
+    #[test]
+    fn catalogue_lookup() {
+        let mut catalogue = EsiCatalogue::new();
+        assert_eq!(catalogue.add_xml(ESI).unwrap(), 2);
+        assert_eq!(catalogue.vendor_name(2), Some("Beckhoff Automation GmbH"));
+        assert!(catalogue.get(2, 0x03f0_3052, 0x0011_0000).is_some());
+        // a newer revision falls back to the highest known one
+        let found = catalogue.lookup(2, 0x03f0_3052, 0x0012_0000).unwrap();
+        assert_eq!(found.revision, Some(0x0011_0000));
+        // revision independent descriptions match any revision
+        assert!(catalogue.lookup(2, 0x1b77_3052, 0x0016_0000).is_some());
+        assert!(catalogue.lookup(2, 0x1234, 0).is_none());
+    }
+
+    #[test]
+    fn duplicates_are_reported() {
+        let mut catalogue = EsiCatalogue::new();
+        catalogue.add_xml(ESI).unwrap();
+        assert_eq!(catalogue.add_xml(ESI).unwrap(), 0);
+        assert_eq!(catalogue.len(), 2);
+
+        let mut device = catalogue.get(2, 0x03f0_3052, 0x0011_0000).unwrap().clone();
+        device.type_name = "EL1008-0000".to_string();
+        let replaced = catalogue.insert(device).unwrap();
+        assert_eq!(replaced.type_name, "EL1008");
+    }
+
+    #[test]
+    fn transitions() {
+        let mask = transition_mask("PS").unwrap() | transition_mask(" OP ").unwrap();
+        assert_eq!(transition_names(mask), ["PS", "OP"]);
+        assert_eq!(transition_mask("XX"), None);
+    }
+
+    #[test]
+    fn errors_expose_their_source() {
+        let error = EsiCatalogue::new().add_xml("<EtherCATInfo>").unwrap_err();
+        assert!(matches!(error, EsiError::Xml(XmlError::Parse(_))));
+        let xml = error.source().unwrap();
+        assert!(xml.source().is_some());
+
+        let error = EsiCatalogue::new()
+            .add_xml("<EtherCATInfo><Vendor/></EtherCATInfo>")
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            EsiError::Xml(XmlError::Missing { ref name, .. }) if name == "<Id>"
+        ));
+    }
+}
@@ -0,0 +1,443 @@
+//! File access over EtherCAT (FoE) that never blocks. Like an `SdoRequest`, a
+//! request is advanced by one mailbox step each time it is polled.
+
+use std::{
+    fmt,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use crate::bindings::*;
+use crate::mailbox::{MBX_HEADER, Step};
+use crate::simple_ng::Fieldbus;
+
+/// Mailbox header and FoE header: opcode, reserved byte and password, packet
+/// number or error code.
+const FOE_HEADER: usize = MBX_HEADER + 6;
+const FOE_TIMEOUT: Duration = Duration::from_micros(EC_TIMEOUTSTATE as u64 * 10);
+/// Default limit for the size of a file read.
+const FOE_MAX_SIZE: usize = 0x100000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FoeError {
+    /// The slave ended the transfer with an FoE error code and text.
+    Slave { code: u32, text: String },
+    /// The slave answered with a mailbox error, carrying its detail code.
+    Mailbox(u16),
+    /// No complete answer within the request timeout.
+    Timeout,
+    /// The slave's answer does not follow the FoE protocol.
+    Protocol(&'static str),
+    /// The file name contains a NUL byte or does not fit into a mailbox frame.
+    InvalidFilename,
+    /// The file is larger than allowed by `FoeRequest::max_size`.
+    TooLarge,
+}
+
+impl fmt::Display for FoeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FoeError::Slave { code, text } if text.is_empty() => {
+                write!(f, "FoE error {:08x}", code)
+            }
+            FoeError::Slave { code, text } => write!(f, "FoE error {:08x}: {}", code, text),
+            FoeError::Mailbox(detail) => write!(f, "mailbox error {:04x}", detail),
+            FoeError::Timeout => write!(f, "FoE timeout"),
+            FoeError::Protocol(what) => write!(f, "FoE protocol error: {}", what),
+            FoeError::InvalidFilename => write!(f, "invalid FoE file name"),
+            FoeError::TooLarge => write!(f, "file exceeds the read limit"),
+        }
+    }
+}
+
+impl std::error::Error for FoeError {}
+
+/// An FoE file read or write. Create it with `read` or `write` and call `poll`
+/// once per cycle until it returns `Poll::Ready`.
+#[derive(Debug)]
+pub struct FoeRequest {
+    slave: u16,
+    filename: String,
+    password: u32,
+    read: bool,
+    max_size: usize,
+    timeout: Duration,
+    /// Read: data received so far. Write: data to send.
+    data: Vec<u8>,
+    /// Write: bytes of `data` sent in packets before the current one.
+    offset: usize,
+    /// Number of the last data packet sent or received.
+    packet: u32,
+    /// Write: the current packet is the last one.
+    last: bool,
+    /// Read: the transfer is complete once the current frame has been sent.
+    complete: bool,
+    frame: Vec<u8>,
+    /// The timeout counts from here.
+    created: Instant,
+    step: Step<FoeError>,
+}
+
+impl FoeRequest {
+    pub fn read(slave: u16, filename: &str, password: u32) -> Self {
+        Self::new(slave, filename, password, true, Vec::new())
+    }
+
+    pub fn write(slave: u16, filename: &str, password: u32, data: impl Into<Vec<u8>>) -> Self {
+        Self::new(slave, filename, password, false, data.into())
+    }
+
+    fn new(slave: u16, filename: &str, password: u32, read: bool, data: Vec<u8>) -> Self {
+        FoeRequest {
+            slave,
+            filename: filename.to_string(),
+            password,
+            read,
+            max_size: FOE_MAX_SIZE,
+            timeout: FOE_TIMEOUT,
+            data,
+            offset: 0,
+            packet: 0,
+            last: false,
+            complete: false,
+            frame: Vec::new(),
+            created: Instant::now(),
+            step: Step::Send,
+        }
+    }
+
+    /// Largest file accepted by a read.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Time allowed for the whole transfer, counted from when the request is
+    /// created, so time spent queued in a `MailboxClient` counts too.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn slave(&self) -> u16 {
+        self.slave
+    }
+
+    /// When the request times out.
+    pub(crate) fn deadline(&self) -> Instant {
+        self.created + self.timeout
+    }
+
+    /// Advance the transfer. Returns the file read, or an empty vector for a
+    /// write, once it is complete. Polling again after that returns the same
+    /// result.
+    pub fn poll(&mut self, bus: &mut Fieldbus) -> Poll<Result<Vec<u8>, FoeError>> {
+        if let Step::Done(result) = &self.step {
+            return Poll::Ready(result.clone());
+        }
+        if self.frame.is_empty() {
+            if self.created.elapsed() > self.timeout {
+                // expired before anything was sent
+                return self.finish(Err(FoeError::Timeout));
+            }
+            match self.request_frame(bus) {
+                Ok(frame) => self.frame = frame,
+                Err(e) => return self.finish(Err(e)),
+            }
+        }
+        let answer = bus.mailbox_step(self.slave, ECT_MBXT_FOE, &self.frame, &mut self.step);
+        let result = match answer {
+            Some(frame) => self.answer(bus, &frame),
+            // the last acknowledgement of a read is not answered
+            None if self.complete && matches!(self.step, Step::Receive) => {
+                Some(Ok(std::mem::take(&mut self.data)))
+            }
+            None => None,
+        };
+        let result = match result {
+            None if self.created.elapsed() > self.timeout => {
+                bus.mailbox_cancel(self.slave, &self.step);
+                Some(Err(FoeError::Timeout))
+            }
+            result => result,
+        };
+        match result {
+            Some(result) => self.finish(result),
+            None => Poll::Pending,
+        }
+    }
+
+    fn finish(&mut self, result: Result<Vec<u8>, FoeError>) -> Poll<Result<Vec<u8>, FoeError>> {
+        self.step = Step::Done(result.clone());
+        Poll::Ready(result)
+    }
+
+    /// Data bytes per packet.
+    fn max_data(bus: &Fieldbus, slave: u16) -> usize {
+        bus.mailbox_capacity(slave)
+            .saturating_sub(FOE_HEADER - MBX_HEADER)
+    }
+
+    fn frame(bus: &mut Fieldbus, slave: u16, opcode: u32, value: u32, data: &[u8]) -> Vec<u8> {
+        let length = FOE_HEADER - MBX_HEADER + data.len();
+        let mut frame = bus.mailbox_frame(slave, ECT_MBXT_FOE, length);
+        frame[MBX_HEADER] = opcode as u8;
+        frame[MBX_HEADER + 2..FOE_HEADER].copy_from_slice(&value.to_le_bytes());
+        frame[FOE_HEADER..].copy_from_slice(data);
+        frame
+    }
+
+    /// The read or write request carrying the file name.
+    fn request_frame(&self, bus: &mut Fieldbus) -> Result<Vec<u8>, FoeError> {
+        let name = self.filename.as_bytes();
+        if name.contains(&0) || name.len() > Self::max_data(bus, self.slave) {
+            return Err(FoeError::InvalidFilename);
+        }
+        let opcode = if self.read {
+            ECT_FOE_READ
+        } else {
+            ECT_FOE_WRITE
+        };
+        Ok(Self::frame(bus, self.slave, opcode, self.password, name))
+    }
+
+    /// The data packet following `offset`. A packet shorter than the maximum ends
+    /// the file, so a file filling its last packet is followed by an empty one.
+    fn data_frame(&mut self, bus: &mut Fieldbus) -> Vec<u8> {
+        let max = Self::max_data(bus, self.slave);
+        let n = (self.data.len() - self.offset).min(max);
+        self.last = n < max;
+        let data = &self.data[self.offset..self.offset + n];
+        Self::frame(bus, self.slave, ECT_FOE_DATA, self.packet + 1, data)
+    }
+
+    /// Handle a frame read from the slave. Returns the outcome, or `None` when the
+    /// transfer continues.
+    fn answer(&mut self, bus: &mut Fieldbus, frame: &[u8]) -> Option<Result<Vec<u8>, FoeError>> {
+        let length = u16::from_le_bytes([frame[0], frame[1]]) as usize;
+        let frame = &frame[..(MBX_HEADER + length).min(frame.len())];
+        if frame[5] as u32 & 0x0f == ECT_MBXT_ERR {
+            if frame.len() < MBX_HEADER + 4 {
+                return None;
+            }
+            let detail = u16::from_le_bytes([frame[8], frame[9]]);
+            return Some(Err(FoeError::Mailbox(detail)));
+        }
+        if frame.len() < FOE_HEADER {
+            return None;
+        }
+        let value = u32::from_le_bytes(frame[MBX_HEADER + 2..FOE_HEADER].try_into().unwrap());
+        match frame[MBX_HEADER] as u32 {
+            ECT_FOE_ERROR => {
+                let text = &frame[FOE_HEADER..];
+                let text = &text[..text.iter().position(|&b| b == 0).unwrap_or(text.len())];
+                Some(Err(FoeError::Slave {
+                    code: value,
+                    text: String::from_utf8_lossy(text).into_owned(),
+                }))
+            }
+            ECT_FOE_DATA if self.read => {
+                if value != self.packet + 1 {
+                    return Some(Err(FoeError::Protocol("unexpected packet number")));
+                }
+                let data = &frame[FOE_HEADER..];
+                if self.data.len() + data.len() > self.max_size {
+                    return Some(Err(FoeError::TooLarge));
+                }
+                self.data.extend_from_slice(data);
+                self.packet = value;
+                self.complete = data.len() < Self::max_data(bus, self.slave);
+                self.frame = Self::frame(bus, self.slave, ECT_FOE_ACK, value, &[]);
+                self.step = Step::Send;
+                None
+            }
+            ECT_FOE_ACK if !self.read => {
+                if value != self.packet {
+                    return Some(Err(FoeError::Protocol("unexpected packet number")));
+                }
+                if self.packet > 0 {
+                    if self.last {
+                        return Some(Ok(Vec::new()));
+                    }
+                    self.offset =
+                        (self.offset + Self::max_data(bus, self.slave)).min(self.data.len());
+                }
+                self.frame = self.data_frame(bus);
+                self.packet += 1;
+                self.step = Step::Send;
+                None
+            }
+            ECT_FOE_BUSY if !self.read && self.packet > 0 => {
+                // send the current packet again
+                self.packet -= 1;
+                self.frame = self.data_frame(bus);
+                self.packet += 1;
+                self.step = Step::Send;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    /// 26 bytes after the mailbox header, 20 data bytes per packet.
+    fn bus() -> Fieldbus {
+        let mut bus = Fieldbus::new(CString::new("test").unwrap());
+        bus.context.slavecount = 1;
+        bus.context.slavelist[1].mbx_l = 32;
+        bus
+    }
+
+    fn start(bus: &mut Fieldbus, mut request: FoeRequest) -> FoeRequest {
+        request.frame = request.request_frame(bus).unwrap();
+        request
+    }
+
+    fn response(opcode: u32, value: u32, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; FOE_HEADER];
+        frame[0..2].copy_from_slice(&((FOE_HEADER - MBX_HEADER + data.len()) as u16).to_le_bytes());
+        frame[5] = ECT_MBXT_FOE as u8;
+        frame[MBX_HEADER] = opcode as u8;
+        frame[MBX_HEADER + 2..FOE_HEADER].copy_from_slice(&value.to_le_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    fn opcode_and_value(frame: &[u8]) -> (u32, u32) {
+        let value = u32::from_le_bytes(frame[MBX_HEADER + 2..FOE_HEADER].try_into().unwrap());
+        (frame[MBX_HEADER] as u32, value)
+    }
+
+    #[test]
+    fn request_frame() {
+        let mut bus = bus();
+        let request = start(&mut bus, FoeRequest::read(1, "firmware.efw", 0x1234));
+        assert_eq!(opcode_and_value(&request.frame), (ECT_FOE_READ, 0x1234));
+        assert_eq!(&request.frame[FOE_HEADER..], b"firmware.efw");
+        let long = "x".repeat(21);
+        assert_eq!(
+            FoeRequest::read(1, &long, 0).request_frame(&mut bus),
+            Err(FoeError::InvalidFilename)
+        );
+        assert_eq!(
+            FoeRequest::read(1, "a\0b", 0).request_frame(&mut bus),
+            Err(FoeError::InvalidFilename)
+        );
+    }
+
+    #[test]
+    fn read() {
+        let mut bus = bus();
+        let mut request = start(&mut bus, FoeRequest::read(1, "data", 0));
+        let first: Vec<u8> = (0..20).collect();
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_DATA, 1, &first)),
+            None
+        );
+        assert_eq!(opcode_and_value(&request.frame), (ECT_FOE_ACK, 1));
+        assert!(!request.complete);
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_DATA, 2, &[20, 21])),
+            None
+        );
+        assert_eq!(opcode_and_value(&request.frame), (ECT_FOE_ACK, 2));
+        assert!(request.complete);
+        assert_eq!(request.data, (0..22).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn read_errors() {
+        let mut bus = bus();
+        let mut request = start(&mut bus, FoeRequest::read(1, "data", 0));
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_DATA, 2, &[])),
+            Some(Err(FoeError::Protocol("unexpected packet number")))
+        );
+
+        let mut request = start(&mut bus, FoeRequest::read(1, "data", 0).max_size(10));
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_DATA, 1, &[0; 20])),
+            Some(Err(FoeError::TooLarge))
+        );
+
+        let mut request = start(&mut bus, FoeRequest::read(1, "data", 0));
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_ERROR, 0x8001, b"not found\0\0")),
+            Some(Err(FoeError::Slave {
+                code: 0x8001,
+                text: "not found".to_string()
+            }))
+        );
+
+        let mut frame = response(0, 0, &[]);
+        frame[5] = ECT_MBXT_ERR as u8;
+        frame[8..10].copy_from_slice(&0x0007u16.to_le_bytes());
+        assert_eq!(
+            request.answer(&mut bus, &frame),
+            Some(Err(FoeError::Mailbox(0x0007)))
+        );
+    }
+
+    #[test]
+    fn write() {
+        let mut bus = bus();
+        let data: Vec<u8> = (0..40).collect();
+        let mut request = start(&mut bus, FoeRequest::write(1, "data", 0, data.clone()));
+        assert_eq!(opcode_and_value(&request.frame), (ECT_FOE_WRITE, 0));
+
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_ACK, 0, &[])),
+            None
+        );
+        assert_eq!(opcode_and_value(&request.frame), (ECT_FOE_DATA, 1));
+        assert_eq!(request.frame[FOE_HEADER..], data[..20]);
+
+        // busy: the same packet again
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_BUSY, 0, &[])),
+            None
+        );
+        assert_eq!(opcode_and_value(&request.frame), (ECT_FOE_DATA, 1));
+        assert_eq!(request.frame[FOE_HEADER..], data[..20]);
+
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_ACK, 1, &[])),
+            None
+        );
+        assert_eq!(opcode_and_value(&request.frame), (ECT_FOE_DATA, 2));
+        assert_eq!(request.frame[FOE_HEADER..], data[20..]);
+
+        // a full last packet is followed by an empty one
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_ACK, 2, &[])),
+            None
+        );
+        assert_eq!(opcode_and_value(&request.frame), (ECT_FOE_DATA, 3));
+        assert_eq!(request.frame.len(), FOE_HEADER);
+
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_ACK, 3, &[])),
+            Some(Ok(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn write_wrong_ack() {
+        let mut bus = bus();
+        let mut request = start(&mut bus, FoeRequest::write(1, "data", 0, [1, 2, 3]));
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_ACK, 0, &[])),
+            None
+        );
+        assert_eq!(
+            request.answer(&mut bus, &response(ECT_FOE_ACK, 0, &[])),
+            Some(Err(FoeError::Protocol("unexpected packet number")))
+        );
+    }
+}
@@ -0,0 +1,469 @@
+//! Hot-connect groups: optional sections of the bus that may be missing at startup
+//! or plugged in and out while running. A group is found by the explicit device ID
+//! or the station alias of its first slave; the slaves behind it up to the length
+//! of the group belong to it.
+//!
+//! The slaves of all hot-connect groups present at startup are mapped into their
+//! own process data group behind the mandatory slaves and exchanged in a frame of
+//! their own, so the working counter of the mandatory part is not affected by
+//! them.
+//!
+//! Limits of this implementation:
+//!
+//! - Mapping happens in `start` only. A group may be unplugged and plugged back in
+//!   while running, but a group absent at startup is never mapped: SOEM
+//!   configures and maps slaves in `ecx_config_init` and `ecx_config_map_group`,
+//!   which cannot add slaves to a running bus. Such a group is reported as
+//!   `HotConnectEvent::Unconfigured`, and taking it into process data needs a
+//!   `stop` and `start`, which interrupts the mandatory part as well.
+//! - All groups share one process data group (`HOT_CONNECT_GROUP`) and so one
+//!   frame and one working counter. A disconnected group is taken out of
+//!   `expected_hot_connect_wkc`, and the frame is no longer sent while all
+//!   groups are disconnected.
+
+use std::{
+    ffi::c_void,
+    ops::Range,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::bindings::*;
+use crate::config::{self, HOT_CONNECT_GROUP, Mismatch, SlaveConfig};
+use crate::datagram::DatagramError;
+use crate::registers::{AlControl, AlStatus};
+use crate::simple_ng::Fieldbus;
+use crate::status::AlStatusCode;
+
+/// How long a slave gets to load its explicit device ID.
+const ID_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// How the first slave of a group is recognised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceId {
+    /// Explicit device identification: the ID the slave reports in the AL status
+    /// code (0x0134) on an ID request, usually set by a rotary switch.
+    Explicit(u16),
+    /// Station alias (0x0012). Alias 0 means "no alias" and never matches.
+    Alias(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotConnectGroup {
+    pub name: String,
+    pub id: DeviceId,
+    /// The slaves of the group in ring order, starting with the identified one.
+    pub slaves: Vec<SlaveConfig>,
+}
+
+impl HotConnectGroup {
+    pub fn new(name: impl Into<String>, id: DeviceId) -> Self {
+        HotConnectGroup {
+            name: name.into(),
+            id,
+            slaves: Vec::new(),
+        }
+    }
+
+    pub fn slave(mut self, slave: SlaveConfig) -> Self {
+        self.slaves.push(slave);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotConnectState {
+    /// Not found at startup. It is not mapped until the next `start`.
+    Absent,
+    /// Found at startup and exchanging process data.
+    Present,
+    /// Found at startup but unplugged since.
+    Disconnected,
+    /// Found, but its slaves do not match the group. It stays in PRE_OP.
+    Excluded,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HotConnectEvent {
+    Connected(String),
+    Disconnected(String),
+    /// Slaves appeared that do not belong to a group mapped at startup, such as a
+    /// group that was `Absent`. They are only configured by the next `start`.
+    Unconfigured {
+        slaves: u16,
+    },
+}
+
+pub(crate) struct HotConnect {
+    group: HotConnectGroup,
+    slaves: Option<Range<u16>>,
+    state: HotConnectState,
+}
+
+impl HotConnect {
+    pub(crate) fn contains(&self, slave: u16) -> bool {
+        self.slaves.as_ref().is_some_and(|s| s.contains(&slave))
+    }
+}
+
+impl Fieldbus {
+    /// Declare an optional group. Groups are looked for in `start`.
+    pub fn add_hot_connect_group(&mut self, group: HotConnectGroup) {
+        self.hot_connect.push(HotConnect {
+            group,
+            slaves: None,
+            state: HotConnectState::Absent,
+        });
+    }
+
+    pub fn hot_connect_state(&self, name: &str) -> Option<HotConnectState> {
+        self.hot_connect
+            .iter()
+            .find(|h| h.group.name == name)
+            .map(|h| h.state)
+    }
+
+    /// Slave numbers of a group found at startup.
+    pub fn hot_connect_slaves(&self, name: &str) -> Option<Range<u16>> {
+        self.hot_connect
+            .iter()
+            .find(|h| h.group.name == name)
+            .and_then(|h| h.slaves.clone())
+    }
+
+    /// Configurations of the slaves of groups found at startup.
+    pub(crate) fn hot_connect_configs(&self) -> Vec<(u16, &SlaveConfig)> {
+        self.hot_connect
+            .iter()
+            .filter(|h| h.state == HotConnectState::Present)
+            .flat_map(|h| h.slaves.clone().unwrap_or_default().zip(&h.group.slaves))
+            .collect()
+    }
+
+    pub(crate) fn is_hot_connect_slave(&self, slave: u16) -> bool {
+        self.hot_connect.iter().any(|h| h.contains(slave))
+    }
+
+    /// Ask a slave for its explicit device ID. `None` if it does not support
+    /// explicit device identification.
+    pub fn explicit_device_id(&mut self, slave: u16) -> Result<Option<u16>, DatagramError> {
+        let state = self.read_register::<AlStatus>(slave)?.state;
+        let request = |request_id| AlControl {
+            state,
+            acknowledge: false,
+            request_id,
+        };
+        self.write_register(slave, &request(true))?;
+        let deadline = Instant::now() + ID_TIMEOUT;
+        let id = loop {
+            match self.read_register::<AlStatus>(slave) {
+                Ok(status) if status.id_loaded => {
+                    break self
+                        .read_register::<AlStatusCode>(slave)
+                        .map(|id| Some(u16::from(id)));
+                }
+                Ok(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+                Ok(_) => break Ok(None),
+                Err(e) => break Err(e),
+            }
+        };
+        self.write_register(slave, &request(false))?;
+        id
+    }
+
+    /// Find the declared groups among the slaves found by `ecx_config_init` and
+    /// exclude those whose slaves do not match.
+    pub(crate) fn identify_hot_connect_groups(&mut self) {
+        if self.hot_connect.is_empty() {
+            return;
+        }
+        let count = self.context.slavecount.max(0) as u16;
+        let explicit = self
+            .hot_connect
+            .iter()
+            .any(|h| matches!(h.group.id, DeviceId::Explicit(_)));
+        let ids: Vec<Option<u16>> = (1..=count)
+            .map(|slave| {
+                explicit
+                    .then(|| self.explicit_device_id(slave).ok().flatten())
+                    .flatten()
+            })
+            .collect();
+
+        for n in 0..self.hot_connect.len() {
+            let group = &self.hot_connect[n].group;
+            let head = (1..=count).find(|&slave| match group.id {
+                DeviceId::Explicit(id) => ids[slave as usize - 1] == Some(id),
+                DeviceId::Alias(alias) => {
+                    alias != 0 && self.context.slavelist[slave as usize].aliasadr == alias
+                }
+            });
+            let Some(head) = head else {
+                log::info!("Hot-connect group {} is absent", group.name);
+                self.hot_connect[n].slaves = None;
+                self.hot_connect[n].state = HotConnectState::Absent;
+                continue;
+            };
+            let end = (head + group.slaves.len().max(1) as u16).min(count + 1);
+            let mismatches: Vec<Mismatch> = group
+                .slaves
+                .iter()
+                .zip(head..)
+                .filter(|&(_, slave)| slave < end)
+                .flat_map(|(expected, slave)| {
+                    config::compare(slave, expected, &self.slave_identity(slave))
+                })
+                .collect();
+            let complete = (end - head) as usize >= group.slaves.len();
+            for mismatch in &mismatches {
+                log::warn!("Hot-connect group {} mismatch at {}", group.name, mismatch);
+            }
+            let state = if mismatches.is_empty() && complete {
+                log::info!("Hot-connect group {} is slaves {:?}", group.name, head..end);
+                HotConnectState::Present
+            } else {
+                log::warn!("Excluding hot-connect group {}", group.name);
+                self.excluded.extend(head..end);
+                HotConnectState::Excluded
+            };
+            self.hot_connect[n].slaves = Some(head..end);
+            self.hot_connect[n].state = state;
+        }
+    }
+
+    /// Map the slaves of the groups present at startup at `offset` in the IOmap,
+    /// behind the mandatory slaves.
+    pub(crate) fn map_hot_connect_group(&mut self, offset: usize) {
+        if self.hot_connect_configs().is_empty() {
+            return;
+        }
+        let context = &mut *self.context as *mut ecx_contextt;
+        let Some(map) = self.map.get_mut(offset..) else {
+            return;
+        };
+        unsafe {
+            ecx_config_map_group(context, map.as_mut_ptr() as *mut c_void, HOT_CONNECT_GROUP)
+        };
+        // the frame of the mandatory part already distributes the reference time
+        if self.context.grouplist[self.group as usize].hasdc != 0 {
+            self.context.grouplist[HOT_CONNECT_GROUP as usize].hasdc = 0;
+        }
+    }
+
+    /// Whether the hot-connect slaves are mapped and a group is connected.
+    pub(crate) fn hot_connect_active(&self) -> bool {
+        let group = &self.context.grouplist[HOT_CONNECT_GROUP as usize];
+        group.Obytes + group.Ibytes > 0
+            && self
+                .hot_connect
+                .iter()
+                .any(|h| h.state == HotConnectState::Present)
+    }
+
+    /// Exchange the process data of the hot-connect slaves after that of the
+    /// mandatory part, unless no group is connected.
+    pub(crate) fn exchange_hot_connect(&mut self) {
+        if !self.hot_connect_active() {
+            self.hot_connect_wkc = 0;
+            return;
+        }
+        // the round trip time stays that of the mandatory part
+        let roundtrip_time = self.roundtrip_time;
+        self.hot_connect_wkc = self.exchange(HOT_CONNECT_GROUP);
+        self.roundtrip_time = roundtrip_time;
+        let ok = self.hot_connect_wkc == self.expected_hot_connect_wkc();
+        self.statistics.record_group(HOT_CONNECT_GROUP, ok);
+    }
+
+    /// Working counter to expect from `roundtrip`, that of the mandatory slaves.
+    pub fn expected_wkc(&self) -> i32 {
+        let group = &self.context.grouplist[self.group as usize];
+        group.outputsWKC as i32 * 2 + group.inputsWKC as i32
+    }
+
+    /// Working counter of the last exchange with the hot-connect slaves, 0 while
+    /// none are connected.
+    pub fn hot_connect_wkc(&self) -> i32 {
+        self.hot_connect_wkc
+    }
+
+    /// Working counter to expect from the exchange with the hot-connect slaves,
+    /// leaving out disconnected groups.
+    pub fn expected_hot_connect_wkc(&self) -> i32 {
+        if !self.hot_connect_active() {
+            return 0;
+        }
+        let group = &self.context.grouplist[HOT_CONNECT_GROUP as usize];
+        let mut wkc = group.outputsWKC as i32 * 2 + group.inputsWKC as i32;
+        for h in &self.hot_connect {
+            if h.state != HotConnectState::Disconnected {
+                continue;
+            }
+            for slave in h.slaves.clone().unwrap_or_default() {
+                let s = &self.context.slavelist[slave as usize];
+                // LRW: +2 for the outputs written, +1 for the inputs or mailbox status read
+                wkc -= 2 * (s.Obits > 0) as i32 + (s.Ibits > 0 || !s.mbxstatus.is_null()) as i32;
+            }
+        }
+        wkc
+    }
+
+    /// Bring the slaves of a reconnected group back to OP. SOEM finds them by ring
+    /// position, so the bus in front of the group must be unchanged.
+    fn reconnect(&mut self, slaves: Range<u16>) -> bool {
+        let context = &mut *self.context as *mut ecx_contextt;
+        for slave in slaves.clone() {
+            if unsafe { ecx_recover_slave(context, slave, EC_TIMEOUTRET3 as i32) } <= 0 {
+                return false;
+            }
+            unsafe { ecx_reconfig_slave(context, slave, EC_TIMEOUTSTATE as i32) };
+        }
+        for slave in slaves {
+            self.context.slavelist[slave as usize].state = ec_state_EC_STATE_OPERATIONAL as u16;
+            unsafe { ecx_writestate(context, slave) };
+        }
+        true
+    }
+
+    /// Look for groups that were unplugged or plugged in. Meant to be called
+    /// periodically from the cyclic loop; while nothing changes this costs one
+    /// broadcast.
+    pub fn poll_hot_connect(&mut self) -> Vec<HotConnectEvent> {
+        let mut events = Vec::new();
+        if self.hot_connect.is_empty() {
+            return events;
+        }
+        let missing = |hot_connect: &[HotConnect]| -> u16 {
+            hot_connect
+                .iter()
+                .filter(|h| h.state == HotConnectState::Disconnected)
+                .map(|h| h.slaves.as_ref().map_or(0, |s| s.len() as u16))
+                .sum()
+        };
+        let responding = self
+            .brd::<u16>(ECT_REG_ALSTAT as u16)
+            .map_or(0, |(_, wkc)| wkc);
+        let expected = self.context.slavecount.max(0) as u16 - missing(&self.hot_connect);
+        if responding == expected {
+            return events;
+        }
+
+        for n in 0..self.hot_connect.len() {
+            let Some(slaves) = self.hot_connect[n].slaves.clone() else {
+                continue;
+            };
+            let state = self.hot_connect[n].state;
+            if responding < expected && state == HotConnectState::Present {
+                let gone = slaves.clone().any(|slave| {
+                    let station = self.context.slavelist[slave as usize].configadr;
+                    self.fprd::<u16>(station, ECT_REG_ALSTAT as u16).is_err()
+                });
+                if gone {
+                    self.hot_connect[n].state = HotConnectState::Disconnected;
+                    events.push(HotConnectEvent::Disconnected(
+                        self.hot_connect[n].group.name.clone(),
+                    ));
+                }
+            } else if responding > expected
+                && state == HotConnectState::Disconnected
+                && self.reconnect(slaves)
+            {
+                self.hot_connect[n].state = HotConnectState::Present;
+                events.push(HotConnectEvent::Connected(
+                    self.hot_connect[n].group.name.clone(),
+                ));
+            }
+        }
+
+        let expected = self.context.slavecount.max(0) as u16 - missing(&self.hot_connect);
+        if responding > expected {
+            events.push(HotConnectEvent::Unconfigured {
+                slaves: responding - expected,
+            });
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+    use crate::config::{ACTIVE_GROUP, EXCLUDED_GROUP};
+
+    /// Four EL1008 terminals, the last two with alias 7.
+    fn bus() -> Fieldbus {
+        let mut bus = Fieldbus::new(CString::new("test").unwrap());
+        bus.context.slavecount = 4;
+        for slave in 1..=4 {
+            let s = &mut bus.context.slavelist[slave];
+            s.eep_man = 2;
+            s.eep_id = 0x03f03052;
+            s.Ibits = 8;
+        }
+        bus.context.slavelist[3].aliasadr = 7;
+        bus.context.slavelist[4].aliasadr = 7;
+        bus
+    }
+
+    fn group(name: &str, id: DeviceId, slaves: usize) -> HotConnectGroup {
+        (0..slaves).fold(HotConnectGroup::new(name, id), |group, _| {
+            group.slave(SlaveConfig::new(2, 0x03f03052))
+        })
+    }
+
+    #[test]
+    fn alias_zero_never_matches() {
+        let mut bus = bus();
+        bus.add_hot_connect_group(group("none", DeviceId::Alias(0), 1));
+        bus.identify_hot_connect_groups();
+        assert_eq!(bus.hot_connect_state("none"), Some(HotConnectState::Absent));
+        assert_eq!(bus.hot_connect_slaves("none"), None);
+    }
+
+    #[test]
+    fn groups_in_their_own_process_data_group() {
+        let mut bus = bus();
+        bus.add_hot_connect_group(group("module", DeviceId::Alias(7), 2));
+        bus.identify_hot_connect_groups();
+        assert_eq!(
+            bus.hot_connect_state("module"),
+            Some(HotConnectState::Present)
+        );
+        assert_eq!(bus.hot_connect_slaves("module"), Some(3..5));
+
+        bus.excluded.push(1);
+        bus.assign_groups();
+        let groups: Vec<u8> = (1..=4).map(|s| bus.context.slavelist[s].group).collect();
+        assert_eq!(
+            groups,
+            [
+                EXCLUDED_GROUP,
+                ACTIVE_GROUP,
+                HOT_CONNECT_GROUP,
+                HOT_CONNECT_GROUP
+            ]
+        );
+        assert_eq!(bus.group, ACTIVE_GROUP);
+    }
+
+    #[test]
+    fn disconnected_groups_leave_the_working_counter() {
+        let mut bus = bus();
+        bus.add_hot_connect_group(group("a", DeviceId::Alias(7), 1));
+        bus.context.slavelist[4].aliasadr = 8;
+        bus.add_hot_connect_group(group("b", DeviceId::Alias(8), 1));
+        bus.identify_hot_connect_groups();
+        let group = &mut bus.context.grouplist[HOT_CONNECT_GROUP as usize];
+        group.Ibytes = 2;
+        group.inputsWKC = 2;
+        assert_eq!(bus.expected_hot_connect_wkc(), 2);
+
+        bus.hot_connect[0].state = HotConnectState::Disconnected;
+        assert_eq!(bus.expected_hot_connect_wkc(), 1);
+        bus.hot_connect[1].state = HotConnectState::Disconnected;
+        assert!(!bus.hot_connect_active());
+        assert_eq!(bus.expected_hot_connect_wkc(), 0);
+    }
+}
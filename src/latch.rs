@@ -0,0 +1,252 @@
+//! DC latch units (touch probes). The ESC captures the system time of edges on its
+//! LATCH0 and LATCH1 inputs (0x09A8-0x09CF). In single event mode only the first
+//! edge is kept until its time is read, in continuous mode every edge overwrites
+//! the time.
+
+use std::sync::mpsc;
+
+use crate::dc::DcError;
+use crate::registers::{
+    DcLatchControl, DcLatchStatus, DcLatchTimes, EscInformation, LatchEdges, Register,
+};
+use crate::simple_ng::Fieldbus;
+
+pub(crate) type LatchHandler = Box<dyn FnMut(&LatchEvent) + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatchInput {
+    Latch0,
+    Latch1,
+}
+
+impl LatchInput {
+    fn index(self) -> usize {
+        match self {
+            LatchInput::Latch0 => 0,
+            LatchInput::Latch1 => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatchEdge {
+    Rising,
+    Falling,
+    Both,
+}
+
+impl LatchEdge {
+    fn rising(self) -> bool {
+        self != LatchEdge::Falling
+    }
+
+    fn falling(self) -> bool {
+        self != LatchEdge::Rising
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LatchMode {
+    /// Keep the first edge until it has been reported.
+    #[default]
+    Single,
+    /// Report the latest edge, earlier ones since the last read are lost.
+    Continuous,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatchEvent {
+    pub slave: u16,
+    pub input: LatchInput,
+    /// `Rising` or `Falling`.
+    pub edge: LatchEdge,
+    /// System time of the edge in ns.
+    pub time: u64,
+}
+
+pub(crate) struct ArmedLatch {
+    slave: u16,
+    input: LatchInput,
+    edge: LatchEdge,
+    mode: LatchMode,
+    /// Whether the latch times are 64 bit; 32 bit ones are extended with the bus
+    /// time.
+    dc64: bool,
+    /// Times of the last read, to spot new edges in continuous mode.
+    last: (u64, u64),
+}
+
+/// Latch status and times in one read (0x09AE-0x09CF). Reading the times
+/// acknowledges single events.
+struct LatchRead {
+    status: DcLatchStatus,
+    times: DcLatchTimes,
+}
+
+impl Register for LatchRead {
+    const ADDRESS: u16 = DcLatchStatus::ADDRESS;
+    const SIZE: usize = DcLatchStatus::SIZE + DcLatchTimes::SIZE;
+
+    fn decode(bytes: &[u8]) -> Self {
+        LatchRead {
+            status: DcLatchStatus::decode(&bytes[..DcLatchStatus::SIZE]),
+            times: DcLatchTimes::decode(&bytes[DcLatchStatus::SIZE..]),
+        }
+    }
+}
+
+/// Extend a 32 bit latch time to the system time, assuming the edge happened in
+/// the 4.29 s before `now`.
+fn extend(time: u64, now: u64) -> u64 {
+    now.wrapping_sub((now as u32).wrapping_sub(time as u32) as u64)
+}
+
+impl Fieldbus {
+    /// Capture edges on a latch input of a slave. Edges are reported by
+    /// `read_latches` or, with a handler registered, from `cycle`.
+    pub fn arm_latch(
+        &mut self,
+        slave: u16,
+        input: LatchInput,
+        edge: LatchEdge,
+        mode: LatchMode,
+    ) -> Result<(), DcError> {
+        let info = self.read_register::<EscInformation>(slave)?;
+        if !info.has_dc() {
+            return Err(DcError::NotSupported(slave));
+        }
+        let mut control = self.read_register::<DcLatchControl>(slave)?;
+        let single = mode == LatchMode::Single;
+        control.latch[input.index()] = LatchEdges {
+            positive_single: single,
+            negative_single: single,
+        };
+        self.write_register(slave, &control)?;
+        // drop edges from before
+        let times = self.read_register::<DcLatchTimes>(slave)?.latch[input.index()];
+
+        self.latches
+            .retain(|l| (l.slave, l.input) != (slave, input));
+        self.latches.push(ArmedLatch {
+            slave,
+            input,
+            edge,
+            mode,
+            dc64: info.has_dc64(),
+            last: (times.positive, times.negative),
+        });
+        Ok(())
+    }
+
+    /// Stop reporting edges of a latch input and return it to continuous mode.
+    pub fn disarm_latch(&mut self, slave: u16, input: LatchInput) -> Result<(), DcError> {
+        self.latches
+            .retain(|l| (l.slave, l.input) != (slave, input));
+        let mut control = self.read_register::<DcLatchControl>(slave)?;
+        control.latch[input.index()] = LatchEdges::default();
+        self.write_register(slave, &control)?;
+        Ok(())
+    }
+
+    /// Read the armed latch inputs of a slave and return the new edges.
+    pub fn read_latches(&mut self, slave: u16) -> Result<Vec<LatchEvent>, DcError> {
+        let read = self.read_register::<LatchRead>(slave)?;
+        let now = self.context.DCtime as u64;
+        let mut events = Vec::new();
+        for latch in self.latches.iter_mut().filter(|l| l.slave == slave) {
+            let state = read.status.latch[latch.input.index()];
+            let times = read.times.latch[latch.input.index()];
+            let (positive, negative) = match latch.mode {
+                LatchMode::Single => (state.positive_event, state.negative_event),
+                LatchMode::Continuous => (
+                    times.positive != latch.last.0,
+                    times.negative != latch.last.1,
+                ),
+            };
+            latch.last = (times.positive, times.negative);
+            for (new, wanted, edge, time) in [
+                (
+                    positive,
+                    latch.edge.rising(),
+                    LatchEdge::Rising,
+                    times.positive,
+                ),
+                (
+                    negative,
+                    latch.edge.falling(),
+                    LatchEdge::Falling,
+                    times.negative,
+                ),
+            ] {
+                if new && wanted {
+                    events.push(LatchEvent {
+                        slave,
+                        input: latch.input,
+                        edge,
+                        time: if latch.dc64 { time } else { extend(time, now) },
+                    });
+                }
+            }
+        }
+        events.sort_by_key(|e| e.time);
+        Ok(events)
+    }
+
+    /// Call `handler` for every latch event from now on.
+    pub fn on_latch(&mut self, handler: impl FnMut(&LatchEvent) + Send + 'static) {
+        self.latch_handlers.push(Box::new(handler));
+    }
+
+    /// Receive latch events through a channel instead of a callback.
+    pub fn latch_channel(&mut self) -> mpsc::Receiver<LatchEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.on_latch(move |e| {
+            let _ = tx.send(*e);
+        });
+        rx
+    }
+
+    /// Read all armed latches and pass new edges to the handlers. Called from
+    /// `cycle` while handlers are registered, at one frame per slave with armed
+    /// latches. Returns the number of events delivered.
+    pub fn dispatch_latches(&mut self) -> usize {
+        let mut slaves: Vec<u16> = self.latches.iter().map(|l| l.slave).collect();
+        slaves.sort_unstable();
+        slaves.dedup();
+        let mut count = 0;
+        for slave in slaves {
+            // a slave that does not answer is read again next cycle
+            let Ok(events) = self.read_latches(slave) else {
+                continue;
+            };
+            for event in &events {
+                for handler in &mut self.latch_handlers {
+                    handler(event);
+                }
+            }
+            count += events.len();
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extend_32_bit_times() {
+        let now = 0x0000_0007_1000_0000;
+        assert_eq!(extend(0x0FFF_FF00, now), 0x0000_0007_0FFF_FF00);
+        assert_eq!(extend(0x1000_0000, now), now);
+    }
+
+    #[test]
+    fn extend_across_wrap() {
+        // the edge came before the low 32 bits of the bus time wrapped
+        let edge = 0x0000_0007_FFFF_FF00;
+        let now = 0x0000_0008_0000_0100;
+        assert_eq!(extend(edge & 0xFFFF_FFFF, now), edge);
+        assert_eq!(extend(0xFFFF_FFFF, 0x1_0000_0000), 0xFFFF_FFFF);
+    }
+}
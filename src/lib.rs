@@ -1 +1,36 @@
-pub mod bindings;
\ No newline at end of file
+#[allow(
+    non_upper_case_globals,
+    non_camel_case_types,
+    non_snake_case,
+    dead_code,
+    clippy::all
+)]
+pub mod bindings;
+pub mod alias;
+pub mod clock;
+pub mod config;
+pub mod cyclic;
+pub mod datagram;
+pub mod dc;
+pub mod diagnostics;
+pub mod emcy;
+pub mod eni;
+pub mod esi;
+pub mod foe;
+pub mod hotconnect;
+pub mod latch;
+pub mod mailbox;
+pub mod oversampling;
+pub mod registers;
+pub mod sdo;
+pub mod shutdown;
+pub mod sii;
+pub mod simple_ng;
+pub mod soe;
+pub mod statistics;
+pub mod status;
+pub mod topology;
+pub mod watchdog;
+mod xml;
+
+pub use xml::XmlError;
@@ -0,0 +1,716 @@
+//! Mailbox transport and asynchronous mailbox access for non real-time code.
+//!
+//! A `MailboxClient` queues typed SDO, FoE and SoE requests to the `Fieldbus`,
+//! which advances them from `cycle`, or `service_mailbox_requests` in loops of
+//! their own. Only the thread that owns the bus touches the context; the returned
+//! futures do not depend on a particular executor.
+//!
+//! Requests to a slave run one at a time in the order they were submitted, while
+//! requests to different slaves make progress in the same cycles. A request's
+//! timeout counts from when it is submitted, and a timer thread ends its `Reply`
+//! with the protocol's timeout error even if the bus owner never services it.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ffi::c_int,
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    ptr,
+    sync::{
+        Arc, Mutex, Weak,
+        mpsc::{self, RecvTimeoutError},
+    },
+    task::{Context, Poll, Waker},
+    thread,
+    time::Instant,
+};
+
+use crate::bindings::*;
+use crate::cyclic::MBX_IN_ENABLE;
+use crate::emcy::Emergency;
+use crate::foe::{FoeError, FoeRequest};
+use crate::sdo::{SdoError, SdoRequest};
+use crate::simple_ng::Fieldbus;
+use crate::soe::{SoeError, SoeRequest};
+
+/// Mailbox header: length, address, channel/priority and type/counter.
+pub(crate) const MBX_HEADER: usize = 6;
+
+// defined in ec_main.c, which does not declare them in a header
+unsafe extern "C" {
+    fn ecx_mbxaddqueue(context: *mut ecx_contextt, slave: u16, mbx: *mut ec_mbxbuft) -> c_int;
+    fn ecx_mbxdonequeue(context: *mut ecx_contextt, slave: u16, ticket: c_int) -> c_int;
+    fn ecx_mbxexpirequeue(context: *mut ecx_contextt, slave: u16, ticket: c_int) -> c_int;
+}
+
+/// Frames of other protocols kept for their requests when mailboxes are read
+/// directly.
+const INBOX_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MailboxError {
+    Sdo(SdoError),
+    Foe(FoeError),
+    Soe(SoeError),
+    /// There is no slave with this number on the bus.
+    NoSlave(u16),
+    /// The bus was stopped before the request completed.
+    Closed,
+}
+
+impl fmt::Display for MailboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailboxError::Sdo(e) => e.fmt(f),
+            MailboxError::Foe(e) => e.fmt(f),
+            MailboxError::Soe(e) => e.fmt(f),
+            MailboxError::NoSlave(slave) => write!(f, "no slave {}", slave),
+            MailboxError::Closed => write!(f, "mailbox requests are shut down"),
+        }
+    }
+}
+
+impl std::error::Error for MailboxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MailboxError::Sdo(e) => Some(e),
+            MailboxError::Foe(e) => Some(e),
+            MailboxError::Soe(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SdoError> for MailboxError {
+    fn from(e: SdoError) -> Self {
+        MailboxError::Sdo(e)
+    }
+}
+
+impl From<FoeError> for MailboxError {
+    fn from(e: FoeError) -> Self {
+        MailboxError::Foe(e)
+    }
+}
+
+impl From<SoeError> for MailboxError {
+    fn from(e: SoeError) -> Self {
+        MailboxError::Soe(e)
+    }
+}
+
+/// Transport state of a mailbox request.
+#[derive(Debug)]
+pub(crate) enum Step<E> {
+    /// The request's frame still has to be handed to SOEM.
+    Send,
+    /// Queued for the cyclic handler under this ticket.
+    Queued(i32),
+    /// Waiting for the slave's answer to the last frame.
+    Receive,
+    Done(Result<Vec<u8>, E>),
+}
+
+/// What became of a frame handed to `Fieldbus::mailbox_send`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outgoing {
+    /// Written to the slave's mailbox.
+    Written,
+    /// Queued for the cyclic handler under this ticket.
+    Queued(i32),
+    /// Not taken: the slave's mailbox, the buffer pool or the queue is full.
+    Retry,
+}
+
+fn frame_type(frame: &[u8]) -> u32 {
+    frame.get(5).map_or(ECT_MBXT_ERR, |t| *t as u32 & 0x0f)
+}
+
+/// Mailbox transport shared by the requests. A frame is sent directly with FPWR or,
+/// with the cyclic handler enabled, queued for it; the ticket is kept until the
+/// handler has written the frame, which it retries after a failed write.
+impl Fieldbus {
+    /// Start a frame of `length` bytes after the mailbox header, with the next
+    /// mailbox counter of `slave`.
+    pub(crate) fn mailbox_frame(&mut self, slave: u16, mbx_type: u32, length: usize) -> Vec<u8> {
+        let s = &mut self.context.slavelist[slave as usize];
+        s.mbx_cnt = unsafe { ec_nextmbxcnt(s.mbx_cnt) };
+        let mut frame = vec![0u8; MBX_HEADER + length];
+        frame[0..2].copy_from_slice(&(length as u16).to_le_bytes());
+        frame[5] = mbx_type as u8 | (s.mbx_cnt << 4);
+        frame
+    }
+
+    /// Bytes available after the mailbox header in a frame to `slave`.
+    pub(crate) fn mailbox_capacity(&self, slave: u16) -> usize {
+        let s = &self.context.slavelist[slave as usize];
+        (s.mbx_l as usize)
+            .min(EC_MAXMBX as usize)
+            .saturating_sub(MBX_HEADER)
+    }
+
+    fn cyclic_mailbox(&self, slave: u16) -> bool {
+        self.context.slavelist[slave as usize].mbxhandlerstate == ECT_MBXH_CYCLIC as i32
+    }
+
+    pub(crate) fn mailbox_send(&mut self, slave: u16, frame: &[u8]) -> Outgoing {
+        let s = &self.context.slavelist[slave as usize];
+        let (station, offset) = (s.configadr, s.mbx_wo);
+        let length = (s.mbx_l as usize).min(EC_MAXMBX as usize);
+        if frame.len() > length {
+            return Outgoing::Retry;
+        }
+        if !self.cyclic_mailbox(slave) {
+            let mut data = frame.to_vec();
+            data.resize(length, 0);
+            return match self.addressed(ecx_FPWR, station, offset, &mut data, Some(1)) {
+                Ok(_) => Outgoing::Written,
+                Err(_) => Outgoing::Retry,
+            };
+        }
+        let context = &mut *self.context as *mut ecx_contextt;
+        unsafe {
+            let mbx = ecx_getmbx(context);
+            if mbx.is_null() {
+                return Outgoing::Retry;
+            }
+            ptr::write_bytes(mbx as *mut u8, 0, size_of::<ec_mbxbuft>());
+            ptr::copy_nonoverlapping(frame.as_ptr(), mbx as *mut u8, frame.len());
+            let ticket = ecx_mbxaddqueue(context, slave, mbx);
+            if ticket < 0 {
+                ecx_dropmbx(context, mbx);
+                return Outgoing::Retry;
+            }
+            Outgoing::Queued(ticket)
+        }
+    }
+
+    /// Whether the cyclic handler has written the frame queued under `ticket`. The
+    /// ticket is released once this returns true.
+    pub(crate) fn mailbox_sent(&mut self, slave: u16, ticket: i32) -> bool {
+        let context = &mut *self.context as *mut ecx_contextt;
+        unsafe { ecx_mbxdonequeue(context, slave, ticket) != 0 }
+    }
+
+    /// Withdraw the frame of a request that is given up while still queued.
+    pub(crate) fn mailbox_cancel<E>(&mut self, slave: u16, step: &Step<E>) {
+        if let Step::Queued(ticket) = *step {
+            let context = &mut *self.context as *mut ecx_contextt;
+            unsafe { ecx_mbxexpirequeue(context, slave, ticket) };
+        }
+    }
+
+    /// Take a frame of protocol `mbx_type` (`ECT_MBXT_*`), or a mailbox error, from
+    /// `slave`. When reading directly, frames of other protocols are kept for their
+    /// requests and emergencies are handed to the emergency handlers.
+    pub(crate) fn mailbox_receive(&mut self, slave: u16, mbx_type: u32) -> Option<Vec<u8>> {
+        if self.cyclic_mailbox(slave) {
+            return self.take_mailbox_slot(slave, mbx_type);
+        }
+        if let Some(i) = self
+            .mailbox_inbox
+            .iter()
+            .position(|(s, frame)| *s == slave && frame_type(frame) == mbx_type)
+        {
+            return self.mailbox_inbox.remove(i).map(|(_, frame)| frame);
+        }
+        let frame = self.read_mailbox(slave)?;
+        let kind = frame_type(&frame);
+        if kind == ECT_MBXT_COE
+            && frame.len() >= MBX_HEADER + 2
+            && u16::from_le_bytes([frame[6], frame[7]]) >> 12 == ECT_COES_EMERGENCY as u16
+        {
+            if let Some(emcy) = Emergency::decode(slave, &frame) {
+                self.deliver_emergency(&emcy);
+            }
+            return None;
+        }
+        if kind == mbx_type || kind == ECT_MBXT_ERR {
+            return Some(frame);
+        }
+        if self.mailbox_inbox.len() == INBOX_SIZE {
+            self.mailbox_inbox.pop_front();
+        }
+        self.mailbox_inbox.push_back((slave, frame));
+        None
+    }
+
+    /// Read the slave's read mailbox if it is full.
+    fn read_mailbox(&mut self, slave: u16) -> Option<Vec<u8>> {
+        let s = &self.context.slavelist[slave as usize];
+        let (station, offset, length) = (s.configadr, s.mbx_ro, s.mbx_rl as usize);
+        if length == 0 || length > EC_MAXMBX as usize {
+            return None;
+        }
+        let status: u8 = self.fprd(station, ECT_REG_SM1STAT as u16).ok()?;
+        if status & 0x08 == 0 {
+            return None;
+        }
+        let mut frame = vec![0u8; length];
+        if self
+            .addressed(ecx_FPRD, station, offset, &mut frame, Some(1))
+            .is_err()
+        {
+            // the slave may already count the frame as read, have it put it back
+            if let Ok(status) = self.fprd::<u16>(station, ECT_REG_SM1STAT as u16) {
+                let _ = self.fpwr(station, ECT_REG_SM1STAT as u16, status ^ 0x0200);
+            }
+            return None;
+        }
+        Some(frame)
+    }
+
+    /// Take the frame the cyclic handler put into the in-mailbox slot of `mbx_type`.
+    fn take_mailbox_slot(&mut self, slave: u16, mbx_type: u32) -> Option<Vec<u8>> {
+        let s = &mut self.context.slavelist[slave as usize];
+        let (slot, full) = match mbx_type {
+            ECT_MBXT_COE => (&mut s.coembxin, &mut s.coembxinfull),
+            ECT_MBXT_SOE => (&mut s.soembxin, &mut s.soembxinfull),
+            ECT_MBXT_FOE => (&mut s.foembxin, &mut s.foembxinfull),
+            ECT_MBXT_EOE => (&mut s.eoembxin, &mut s.eoembxinfull),
+            _ => return None,
+        };
+        if *full == 0 {
+            return None;
+        }
+        let mbx = mem::replace(slot, MBX_IN_ENABLE) as *mut ec_mbxbuft;
+        *full = 0;
+        let frame = unsafe { (*mbx).to_vec() };
+        let context = &mut *self.context as *mut ecx_contextt;
+        unsafe { ecx_dropmbx(context, mbx) };
+        Some(frame)
+    }
+
+    /// One transport step of a request whose current frame is `frame`: hand it to
+    /// SOEM, wait until the cyclic handler has written it or look for an answer of
+    /// protocol `mbx_type`. Returns the answer once there is one.
+    pub(crate) fn mailbox_step<E>(
+        &mut self,
+        slave: u16,
+        mbx_type: u32,
+        frame: &[u8],
+        step: &mut Step<E>,
+    ) -> Option<Vec<u8>> {
+        match *step {
+            Step::Send => {
+                match self.mailbox_send(slave, frame) {
+                    Outgoing::Written => *step = Step::Receive,
+                    Outgoing::Queued(ticket) => *step = Step::Queued(ticket),
+                    Outgoing::Retry => {}
+                }
+                None
+            }
+            Step::Queued(ticket) => {
+                if self.mailbox_sent(slave, ticket) {
+                    *step = Step::Receive;
+                }
+                None
+            }
+            Step::Receive => self.mailbox_receive(slave, mbx_type),
+            Step::Done(_) => None,
+        }
+    }
+}
+
+/// A request queued through a `MailboxClient`.
+pub(crate) enum MailboxRequest {
+    Sdo(SdoRequest),
+    Foe(FoeRequest),
+    Soe(SoeRequest),
+}
+
+impl MailboxRequest {
+    fn slave(&self) -> u16 {
+        match self {
+            MailboxRequest::Sdo(r) => r.slave(),
+            MailboxRequest::Foe(r) => r.slave(),
+            MailboxRequest::Soe(r) => r.slave(),
+        }
+    }
+
+    /// When the request times out, and the error it then ends with.
+    fn deadline(&self) -> (Instant, MailboxError) {
+        match self {
+            MailboxRequest::Sdo(r) => (r.deadline(), SdoError::Timeout.into()),
+            MailboxRequest::Foe(r) => (r.deadline(), FoeError::Timeout.into()),
+            MailboxRequest::Soe(r) => (r.deadline(), SoeError::Timeout.into()),
+        }
+    }
+
+    fn poll(&mut self, bus: &mut Fieldbus) -> Poll<Result<Vec<u8>, MailboxError>> {
+        let slave = self.slave();
+        if slave == 0 || slave as i32 > bus.context.slavecount {
+            return Poll::Ready(Err(MailboxError::NoSlave(slave)));
+        }
+        match self {
+            MailboxRequest::Sdo(r) => r.poll(bus).map_err(MailboxError::from),
+            MailboxRequest::Foe(r) => r.poll(bus).map_err(MailboxError::from),
+            MailboxRequest::Soe(r) => r.poll(bus).map_err(MailboxError::from),
+        }
+    }
+}
+
+struct Job {
+    request: MailboxRequest,
+    completion: Completion<Vec<u8>>,
+}
+
+/// Requests waiting for the thread that owns the `Fieldbus`.
+pub(crate) struct MailboxQueue {
+    sender: mpsc::Sender<Job>,
+    receiver: mpsc::Receiver<Job>,
+    timer: mpsc::Sender<Deadline>,
+    /// Per slave in submission order; the front one is in progress.
+    pending: BTreeMap<u16, VecDeque<Job>>,
+}
+
+impl MailboxQueue {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let (timer, deadlines) = mpsc::channel();
+        thread::spawn(move || expire_replies(deadlines));
+        MailboxQueue {
+            sender,
+            receiver,
+            timer,
+            pending: BTreeMap::new(),
+        }
+    }
+}
+
+struct Slot<T> {
+    value: Option<Result<T, MailboxError>>,
+    /// A value has been set; later ones are dropped.
+    done: bool,
+    waker: Option<Waker>,
+}
+
+impl<T> Slot<T> {
+    fn set(&mut self, value: Result<T, MailboxError>) {
+        if self.done {
+            return;
+        }
+        self.value = Some(value);
+        self.done = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The timeout of a submitted request, for the timer thread.
+struct Deadline {
+    at: Instant,
+    error: MailboxError,
+    slot: Weak<Mutex<Slot<Vec<u8>>>>,
+}
+
+/// Timer thread: end replies whose requests are past their deadline, whether or
+/// not the bus owner has got to them. The request itself times out at the same
+/// moment when it is next polled. Runs until all clients and the queue are gone
+/// and no deadline is left.
+fn expire_replies(deadlines: mpsc::Receiver<Deadline>) {
+    let mut pending: Vec<Deadline> = Vec::new();
+    let mut connected = true;
+    loop {
+        let next = pending.iter().map(|d| d.at).min();
+        match (next, connected) {
+            (None, false) => return,
+            (None, true) => match deadlines.recv() {
+                Ok(deadline) => pending.push(deadline),
+                Err(_) => connected = false,
+            },
+            (Some(at), true) => {
+                match deadlines.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                    Ok(deadline) => pending.push(deadline),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => connected = false,
+                }
+            }
+            (Some(at), false) => thread::sleep(at.saturating_duration_since(Instant::now())),
+        }
+        let now = Instant::now();
+        pending.retain(|deadline| {
+            let Some(slot) = deadline.slot.upgrade() else {
+                return false;
+            };
+            let mut slot = slot.lock().unwrap();
+            if deadline.at <= now {
+                slot.set(Err(deadline.error.clone()));
+            }
+            !slot.done
+        });
+    }
+}
+
+/// Future for the result of a queued mailbox request.
+pub struct Reply<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T, MailboxError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.value.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Completes the `Reply` when the request is done, or with `Closed` if it is
+/// dropped before.
+struct Completion<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+    value: Option<Result<T, MailboxError>>,
+}
+
+impl<T> Completion<T> {
+    fn complete(mut self, value: Result<T, MailboxError>) {
+        self.value = Some(value);
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let value = self.value.take().unwrap_or(Err(MailboxError::Closed));
+        self.slot.lock().unwrap().set(value);
+    }
+}
+
+/// Cloneable handle for queueing mailbox requests from other threads, obtained
+/// from `Fieldbus::mailbox_client`.
+///
+/// The client never touches the bus. Requests wait until the thread that owns
+/// the `Fieldbus` advances them with `cycle` or `service_mailbox_requests`, one
+/// mailbox step per call. A tool without a cyclic loop has to call
+/// `service_mailbox_requests` itself while it waits; otherwise the replies end
+/// with a timeout once the request's timeout has passed since submission.
+#[derive(Clone)]
+pub struct MailboxClient {
+    sender: mpsc::Sender<Job>,
+    timer: mpsc::Sender<Deadline>,
+}
+
+impl MailboxClient {
+    pub(crate) fn submit(&self, request: MailboxRequest) -> Reply<Vec<u8>> {
+        let slot = Arc::new(Mutex::new(Slot {
+            value: None,
+            done: false,
+            waker: None,
+        }));
+        let completion = Completion {
+            slot: slot.clone(),
+            value: None,
+        };
+        let (at, error) = request.deadline();
+        let _ = self.timer.send(Deadline {
+            at,
+            error,
+            slot: Arc::downgrade(&slot),
+        });
+        // if the queue is gone the job is dropped here and completes as Closed
+        let _ = self.sender.send(Job {
+            request,
+            completion,
+        });
+        Reply { slot }
+    }
+
+    pub async fn sdo_read(
+        &self,
+        slave: u16,
+        index: u16,
+        subindex: u8,
+    ) -> Result<Vec<u8>, MailboxError> {
+        self.sdo(SdoRequest::read(slave, index, subindex)).await
+    }
+
+    pub async fn sdo_write(
+        &self,
+        slave: u16,
+        index: u16,
+        subindex: u8,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<(), MailboxError> {
+        self.sdo(SdoRequest::write(slave, index, subindex, data))
+            .await
+            .map(|_| ())
+    }
+
+    /// Run a prepared request, e.g. one with complete access or its own timeout.
+    pub async fn sdo(&self, request: SdoRequest) -> Result<Vec<u8>, MailboxError> {
+        self.submit(MailboxRequest::Sdo(request)).await
+    }
+
+    /// Read a file of at most `max_size` bytes.
+    pub async fn foe_read(
+        &self,
+        slave: u16,
+        filename: &str,
+        password: u32,
+        max_size: usize,
+    ) -> Result<Vec<u8>, MailboxError> {
+        self.foe(FoeRequest::read(slave, filename, password).max_size(max_size))
+            .await
+    }
+
+    pub async fn foe_write(
+        &self,
+        slave: u16,
+        filename: &str,
+        password: u32,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<(), MailboxError> {
+        self.foe(FoeRequest::write(slave, filename, password, data))
+            .await
+            .map(|_| ())
+    }
+
+    /// Run a prepared FoE request.
+    pub async fn foe(&self, request: FoeRequest) -> Result<Vec<u8>, MailboxError> {
+        self.submit(MailboxRequest::Foe(request)).await
+    }
+
+    /// Read the value of an SoE IDN.
+    pub async fn soe_read(&self, slave: u16, drive: u8, idn: u16) -> Result<Vec<u8>, MailboxError> {
+        self.soe(SoeRequest::read(slave, drive, idn)).await
+    }
+
+    /// Write the value of an SoE IDN.
+    pub async fn soe_write(
+        &self,
+        slave: u16,
+        drive: u8,
+        idn: u16,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<(), MailboxError> {
+        self.soe(SoeRequest::write(slave, drive, idn, data))
+            .await
+            .map(|_| ())
+    }
+
+    /// Run a prepared SoE request, e.g. for another element than the value.
+    pub async fn soe(&self, request: SoeRequest) -> Result<Vec<u8>, MailboxError> {
+        self.submit(MailboxRequest::Soe(request)).await
+    }
+}
+
+impl Fieldbus {
+    /// Get a handle for queueing mailbox requests. They are carried out by `cycle`,
+    /// or by `service_mailbox_requests` in loops of their own, until `stop`.
+    pub fn mailbox_client(&mut self) -> MailboxClient {
+        let queue = self.mailbox_queue.get_or_insert_with(MailboxQueue::new);
+        MailboxClient {
+            sender: queue.sender.clone(),
+            timer: queue.timer.clone(),
+        }
+    }
+
+    /// Advance the queued mailbox requests: the oldest request of each slave is
+    /// polled once.
+    pub fn service_mailbox_requests(&mut self) {
+        let Some(mut queue) = self.mailbox_queue.take() else {
+            return;
+        };
+        while let Ok(job) = queue.receiver.try_recv() {
+            queue
+                .pending
+                .entry(job.request.slave())
+                .or_default()
+                .push_back(job);
+        }
+        for jobs in queue.pending.values_mut() {
+            let Some(job) = jobs.front_mut() else {
+                continue;
+            };
+            if let Poll::Ready(result) = job.request.poll(self) {
+                let job = jobs.pop_front().unwrap();
+                job.completion.complete(result);
+            }
+        }
+        queue.pending.retain(|_, jobs| !jobs.is_empty());
+        self.mailbox_queue = Some(queue);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CString,
+        sync::Arc,
+        task::Wake,
+        thread::Thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn unserviced_requests_time_out() {
+        let mut bus = Fieldbus::new(CString::new("test").unwrap());
+        let client = bus.mailbox_client();
+        let started = Instant::now();
+        let request = SdoRequest::read(1, 0x1018, 1).timeout(Duration::from_millis(20));
+        assert_eq!(
+            block_on(client.sdo(request)),
+            Err(MailboxError::Sdo(SdoError::Timeout))
+        );
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        let request = FoeRequest::read(1, "data", 0).timeout(Duration::ZERO);
+        assert_eq!(
+            block_on(client.foe(request)),
+            Err(MailboxError::Foe(FoeError::Timeout))
+        );
+        // the owner finds the requests expired, one per call, and sends nothing
+        bus.context.slavecount = 1;
+        bus.service_mailbox_requests();
+        bus.service_mailbox_requests();
+        assert!(bus.mailbox_queue.as_ref().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn serviced_requests_complete() {
+        let mut bus = Fieldbus::new(CString::new("test").unwrap());
+        let client = bus.mailbox_client();
+        let reply = client.submit(MailboxRequest::Soe(SoeRequest::read(3, 0, 32)));
+        bus.service_mailbox_requests();
+        assert_eq!(block_on(reply), Err(MailboxError::NoSlave(3)));
+    }
+
+    #[test]
+    fn stopped_queue_closes_replies() {
+        let mut bus = Fieldbus::new(CString::new("test").unwrap());
+        let client = bus.mailbox_client();
+        let reply = client.submit(MailboxRequest::Sdo(SdoRequest::read(1, 0x1018, 1)));
+        bus.mailbox_queue = None;
+        assert_eq!(block_on(reply), Err(MailboxError::Closed));
+    }
+}
@@ -0,0 +1,267 @@
+//! Oversampled process data: terminals that transfer `factor` samples of a signal
+//! per cycle, e.g. the EL37xx inputs or EL47xx outputs. They are synchronised with
+//! `DcMode::Sync01`, SYNC0 clocking the samples and SYNC1 (every `factor` SYNC0
+//! pulses) swapping the buffers, so the time of each sample follows from the bus
+//! time of the frame and the sync settings.
+
+use std::{fmt, marker::PhantomData};
+
+use crate::simple_ng::Fieldbus;
+
+/// A value in an oversampling array, little endian in the process image.
+pub trait Sample: Copy {
+    const SIZE: usize;
+    fn decode(bytes: &[u8]) -> Self;
+    fn encode(self, bytes: &mut [u8]);
+}
+
+macro_rules! sample {
+    ($($t:ty),*) => {$(
+        impl Sample for $t {
+            const SIZE: usize = size_of::<$t>();
+
+            fn decode(bytes: &[u8]) -> Self {
+                <$t>::from_le_bytes(bytes[..Self::SIZE].try_into().unwrap())
+            }
+
+            fn encode(self, bytes: &mut [u8]) {
+                bytes[..Self::SIZE].copy_from_slice(&self.to_le_bytes());
+            }
+        }
+    )*};
+}
+
+sample!(u8, i8, u16, i16, u32, i32, u64, i64, f32, f64);
+
+/// An array of samples in the inputs or outputs of a slave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Oversampling<T> {
+    pub slave: u16,
+    /// Byte offset of the first sample in the slave's inputs or outputs.
+    pub offset: usize,
+    /// Samples per cycle.
+    pub factor: u32,
+    /// Bytes from one sample to the next, more than the sample size if each sits
+    /// in a PDO with other entries.
+    pub stride: usize,
+    /// Added to every timestamp in ns, e.g. the conversion time of the terminal.
+    pub delay: i64,
+    sample: PhantomData<T>,
+}
+
+impl<T: Sample> Oversampling<T> {
+    pub fn new(slave: u16, offset: usize, factor: u32) -> Self {
+        Oversampling {
+            slave,
+            offset,
+            factor,
+            stride: T::SIZE,
+            delay: 0,
+            sample: PhantomData,
+        }
+    }
+
+    /// At least the sample size, checked when the array is accessed.
+    pub fn stride(mut self, stride: usize) -> Self {
+        self.stride = stride;
+        self
+    }
+
+    pub fn delay(mut self, delay: i64) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Times of the samples of a SYNC1 period starting at `start`.
+    fn times(&self, start: u64, interval: u64) -> impl Iterator<Item = u64> {
+        let delay = self.delay;
+        (0..self.factor as u64).map(move |k| (start + k * interval).wrapping_add_signed(delay))
+    }
+
+    /// Bytes the array spans.
+    fn len(&self) -> usize {
+        match self.factor {
+            0 => 0,
+            n => (n as usize - 1) * self.stride + T::SIZE,
+        }
+    }
+
+    /// Check the array against a slave's inputs or outputs of `bytes` bytes.
+    fn check_layout(&self, bytes: usize) -> Result<(), OversamplingError> {
+        if self.stride < T::SIZE {
+            return Err(OversamplingError::Stride {
+                stride: self.stride,
+                size: T::SIZE,
+            });
+        }
+        if self.offset + self.len() > bytes {
+            return Err(OversamplingError::OutOfRange(self.slave));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimedSample<T> {
+    /// System time of the sample in ns.
+    pub time: u64,
+    pub value: T,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OversamplingError {
+    /// The slave has no active SYNC0, so sample times are unknown.
+    NotSynchronised(u16),
+    /// The array does not fit in the slave's process data, or that is not byte
+    /// aligned.
+    OutOfRange(u16),
+    /// Other than `factor` values were given.
+    Length { expected: u32, found: usize },
+    /// The stride is below the sample size, so samples would overlap.
+    Stride { stride: usize, size: usize },
+}
+
+impl fmt::Display for OversamplingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OversamplingError::NotSynchronised(slave) => {
+                write!(f, "slave {} has no active SYNC0", slave)
+            }
+            OversamplingError::OutOfRange(slave) => {
+                write!(f, "samples are outside the process data of slave {}", slave)
+            }
+            OversamplingError::Length { expected, found } => {
+                write!(f, "expected {} samples, got {}", expected, found)
+            }
+            OversamplingError::Stride { stride, size } => {
+                write!(f, "stride {} is below the sample size {}", stride, size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OversamplingError {}
+
+impl Fieldbus {
+    /// SYNC0 cycle of a slave and the start of the SYNC1 period the last frame
+    /// passed in, both in ns.
+    fn sample_clock<T>(&self, channel: &Oversampling<T>) -> Result<(u64, u64), OversamplingError> {
+        let s = &self.context.slavelist[channel.slave as usize];
+        if s.DCactive == 0 || s.DCcycle == 0 || channel.factor == 0 {
+            return Err(OversamplingError::NotSynchronised(channel.slave));
+        }
+        let interval = s.DCcycle as u64;
+        let period = interval * channel.factor as u64;
+        let since = (self.context.DCtime - s.DCshift as i64).rem_euclid(period as i64) as u64;
+        Ok((interval, self.context.DCtime as u64 - since))
+    }
+
+    /// The samples received with the last process data exchange. They were taken
+    /// in the SYNC1 period before the frame.
+    pub fn oversampled_inputs<T: Sample>(
+        &self,
+        channel: &Oversampling<T>,
+    ) -> Result<Vec<TimedSample<T>>, OversamplingError> {
+        let (interval, current) = self.sample_clock(channel)?;
+        let s = &self.context.slavelist[channel.slave as usize];
+        if s.inputs.is_null() || s.Istartbit != 0 {
+            return Err(OversamplingError::OutOfRange(channel.slave));
+        }
+        channel.check_layout(s.Ibytes as usize)?;
+        let inputs = unsafe { std::slice::from_raw_parts(s.inputs, s.Ibytes as usize) };
+        let start = current - interval * channel.factor as u64;
+        Ok(channel
+            .times(start, interval)
+            .enumerate()
+            .map(|(k, time)| TimedSample {
+                time,
+                value: T::decode(&inputs[channel.offset + k * channel.stride..]),
+            })
+            .collect())
+    }
+
+    /// Put `values` into the outputs for the next process data exchange and return
+    /// the times they will be output, in the SYNC1 period after the frame.
+    pub fn write_oversampled_outputs<T: Sample>(
+        &mut self,
+        channel: &Oversampling<T>,
+        values: &[T],
+    ) -> Result<Vec<u64>, OversamplingError> {
+        if values.len() != channel.factor as usize {
+            return Err(OversamplingError::Length {
+                expected: channel.factor,
+                found: values.len(),
+            });
+        }
+        let (interval, current) = self.sample_clock(channel)?;
+        let s = &self.context.slavelist[channel.slave as usize];
+        if s.outputs.is_null() || s.Ostartbit != 0 {
+            return Err(OversamplingError::OutOfRange(channel.slave));
+        }
+        channel.check_layout(s.Obytes as usize)?;
+        let outputs = unsafe { std::slice::from_raw_parts_mut(s.outputs, s.Obytes as usize) };
+        for (k, value) in values.iter().enumerate() {
+            value.encode(&mut outputs[channel.offset + k * channel.stride..]);
+        }
+        let start = current + interval * channel.factor as u64;
+        Ok(channel.times(start, interval).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let channel = Oversampling::<i16>::new(1, 4, 10);
+        assert_eq!(channel.len(), 20);
+        assert_eq!(channel.check_layout(24), Ok(()));
+        assert_eq!(
+            channel.check_layout(23),
+            Err(OversamplingError::OutOfRange(1))
+        );
+        let channel = channel.stride(6);
+        assert_eq!(channel.len(), 9 * 6 + 2);
+        assert_eq!(Oversampling::<u8>::new(1, 0, 0).len(), 0);
+        assert_eq!(
+            Oversampling::<i32>::new(1, 0, 4).stride(2).check_layout(64),
+            Err(OversamplingError::Stride { stride: 2, size: 4 })
+        );
+    }
+
+    #[test]
+    fn times() {
+        let channel = Oversampling::<i16>::new(1, 0, 4).delay(-500);
+        let times: Vec<u64> = channel.times(1_000_000, 250_000).collect();
+        assert_eq!(times, [999_500, 1_249_500, 1_499_500, 1_749_500]);
+    }
+
+    #[test]
+    fn sample_times_from_the_bus_time() {
+        let mut bus = Fieldbus::new(CString::new("test").unwrap());
+        let mut inputs = [1u8, 0, 0xAA, 2, 0, 0xAA, 3, 0, 0xAA, 4, 0, 0xAA];
+        let slave = &mut bus.context.slavelist[1];
+        slave.DCactive = 1;
+        slave.DCcycle = 250_000;
+        slave.DCshift = 10_000;
+        slave.inputs = inputs.as_mut_ptr();
+        slave.Ibytes = inputs.len() as u32;
+        // frame 300 us into the SYNC1 period starting at 5 ms + shift
+        bus.context.DCtime = 5_310_000;
+        let channel = Oversampling::<u16>::new(1, 0, 4).stride(3);
+        let samples = bus.oversampled_inputs(&channel).unwrap();
+        let times: Vec<u64> = samples.iter().map(|s| s.time).collect();
+        let values: Vec<u16> = samples.iter().map(|s| s.value).collect();
+        assert_eq!(times, [4_010_000, 4_260_000, 4_510_000, 4_760_000]);
+        assert_eq!(values, [1, 2, 3, 4]);
+        let overlapping = Oversampling::<u32>::new(1, 0, 2).stride(3);
+        assert!(matches!(
+            bus.oversampled_inputs(&overlapping),
+            Err(OversamplingError::Stride { .. })
+        ));
+        bus.context.slavelist[1].inputs = std::ptr::null_mut();
+    }
+}
@@ -0,0 +1,907 @@
+//! Typed definitions of the ESC registers used by SOEM (ETG.1000.4 / ESC
+//! datasheets). Read them with `Fieldbus::read_register::<DlStatus>(slave)`.
+
+use crate::bindings::*;
+use crate::datagram::DatagramError;
+use crate::simple_ng::Fieldbus;
+use crate::status::AlStatusCode;
+
+/// A register or block of registers at a fixed address.
+pub trait Register: Sized {
+    const ADDRESS: u16;
+    const SIZE: usize;
+
+    fn decode(bytes: &[u8]) -> Self;
+}
+
+/// A register the master may write.
+pub trait WritableRegister: Register {
+    fn encode(&self, bytes: &mut [u8]);
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// Registers that hold a single number.
+macro_rules! value_register {
+    ($(#[$meta:meta])* $name:ident($ty:ty) = $address:expr) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name(pub $ty);
+
+        impl Register for $name {
+            const ADDRESS: u16 = $address as u16;
+            const SIZE: usize = size_of::<$ty>();
+
+            fn decode(bytes: &[u8]) -> Self {
+                $name(<$ty>::from_le_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    };
+    ($(#[$meta:meta])* $name:ident($ty:ty) = $address:expr, writable) => {
+        value_register!($(#[$meta])* $name($ty) = $address);
+
+        impl WritableRegister for $name {
+            fn encode(&self, bytes: &mut [u8]) {
+                bytes.copy_from_slice(&self.0.to_le_bytes());
+            }
+        }
+    };
+}
+
+/// Physical layer of a port from the port descriptor (0x0007).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortType {
+    NotImplemented,
+    NotConfigured,
+    Ebus,
+    Mii,
+}
+
+/// ESC information (0x0000-0x0009).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EscInformation {
+    pub esc_type: u8,
+    pub revision: u8,
+    pub build: u16,
+    pub fmmus: u8,
+    pub sync_managers: u8,
+    /// Process data RAM in KiB.
+    pub ram_size: u8,
+    pub ports: [PortType; 4],
+    /// ESC features supported (0x0008), e.g. bit 2 distributed clocks and bit 3
+    /// 64 bit DC.
+    pub features: u16,
+}
+
+impl EscInformation {
+    pub fn has_dc(&self) -> bool {
+        self.features & 0x0004 != 0
+    }
+
+    pub fn has_dc64(&self) -> bool {
+        self.features & 0x0008 != 0
+    }
+}
+
+impl Register for EscInformation {
+    const ADDRESS: u16 = ECT_REG_TYPE as u16;
+    const SIZE: usize = 10;
+
+    fn decode(bytes: &[u8]) -> Self {
+        let port = |n: usize| match (bytes[7] >> (2 * n)) & 0x03 {
+            0 => PortType::NotImplemented,
+            1 => PortType::NotConfigured,
+            2 => PortType::Ebus,
+            _ => PortType::Mii,
+        };
+        EscInformation {
+            esc_type: bytes[0],
+            revision: bytes[1],
+            build: u16_at(bytes, 2),
+            fmmus: bytes[4],
+            sync_managers: bytes[5],
+            ram_size: bytes[6],
+            ports: [port(0), port(1), port(2), port(3)],
+            features: u16_at(bytes, 8),
+        }
+    }
+}
+
+value_register!(
+    /// Configured station address (0x0010), used by FP addressing.
+    StationAddress(u16) = ECT_REG_STADR, writable
+);
+value_register!(
+    /// Configured station alias (0x0012), loaded from SII word 4 at power-up or
+    /// reload. Read only for the master.
+    StationAlias(u16) = ECT_REG_ALIAS
+);
+
+/// Loop setting of a port in the DL control register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopControl {
+    /// Closed when the link is down, open when it is up.
+    Auto,
+    /// Like `Auto`, but the port only opens after a valid frame was received.
+    AutoClose,
+    AlwaysOpen,
+    AlwaysClosed,
+}
+
+/// DL control (0x0100).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DlControl {
+    /// Non-EtherCAT frames are destroyed instead of forwarded.
+    pub forwarding_rule: bool,
+    /// Loop settings only last until the next link change.
+    pub temporary: bool,
+    pub loops: [LoopControl; 4],
+    pub rx_fifo_size: u8,
+    pub ebus_low_jitter: bool,
+    /// Station alias (0x0012) may be used for FP addressing.
+    pub alias_enable: bool,
+}
+
+impl Register for DlControl {
+    const ADDRESS: u16 = ECT_REG_DLCTL as u16;
+    const SIZE: usize = 4;
+
+    fn decode(bytes: &[u8]) -> Self {
+        let value = u32_at(bytes, 0);
+        let loop_control = |n: u32| match (value >> (8 + 2 * n)) & 0x03 {
+            0 => LoopControl::Auto,
+            1 => LoopControl::AutoClose,
+            2 => LoopControl::AlwaysOpen,
+            _ => LoopControl::AlwaysClosed,
+        };
+        DlControl {
+            forwarding_rule: value & 0x01 != 0,
+            temporary: value & 0x02 != 0,
+            loops: [
+                loop_control(0),
+                loop_control(1),
+                loop_control(2),
+                loop_control(3),
+            ],
+            rx_fifo_size: ((value >> 16) & 0x07) as u8,
+            ebus_low_jitter: value & (1 << 19) != 0,
+            alias_enable: value & (1 << 24) != 0,
+        }
+    }
+}
+
+impl WritableRegister for DlControl {
+    fn encode(&self, bytes: &mut [u8]) {
+        let mut value = self.forwarding_rule as u32 | (self.temporary as u32) << 1;
+        for (n, l) in self.loops.iter().enumerate() {
+            value |= (*l as u32) << (8 + 2 * n);
+        }
+        value |= (self.rx_fifo_size as u32 & 0x07) << 16;
+        value |= (self.ebus_low_jitter as u32) << 19;
+        value |= (self.alias_enable as u32) << 24;
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Link state of one port from DL status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortStatus {
+    pub link: bool,
+    pub loop_closed: bool,
+    pub communication: bool,
+}
+
+/// DL status (0x0110).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DlStatus {
+    pub pdi_operational: bool,
+    /// PDI watchdog not expired (or disabled).
+    pub pdi_watchdog_ok: bool,
+    pub enhanced_link_detection: bool,
+    pub ports: [PortStatus; 4],
+}
+
+impl Register for DlStatus {
+    const ADDRESS: u16 = ECT_REG_DLSTAT as u16;
+    const SIZE: usize = 2;
+
+    fn decode(bytes: &[u8]) -> Self {
+        let value = u16_at(bytes, 0);
+        let port = |n: u16| PortStatus {
+            link: value & (1 << (4 + n)) != 0,
+            loop_closed: value & (1 << (8 + 2 * n)) != 0,
+            communication: value & (1 << (9 + 2 * n)) != 0,
+        };
+        DlStatus {
+            pdi_operational: value & 0x01 != 0,
+            pdi_watchdog_ok: value & 0x02 != 0,
+            enhanced_link_detection: value & 0x04 != 0,
+            ports: [port(0), port(1), port(2), port(3)],
+        }
+    }
+}
+
+/// AL control (0x0120): the state requested by the master.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlControl {
+    /// `ec_state_EC_STATE_*` value.
+    pub state: u8,
+    pub acknowledge: bool,
+    pub request_id: bool,
+}
+
+impl Register for AlControl {
+    const ADDRESS: u16 = ECT_REG_ALCTL as u16;
+    const SIZE: usize = 2;
+
+    fn decode(bytes: &[u8]) -> Self {
+        AlControl {
+            state: bytes[0] & 0x0F,
+            acknowledge: bytes[0] & 0x10 != 0,
+            request_id: bytes[0] & 0x20 != 0,
+        }
+    }
+}
+
+impl WritableRegister for AlControl {
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes[0] = self.state & 0x0F | (self.acknowledge as u8) << 4 | (self.request_id as u8) << 5;
+        bytes[1] = 0;
+    }
+}
+
+/// AL status (0x0130): the slave's current state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlStatus {
+    /// `ec_state_EC_STATE_*` value.
+    pub state: u8,
+    /// The slave refused or left a state; see `AlStatusCode`.
+    pub error: bool,
+    pub id_loaded: bool,
+}
+
+impl Register for AlStatus {
+    const ADDRESS: u16 = ECT_REG_ALSTAT as u16;
+    const SIZE: usize = 2;
+
+    fn decode(bytes: &[u8]) -> Self {
+        AlStatus {
+            state: bytes[0] & 0x0F,
+            error: bytes[0] & 0x10 != 0,
+            id_loaded: bytes[0] & 0x20 != 0,
+        }
+    }
+}
+
+/// AL status code (0x0134).
+impl Register for AlStatusCode {
+    const ADDRESS: u16 = ECT_REG_ALSTATCODE as u16;
+    const SIZE: usize = 2;
+
+    fn decode(bytes: &[u8]) -> Self {
+        AlStatusCode::from(u16_at(bytes, 0))
+    }
+}
+
+/// Error counters of one port.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PortErrorCounters {
+    pub invalid_frame: u8,
+    pub rx_error: u8,
+    /// Errors detected by a slave further down the line.
+    pub forwarded_rx_error: u8,
+    pub lost_link: u8,
+}
+
+/// Error counters (0x0300-0x0313). The counters saturate at 255. Writing any value
+/// clears them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorCounters {
+    pub ports: [PortErrorCounters; 4],
+    pub processing_unit: u8,
+    pub pdi: u8,
+    pub pdi_error_code: u8,
+}
+
+impl Register for ErrorCounters {
+    const ADDRESS: u16 = ECT_REG_RXERR as u16;
+    const SIZE: usize = 0x14;
+
+    fn decode(bytes: &[u8]) -> Self {
+        let port = |n: usize| PortErrorCounters {
+            invalid_frame: bytes[2 * n],
+            rx_error: bytes[2 * n + 1],
+            forwarded_rx_error: bytes[0x08 + n],
+            lost_link: bytes[0x10 + n],
+        };
+        ErrorCounters {
+            ports: [port(0), port(1), port(2), port(3)],
+            processing_unit: bytes[0x0C],
+            pdi: bytes[0x0D],
+            pdi_error_code: bytes[0x0E],
+        }
+    }
+}
+
+impl WritableRegister for ErrorCounters {
+    fn encode(&self, bytes: &mut [u8]) {
+        for (n, p) in self.ports.iter().enumerate() {
+            bytes[2 * n] = p.invalid_frame;
+            bytes[2 * n + 1] = p.rx_error;
+            bytes[0x08 + n] = p.forwarded_rx_error;
+            bytes[0x10 + n] = p.lost_link;
+        }
+        bytes[0x0C] = self.processing_unit;
+        bytes[0x0D] = self.pdi;
+        bytes[0x0E] = self.pdi_error_code;
+    }
+}
+
+value_register!(
+    /// Watchdog divider (0x0400): watchdog time unit in 40 ns steps minus 2.
+    WatchdogDivider(u16) = 0x0400, writable
+);
+value_register!(
+    /// PDI watchdog time (0x0410) in divider units.
+    PdiWatchdogTime(u16) = 0x0410, writable
+);
+value_register!(
+    /// SyncManager (process data) watchdog time (0x0420) in divider units.
+    SmWatchdogTime(u16) = 0x0420, writable
+);
+
+/// Watchdog status and counters (0x0440-0x0443).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogStatus {
+    /// The process data watchdog has not expired.
+    pub process_data_ok: bool,
+    pub sm_expirations: u8,
+    pub pdi_expirations: u8,
+}
+
+impl Register for WatchdogStatus {
+    const ADDRESS: u16 = 0x0440;
+    const SIZE: usize = 4;
+
+    fn decode(bytes: &[u8]) -> Self {
+        WatchdogStatus {
+            process_data_ok: bytes[0] & 0x01 != 0,
+            sm_expirations: bytes[2],
+            pdi_expirations: bytes[3],
+        }
+    }
+}
+
+/// SII (EEPROM) control/status (0x0502).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SiiStatus {
+    pub write_enable: bool,
+    /// Reads return 8 bytes instead of 4.
+    pub read_8_bytes: bool,
+    pub two_byte_address: bool,
+    pub checksum_error: bool,
+    pub device_info_error: bool,
+    pub command_error: bool,
+    pub write_error: bool,
+    pub busy: bool,
+}
+
+impl Register for SiiStatus {
+    const ADDRESS: u16 = ECT_REG_EEPSTAT as u16;
+    const SIZE: usize = 2;
+
+    fn decode(bytes: &[u8]) -> Self {
+        let value = u16_at(bytes, 0);
+        let bit = |n: u16| value & (1 << n) != 0;
+        SiiStatus {
+            write_enable: bit(0),
+            read_8_bytes: bit(6),
+            two_byte_address: bit(7),
+            checksum_error: bit(11),
+            device_info_error: bit(12),
+            command_error: bit(13),
+            write_error: bit(14),
+            busy: bit(15),
+        }
+    }
+}
+
+/// FMMU `N` (0x0600 + 16 N).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fmmu<const N: u8> {
+    pub logical_start: u32,
+    pub length: u16,
+    pub logical_start_bit: u8,
+    pub logical_end_bit: u8,
+    pub physical_start: u16,
+    pub physical_start_bit: u8,
+    /// 1 read (inputs), 2 write (outputs), 3 both.
+    pub fmmu_type: u8,
+    pub active: bool,
+}
+
+impl<const N: u8> Register for Fmmu<N> {
+    const ADDRESS: u16 = ECT_REG_FMMU0 as u16 + 16 * N as u16;
+    const SIZE: usize = 16;
+
+    fn decode(bytes: &[u8]) -> Self {
+        Fmmu {
+            logical_start: u32_at(bytes, 0),
+            length: u16_at(bytes, 4),
+            logical_start_bit: bytes[6],
+            logical_end_bit: bytes[7],
+            physical_start: u16_at(bytes, 8),
+            physical_start_bit: bytes[10],
+            fmmu_type: bytes[11],
+            active: bytes[12] & 0x01 != 0,
+        }
+    }
+}
+
+impl<const N: u8> WritableRegister for Fmmu<N> {
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes.fill(0);
+        bytes[0..4].copy_from_slice(&self.logical_start.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.length.to_le_bytes());
+        bytes[6] = self.logical_start_bit;
+        bytes[7] = self.logical_end_bit;
+        bytes[8..10].copy_from_slice(&self.physical_start.to_le_bytes());
+        bytes[10] = self.physical_start_bit;
+        bytes[11] = self.fmmu_type;
+        bytes[12] = self.active as u8;
+    }
+}
+
+/// SyncManager `N` (0x0800 + 8 N).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncManager<const N: u8> {
+    pub start_address: u16,
+    pub length: u16,
+    /// Control byte: mode (buffered/mailbox), direction, interrupts, watchdog.
+    pub control: u8,
+    /// Status byte, read only: interrupt flags, mailbox full, buffer state.
+    pub status: u8,
+    pub enable: bool,
+    pub repeat: bool,
+    /// PDI control: deactivate bit set by the application.
+    pub pdi_control: u8,
+}
+
+impl<const N: u8> SyncManager<N> {
+    /// Mailbox mode: the mailbox is full.
+    pub fn mailbox_full(&self) -> bool {
+        self.status & 0x08 != 0
+    }
+
+    /// The watchdog of this SyncManager is enabled.
+    pub fn watchdog_enabled(&self) -> bool {
+        self.control & 0x40 != 0
+    }
+}
+
+impl<const N: u8> Register for SyncManager<N> {
+    const ADDRESS: u16 = ECT_REG_SM0 as u16 + 8 * N as u16;
+    const SIZE: usize = 8;
+
+    fn decode(bytes: &[u8]) -> Self {
+        SyncManager {
+            start_address: u16_at(bytes, 0),
+            length: u16_at(bytes, 2),
+            control: bytes[4],
+            status: bytes[5],
+            enable: bytes[6] & 0x01 != 0,
+            repeat: bytes[6] & 0x02 != 0,
+            pdi_control: bytes[7],
+        }
+    }
+}
+
+impl<const N: u8> WritableRegister for SyncManager<N> {
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes[0..2].copy_from_slice(&self.start_address.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.length.to_le_bytes());
+        bytes[4] = self.control;
+        bytes[5] = self.status;
+        bytes[6] = self.enable as u8 | (self.repeat as u8) << 1;
+        bytes[7] = self.pdi_control;
+    }
+}
+
+/// DC receive times (0x0900-0x090F): local time when a frame passed each port.
+/// Writing 0x0900 latches the times of all ports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DcReceiveTimes {
+    pub ports: [u32; 4],
+}
+
+impl Register for DcReceiveTimes {
+    const ADDRESS: u16 = ECT_REG_DCTIME0 as u16;
+    const SIZE: usize = 16;
+
+    fn decode(bytes: &[u8]) -> Self {
+        DcReceiveTimes {
+            ports: [
+                u32_at(bytes, 0),
+                u32_at(bytes, 4),
+                u32_at(bytes, 8),
+                u32_at(bytes, 12),
+            ],
+        }
+    }
+}
+
+value_register!(
+    /// DC system time (0x0910) in ns since 2000-01-01. Writing it feeds the drift
+    /// compensation.
+    DcSystemTime(u64) = ECT_REG_DCSYSTIME, writable
+);
+value_register!(
+    /// DC system time offset (0x0920) between local and system time.
+    DcSystemTimeOffset(u64) = ECT_REG_DCSYSOFFSET, writable
+);
+value_register!(
+    /// DC system time delay (0x0928): propagation delay from the reference clock.
+    DcSystemTimeDelay(u32) = ECT_REG_DCSYSDELAY, writable
+);
+
+/// DC system time difference (0x092C): local copy of the system time minus the
+/// received system time, in ns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DcSystemTimeDifference(pub i32);
+
+impl Register for DcSystemTimeDifference {
+    const ADDRESS: u16 = ECT_REG_DCSYSDIFF as u16;
+    const SIZE: usize = 4;
+
+    fn decode(bytes: &[u8]) -> Self {
+        // sign and magnitude, not two's complement
+        let raw = u32_at(bytes, 0);
+        let magnitude = (raw & 0x7FFF_FFFF) as i32;
+        DcSystemTimeDifference(if raw & 0x8000_0000 != 0 {
+            -magnitude
+        } else {
+            magnitude
+        })
+    }
+}
+
+/// DC SYNC activation (0x0981).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DcActivation {
+    pub cyclic_operation: bool,
+    pub sync0: bool,
+    pub sync1: bool,
+}
+
+impl Register for DcActivation {
+    const ADDRESS: u16 = ECT_REG_DCSYNCACT as u16;
+    const SIZE: usize = 1;
+
+    fn decode(bytes: &[u8]) -> Self {
+        DcActivation {
+            cyclic_operation: bytes[0] & 0x01 != 0,
+            sync0: bytes[0] & 0x02 != 0,
+            sync1: bytes[0] & 0x04 != 0,
+        }
+    }
+}
+
+impl WritableRegister for DcActivation {
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes[0] = self.cyclic_operation as u8 | (self.sync0 as u8) << 1 | (self.sync1 as u8) << 2;
+    }
+}
+
+value_register!(
+    /// DC SYNC0 start time (0x0990) in system time.
+    DcSync0StartTime(u64) = ECT_REG_DCSTART0, writable
+);
+value_register!(
+    /// DC SYNC0 cycle time (0x09A0) in ns.
+    DcSync0CycleTime(u32) = ECT_REG_DCCYCLE0, writable
+);
+value_register!(
+    /// DC SYNC1 cycle time (0x09A4) in ns, relative to SYNC0.
+    DcSync1CycleTime(u32) = ECT_REG_DCCYCLE1, writable
+);
+
+/// Latch mode of one edge: continuous or only the first event until the time is
+/// read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatchEdges {
+    pub positive_single: bool,
+    pub negative_single: bool,
+}
+
+/// DC latch control (0x09A8-0x09A9) for LATCH0 and LATCH1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DcLatchControl {
+    pub latch: [LatchEdges; 2],
+}
+
+impl Register for DcLatchControl {
+    const ADDRESS: u16 = 0x09A8;
+    const SIZE: usize = 2;
+
+    fn decode(bytes: &[u8]) -> Self {
+        let latch = |b: u8| LatchEdges {
+            positive_single: b & 0x01 != 0,
+            negative_single: b & 0x02 != 0,
+        };
+        DcLatchControl {
+            latch: [latch(bytes[0]), latch(bytes[1])],
+        }
+    }
+}
+
+impl WritableRegister for DcLatchControl {
+    fn encode(&self, bytes: &mut [u8]) {
+        for (b, l) in bytes.iter_mut().zip(&self.latch) {
+            *b = l.positive_single as u8 | (l.negative_single as u8) << 1;
+        }
+    }
+}
+
+/// Event state of one latch input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatchState {
+    pub positive_event: bool,
+    pub negative_event: bool,
+    pub pin: bool,
+}
+
+/// DC latch status (0x09AE-0x09AF).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DcLatchStatus {
+    pub latch: [LatchState; 2],
+}
+
+impl Register for DcLatchStatus {
+    const ADDRESS: u16 = 0x09AE;
+    const SIZE: usize = 2;
+
+    fn decode(bytes: &[u8]) -> Self {
+        let latch = |b: u8| LatchState {
+            positive_event: b & 0x01 != 0,
+            negative_event: b & 0x02 != 0,
+            pin: b & 0x04 != 0,
+        };
+        DcLatchStatus {
+            latch: [latch(bytes[0]), latch(bytes[1])],
+        }
+    }
+}
+
+/// Edge times of one latch input in system time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatchTimes {
+    pub positive: u64,
+    pub negative: u64,
+}
+
+/// DC latch times (0x09B0-0x09CF). Reading a time acknowledges the event.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DcLatchTimes {
+    pub latch: [LatchTimes; 2],
+}
+
+impl Register for DcLatchTimes {
+    const ADDRESS: u16 = 0x09B0;
+    const SIZE: usize = 32;
+
+    fn decode(bytes: &[u8]) -> Self {
+        let latch = |at: usize| LatchTimes {
+            positive: u64_at(bytes, at),
+            negative: u64_at(bytes, at + 8),
+        };
+        DcLatchTimes {
+            latch: [latch(0), latch(16)],
+        }
+    }
+}
+
+impl Fieldbus {
+    /// Read a register of a slave (by slave number) using its station address.
+    pub fn read_register<R: Register>(&mut self, slave: u16) -> Result<R, DatagramError> {
+        let station = self.context.slavelist[slave as usize].configadr;
+        let mut data = vec![0u8; R::SIZE];
+        self.addressed(ecx_FPRD, station, R::ADDRESS, &mut data, Some(1))?;
+        Ok(R::decode(&data))
+    }
+
+    /// Write a register of a slave (by slave number).
+    pub fn write_register<R: WritableRegister>(
+        &mut self,
+        slave: u16,
+        value: &R,
+    ) -> Result<(), DatagramError> {
+        let station = self.context.slavelist[slave as usize].configadr;
+        let mut data = vec![0u8; R::SIZE];
+        value.encode(&mut data);
+        self.addressed(ecx_FPWR, station, R::ADDRESS, &mut data, Some(1))
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<R: WritableRegister + PartialEq + std::fmt::Debug>(bytes: &[u8]) {
+        assert_eq!(bytes.len(), R::SIZE);
+        let value = R::decode(bytes);
+        let mut encoded = vec![0u8; R::SIZE];
+        value.encode(&mut encoded);
+        assert_eq!(encoded, bytes);
+        assert_eq!(R::decode(&encoded), value);
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(DlControl::ADDRESS, 0x0100);
+        assert_eq!(AlStatusCode::ADDRESS, 0x0134);
+        assert_eq!(ErrorCounters::ADDRESS, 0x0300);
+        assert_eq!(Fmmu::<2>::ADDRESS, 0x0620);
+        assert_eq!(SyncManager::<3>::ADDRESS, 0x0818);
+        assert_eq!(DcSystemTime::ADDRESS, 0x0910);
+        assert_eq!(DcSystemTime::SIZE, 8);
+    }
+
+    #[test]
+    fn esc_information() {
+        let info = EscInformation::decode(&[0x11, 0x00, 0x02, 0x00, 8, 8, 8, 0x0F, 0xCC, 0x01]);
+        assert_eq!(info.esc_type, 0x11);
+        assert_eq!(info.build, 2);
+        assert_eq!(
+            info.ports,
+            [
+                PortType::Mii,
+                PortType::Mii,
+                PortType::NotImplemented,
+                PortType::NotImplemented
+            ]
+        );
+        assert!(info.has_dc());
+        assert!(info.has_dc64());
+    }
+
+    #[test]
+    fn dl_control() {
+        let bytes = [0x01, 0b1110_0100, 0x03, 0x01];
+        let control = DlControl::decode(&bytes);
+        assert!(control.forwarding_rule);
+        assert!(!control.temporary);
+        assert_eq!(
+            control.loops,
+            [
+                LoopControl::Auto,
+                LoopControl::AutoClose,
+                LoopControl::AlwaysOpen,
+                LoopControl::AlwaysClosed
+            ]
+        );
+        assert_eq!(control.rx_fifo_size, 3);
+        assert!(!control.ebus_low_jitter);
+        assert!(control.alias_enable);
+        round_trip::<DlControl>(&bytes);
+    }
+
+    #[test]
+    fn dl_status() {
+        // PDI operational, link and communication on port 0, loop closed on port 1
+        let status = DlStatus::decode(&0x0611u16.to_le_bytes());
+        assert!(status.pdi_operational);
+        assert!(!status.pdi_watchdog_ok);
+        assert!(status.ports[0].link);
+        assert!(status.ports[0].communication);
+        assert!(!status.ports[0].loop_closed);
+        assert!(status.ports[1].loop_closed);
+        assert!(!status.ports[1].link);
+    }
+
+    #[test]
+    fn al_registers() {
+        let control = AlControl {
+            state: ec_state_EC_STATE_SAFE_OP as u8,
+            acknowledge: true,
+            request_id: false,
+        };
+        let mut bytes = [0xFF; 2];
+        control.encode(&mut bytes);
+        assert_eq!(bytes, [0x14, 0x00]);
+        assert_eq!(AlControl::decode(&bytes), control);
+
+        let status = AlStatus::decode(&[0x12, 0x00]);
+        assert_eq!(status.state, ec_state_EC_STATE_PRE_OP as u8);
+        assert!(status.error);
+        assert_eq!(
+            AlStatusCode::decode(&[0x1B, 0x00]),
+            AlStatusCode::SyncManagerWatchdog
+        );
+    }
+
+    #[test]
+    fn error_counters() {
+        let mut bytes = [0u8; 0x14];
+        bytes[0x02] = 3; // invalid frames port 1
+        bytes[0x03] = 4; // rx errors port 1
+        bytes[0x09] = 5; // forwarded rx errors port 1
+        bytes[0x0C] = 6;
+        bytes[0x0D] = 7;
+        bytes[0x0E] = 8;
+        bytes[0x13] = 9; // lost link port 3
+        let counters = ErrorCounters::decode(&bytes);
+        assert_eq!(
+            counters.ports[1],
+            PortErrorCounters {
+                invalid_frame: 3,
+                rx_error: 4,
+                forwarded_rx_error: 5,
+                lost_link: 0,
+            }
+        );
+        assert_eq!(counters.ports[3].lost_link, 9);
+        assert_eq!(
+            (
+                counters.processing_unit,
+                counters.pdi,
+                counters.pdi_error_code
+            ),
+            (6, 7, 8)
+        );
+        round_trip::<ErrorCounters>(&bytes);
+    }
+
+    #[test]
+    fn fmmu_and_sync_manager() {
+        let bytes = [
+            0x00, 0x10, 0x00, 0x00, 0x04, 0x00, 0x00, 0x07, 0x00, 0x11, 0x00, 0x02, 0x01, 0, 0, 0,
+        ];
+        let fmmu = Fmmu::<0>::decode(&bytes);
+        assert_eq!(fmmu.logical_start, 0x1000);
+        assert_eq!(fmmu.length, 4);
+        assert_eq!(fmmu.logical_end_bit, 7);
+        assert_eq!(fmmu.physical_start, 0x1100);
+        assert_eq!(fmmu.fmmu_type, 2);
+        assert!(fmmu.active);
+        round_trip::<Fmmu<0>>(&bytes);
+
+        let bytes = [0x00, 0x18, 0x80, 0x00, 0x26, 0x08, 0x03, 0x00];
+        let sm = SyncManager::<0>::decode(&bytes);
+        assert_eq!(sm.start_address, 0x1800);
+        assert_eq!(sm.length, 0x80);
+        assert!(sm.mailbox_full());
+        assert!(!sm.watchdog_enabled());
+        assert!(sm.enable);
+        assert!(sm.repeat);
+        round_trip::<SyncManager<0>>(&bytes);
+    }
+
+    #[test]
+    fn dc_registers() {
+        assert_eq!(DcSystemTimeDifference::decode(&100u32.to_le_bytes()).0, 100);
+        assert_eq!(
+            DcSystemTimeDifference::decode(&0x8000_0064u32.to_le_bytes()).0,
+            -100
+        );
+        round_trip::<DcSystemTime>(&0x0102_0304_0506_0708u64.to_le_bytes());
+        round_trip::<DcActivation>(&[0x03]);
+        round_trip::<DcLatchControl>(&[0x01, 0x02]);
+
+        let mut bytes = [0u8; 32];
+        bytes[16..24].copy_from_slice(&42u64.to_le_bytes());
+        let times = DcLatchTimes::decode(&bytes);
+        assert_eq!(times.latch[1].positive, 42);
+        assert_eq!(times.latch[0].positive, 0);
+    }
+}
@@ -0,0 +1,30 @@
+use std::{env, ffi::CString};
+
+use soem_rust::bindings::*;
+use soem_rust::simple_ng::*;
+
+fn main() {
+    let ifname = env::args().nth(1).expect("Usage: program <interface>");
+    let ifname_c = CString::new(ifname).unwrap();
+    let mut fieldbus = Fieldbus::new(ifname_c);
+
+    if fieldbus.start() {
+        for i in 1..=100000 {
+            println!("Iteration {}", i);
+            if !fieldbus.dump() {
+                // check state could be implemented
+            }
+
+            // 0x001C is the start of the 750-501 process image
+            let byte_offset = 0x001C;
+
+            if i % 100 == 0 {
+                set_output(&mut fieldbus,1,byte_offset,1,true);
+            }else{
+                set_output(&mut fieldbus,1,byte_offset,1,false);
+            }
+            unsafe { osal_usleep(5000) };
+        }
+        fieldbus.stop();
+    }
+}
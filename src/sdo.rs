@@ -0,0 +1,588 @@
+//! SDO transfers that never block: a request is advanced by one mailbox step each
+//! time it is polled, so it can be driven from the cyclic loop.
+
+use std::{
+    fmt,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use crate::bindings::*;
+use crate::mailbox::{MBX_HEADER, Step};
+use crate::simple_ng::Fieldbus;
+use crate::status::SdoAbortCode;
+
+/// Mailbox header (6 bytes) and CoE header (2 bytes).
+const COE_HEADER: usize = MBX_HEADER + 2;
+/// Offset of the SDO data after command, index and subindex.
+const SDO_DATA: usize = COE_HEADER + 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SdoError {
+    /// The slave aborted the transfer.
+    Abort(SdoAbortCode),
+    /// The slave answered with a mailbox error, carrying its detail code.
+    Mailbox(u16),
+    /// No complete answer within the request timeout.
+    Timeout,
+    /// The slave's answer does not follow the SDO protocol.
+    Protocol(&'static str),
+}
+
+impl fmt::Display for SdoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SdoError::Abort(code) => code.fmt(f),
+            SdoError::Mailbox(detail) => write!(f, "mailbox error {:04x}", detail),
+            SdoError::Timeout => write!(f, "SDO timeout"),
+            SdoError::Protocol(what) => write!(f, "SDO protocol error: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SdoError {}
+
+/// An SDO upload or download. Create it with `read` or `write` and call `poll`
+/// once per cycle until it returns `Poll::Ready`. Each poll does at most one
+/// mailbox write or read.
+#[derive(Debug)]
+pub struct SdoRequest {
+    slave: u16,
+    index: u16,
+    subindex: u8,
+    complete_access: bool,
+    timeout: Duration,
+    upload: bool,
+    /// Upload: data received so far. Download: data to send.
+    data: Vec<u8>,
+    /// Download: bytes of `data` already sent. Upload: expected size.
+    offset: usize,
+    toggle: bool,
+    frame: Vec<u8>,
+    /// The timeout counts from here.
+    created: Instant,
+    step: Step<SdoError>,
+}
+
+impl SdoRequest {
+    pub fn read(slave: u16, index: u16, subindex: u8) -> Self {
+        Self::new(slave, index, subindex, true, Vec::new())
+    }
+
+    pub fn write(slave: u16, index: u16, subindex: u8, data: impl Into<Vec<u8>>) -> Self {
+        Self::new(slave, index, subindex, false, data.into())
+    }
+
+    fn new(slave: u16, index: u16, subindex: u8, upload: bool, data: Vec<u8>) -> Self {
+        SdoRequest {
+            slave,
+            index,
+            subindex,
+            complete_access: false,
+            timeout: Duration::from_micros(EC_TIMEOUTRXM as u64),
+            upload,
+            data,
+            offset: 0,
+            toggle: false,
+            frame: Vec::new(),
+            created: Instant::now(),
+            step: Step::Send,
+        }
+    }
+
+    /// Transfer the whole object starting at `subindex` (0 or 1).
+    pub fn complete_access(mut self) -> Self {
+        self.complete_access = true;
+        self
+    }
+
+    /// Time allowed for the whole transfer, counted from when the request is
+    /// created, so time spent queued in a `MailboxClient` counts too.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn slave(&self) -> u16 {
+        self.slave
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn subindex(&self) -> u8 {
+        self.subindex
+    }
+
+    /// When the request times out.
+    pub(crate) fn deadline(&self) -> Instant {
+        self.created + self.timeout
+    }
+
+    /// Advance the transfer. Returns the uploaded data, or an empty vector for a
+    /// download, once the slave has answered. Polling again after that returns the
+    /// same result.
+    pub fn poll(&mut self, bus: &mut Fieldbus) -> Poll<Result<Vec<u8>, SdoError>> {
+        if let Step::Done(result) = &self.step {
+            return Poll::Ready(result.clone());
+        }
+        if self.frame.is_empty() {
+            if self.created.elapsed() > self.timeout {
+                // expired before anything was sent
+                self.step = Step::Done(Err(SdoError::Timeout));
+                return Poll::Ready(Err(SdoError::Timeout));
+            }
+            self.frame = self.init_frame(bus);
+        }
+        let answer = bus.mailbox_step(self.slave, ECT_MBXT_COE, &self.frame, &mut self.step);
+        let result = answer.and_then(|frame| self.answer(bus, &frame));
+        let result = match result {
+            None if self.created.elapsed() > self.timeout => {
+                bus.mailbox_cancel(self.slave, &self.step);
+                Some(Err(SdoError::Timeout))
+            }
+            result => result,
+        };
+        match result {
+            Some(result) => {
+                self.step = Step::Done(result.clone());
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    fn frame(bus: &mut Fieldbus, slave: u16, length: usize) -> Vec<u8> {
+        let mut frame = bus.mailbox_frame(slave, ECT_MBXT_COE, length);
+        frame[6..8].copy_from_slice(&((ECT_COES_SDOREQ as u16) << 12).to_le_bytes());
+        frame
+    }
+
+    fn init_frame(&mut self, bus: &mut Fieldbus) -> Vec<u8> {
+        let max = bus
+            .mailbox_capacity(self.slave)
+            .saturating_sub(SDO_DATA - MBX_HEADER + 4);
+        let (command, payload) = if self.upload {
+            let command = if self.complete_access {
+                ECT_SDO_UP_REQ_CA
+            } else {
+                ECT_SDO_UP_REQ
+            };
+            (command as u8, Vec::new())
+        } else if !self.data.is_empty() && self.data.len() <= 4 && !self.complete_access {
+            let unused = 4 - self.data.len() as u8;
+            self.offset = self.data.len();
+            let mut payload = self.data.clone();
+            payload.resize(4, 0);
+            (ECT_SDO_DOWN_EXP as u8 | (unused << 2), payload)
+        } else {
+            let command = if self.complete_access {
+                ECT_SDO_DOWN_INIT_CA
+            } else {
+                ECT_SDO_DOWN_INIT
+            };
+            self.offset = self.data.len().min(max);
+            let mut payload = (self.data.len() as u32).to_le_bytes().to_vec();
+            payload.extend_from_slice(&self.data[..self.offset]);
+            (command as u8, payload)
+        };
+        let mut frame = Self::frame(bus, self.slave, 2 + 4 + payload.len().max(4));
+        frame[COE_HEADER] = command;
+        frame[COE_HEADER + 1..COE_HEADER + 3].copy_from_slice(&self.index.to_le_bytes());
+        frame[COE_HEADER + 3] = if self.complete_access {
+            self.subindex.min(1)
+        } else {
+            self.subindex
+        };
+        frame[SDO_DATA..SDO_DATA + payload.len()].copy_from_slice(&payload);
+        frame
+    }
+
+    /// Next segment of a segmented download.
+    fn download_segment(&mut self, bus: &mut Fieldbus) -> Vec<u8> {
+        let max = bus
+            .mailbox_capacity(self.slave)
+            .saturating_sub(COE_HEADER - MBX_HEADER + 1);
+        let n = (self.data.len() - self.offset).min(max);
+        let last = self.offset + n == self.data.len();
+        let mut frame = Self::frame(bus, self.slave, 2 + 1 + n.max(7));
+        let mut command = (self.toggle as u8) << 4 | last as u8;
+        if n < 7 {
+            command |= ((7 - n) as u8) << 1;
+        }
+        frame[COE_HEADER] = command;
+        frame[COE_HEADER + 1..COE_HEADER + 1 + n]
+            .copy_from_slice(&self.data[self.offset..self.offset + n]);
+        self.offset += n;
+        frame
+    }
+
+    fn upload_segment_request(&mut self, bus: &mut Fieldbus) -> Vec<u8> {
+        let mut frame = Self::frame(bus, self.slave, 2 + 8);
+        frame[COE_HEADER] = ECT_SDO_SEG_UP_REQ as u8 | (self.toggle as u8) << 4;
+        frame
+    }
+
+    /// Handle a frame read from the slave. Returns the outcome, or `None` when
+    /// the transfer continues.
+    fn answer(&mut self, bus: &mut Fieldbus, frame: &[u8]) -> Option<Result<Vec<u8>, SdoError>> {
+        let length = u16::from_le_bytes([frame[0], frame[1]]) as usize;
+        let frame = &frame[..(MBX_HEADER + length).min(frame.len())];
+        if frame.len() < COE_HEADER + 2 {
+            return None;
+        }
+        match frame[5] as u32 & 0x0f {
+            ECT_MBXT_ERR => {
+                let detail = u16::from_le_bytes([frame[8], frame[9]]);
+                return Some(Err(SdoError::Mailbox(detail)));
+            }
+            ECT_MBXT_COE => {}
+            _ => return None,
+        }
+        let service = u16::from_le_bytes([frame[6], frame[7]]) >> 12;
+        if service != ECT_COES_SDORES as u16 {
+            return None;
+        }
+        let command = frame[COE_HEADER];
+        if command == ECT_SDO_ABORT as u8 && frame.len() >= SDO_DATA + 4 {
+            let code = u32::from_le_bytes(frame[SDO_DATA..SDO_DATA + 4].try_into().unwrap());
+            return Some(Err(SdoError::Abort(code.into())));
+        }
+        let segment = self.segment_phase();
+        if self.upload {
+            self.upload_answer(bus, command, frame, segment)
+        } else {
+            self.download_answer(bus, command, frame, segment)
+        }
+    }
+
+    /// True once the init exchange is over and segments are being transferred.
+    fn segment_phase(&self) -> bool {
+        if self.upload {
+            self.offset > 0
+        } else {
+            self.frame[COE_HEADER] & 0xE0 == 0x00
+        }
+    }
+
+    fn upload_answer(
+        &mut self,
+        bus: &mut Fieldbus,
+        command: u8,
+        frame: &[u8],
+        segment: bool,
+    ) -> Option<Result<Vec<u8>, SdoError>> {
+        if segment {
+            if command & 0xE0 != 0x00 {
+                return None;
+            }
+            if (command & 0x10 != 0) != self.toggle {
+                return Some(Err(SdoError::Protocol("segment toggle mismatch")));
+            }
+            let mut n = frame.len() - (COE_HEADER + 1);
+            if n == 7 {
+                n -= ((command >> 1) & 0x07) as usize;
+            }
+            self.data
+                .extend_from_slice(&frame[COE_HEADER + 1..COE_HEADER + 1 + n]);
+        } else {
+            if command & 0xE0 != 0x40 || !self.matches(frame) {
+                return None;
+            }
+            if command & 0x02 != 0 {
+                // expedited
+                let n = if command & 0x01 != 0 {
+                    4 - ((command >> 2) & 0x03) as usize
+                } else {
+                    4
+                };
+                if frame.len() < SDO_DATA + n {
+                    return Some(Err(SdoError::Protocol("short expedited response")));
+                }
+                return Some(Ok(frame[SDO_DATA..SDO_DATA + n].to_vec()));
+            }
+            if frame.len() < SDO_DATA + 4 {
+                return Some(Err(SdoError::Protocol("short upload response")));
+            }
+            self.offset =
+                u32::from_le_bytes(frame[SDO_DATA..SDO_DATA + 4].try_into().unwrap()) as usize;
+            self.data.extend_from_slice(
+                &frame[SDO_DATA + 4..frame.len().min(SDO_DATA + 4 + self.offset)],
+            );
+            if self.offset == 0 {
+                return Some(Ok(Vec::new()));
+            }
+        }
+        if self.data.len() >= self.offset || (segment && command & 0x01 != 0) {
+            self.data.truncate(self.offset);
+            return Some(Ok(std::mem::take(&mut self.data)));
+        }
+        if segment {
+            self.toggle = !self.toggle;
+        }
+        self.frame = self.upload_segment_request(bus);
+        self.step = Step::Send;
+        None
+    }
+
+    fn download_answer(
+        &mut self,
+        bus: &mut Fieldbus,
+        command: u8,
+        frame: &[u8],
+        segment: bool,
+    ) -> Option<Result<Vec<u8>, SdoError>> {
+        if segment {
+            if command & 0xE0 != 0x20 {
+                return None;
+            }
+            if (command & 0x10 != 0) != self.toggle {
+                return Some(Err(SdoError::Protocol("segment toggle mismatch")));
+            }
+            self.toggle = !self.toggle;
+        } else if command != 0x60 || !self.matches(frame) {
+            return None;
+        }
+        if self.offset >= self.data.len() {
+            return Some(Ok(Vec::new()));
+        }
+        self.frame = self.download_segment(bus);
+        self.step = Step::Send;
+        None
+    }
+
+    /// Whether an init response is for this object. With complete access the
+    /// slave may answer with either subindex 0 or 1.
+    fn matches(&self, frame: &[u8]) -> bool {
+        frame.len() >= SDO_DATA
+            && u16::from_le_bytes([frame[COE_HEADER + 1], frame[COE_HEADER + 2]]) == self.index
+            && (self.complete_access || frame[COE_HEADER + 3] == self.subindex)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    fn bus(mailbox: u16) -> Fieldbus {
+        let mut bus = Fieldbus::new(CString::new("test").unwrap());
+        bus.context.slavecount = 1;
+        bus.context.slavelist[1].mbx_l = mailbox;
+        bus
+    }
+
+    fn start(bus: &mut Fieldbus, mut request: SdoRequest) -> SdoRequest {
+        request.frame = request.init_frame(bus);
+        request
+    }
+
+    fn response(command: u8, index: u16, subindex: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; SDO_DATA];
+        frame[5] = ECT_MBXT_COE as u8;
+        frame[6..8].copy_from_slice(&((ECT_COES_SDORES as u16) << 12).to_le_bytes());
+        frame[COE_HEADER] = command;
+        frame[COE_HEADER + 1..COE_HEADER + 3].copy_from_slice(&index.to_le_bytes());
+        frame[COE_HEADER + 3] = subindex;
+        frame.extend_from_slice(data);
+        let length = (frame.len() - MBX_HEADER) as u16;
+        frame[0..2].copy_from_slice(&length.to_le_bytes());
+        frame
+    }
+
+    /// A segment response: command byte and up to 7 data bytes, padded to 7.
+    fn segment(command: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = response(command, 0, 0, &[]);
+        frame.truncate(COE_HEADER + 1);
+        frame.extend_from_slice(data);
+        frame.resize(COE_HEADER + 8, 0);
+        let length = (frame.len() - MBX_HEADER) as u16;
+        frame[0..2].copy_from_slice(&length.to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn expedited_upload() {
+        let mut bus = bus(128);
+        let mut request = start(&mut bus, SdoRequest::read(1, 0x1018, 1));
+        assert_eq!(request.frame[COE_HEADER], ECT_SDO_UP_REQ as u8);
+        let frame = response(0x43, 0x1018, 1, &[2, 0, 0, 0]);
+        assert_eq!(request.answer(&mut bus, &frame), Some(Ok(vec![2, 0, 0, 0])));
+
+        let mut request = start(&mut bus, SdoRequest::read(1, 0x6000, 1));
+        let frame = response(0x4F, 0x6000, 1, &[7, 0, 0, 0]);
+        assert_eq!(request.answer(&mut bus, &frame), Some(Ok(vec![7])));
+
+        let mut request = start(&mut bus, SdoRequest::read(1, 0x1018, 1));
+        let frame = response(0x43, 0x1018, 1, &[2, 0]);
+        assert_eq!(
+            request.answer(&mut bus, &frame),
+            Some(Err(SdoError::Protocol("short expedited response")))
+        );
+    }
+
+    #[test]
+    fn stale_responses_are_ignored() {
+        let mut bus = bus(128);
+        let mut request = start(&mut bus, SdoRequest::read(1, 0x1018, 2));
+        let frame = response(0x43, 0x1018, 1, &[2, 0, 0, 0]);
+        assert_eq!(request.answer(&mut bus, &frame), None);
+        let frame = response(0x43, 0x1008, 2, &[2, 0, 0, 0]);
+        assert_eq!(request.answer(&mut bus, &frame), None);
+        let frame = response(0x43, 0x1018, 2, &[3, 0, 0, 0]);
+        assert_eq!(request.answer(&mut bus, &frame), Some(Ok(vec![3, 0, 0, 0])));
+
+        let mut request = start(&mut bus, SdoRequest::read(1, 0x1C12, 1).complete_access());
+        assert_eq!(request.frame[COE_HEADER], ECT_SDO_UP_REQ_CA as u8);
+        let frame = response(0x41, 0x1C12, 0, &[3, 0, 0, 0, 1, 0x00, 0x16]);
+        assert_eq!(
+            request.answer(&mut bus, &frame),
+            Some(Ok(vec![1, 0x00, 0x16]))
+        );
+    }
+
+    #[test]
+    fn normal_upload() {
+        let mut bus = bus(128);
+        let mut request = start(&mut bus, SdoRequest::read(1, 0x1008, 0));
+        let frame = response(
+            0x41,
+            0x1008,
+            0,
+            &[6, 0, 0, 0, b'E', b'L', b'1', b'0', b'0', b'4'],
+        );
+        assert_eq!(
+            request.answer(&mut bus, &frame),
+            Some(Ok(b"EL1004".to_vec()))
+        );
+    }
+
+    #[test]
+    fn segmented_upload() {
+        let mut bus = bus(128);
+        let mut request = start(&mut bus, SdoRequest::read(1, 0x1008, 0));
+        let frame = response(0x41, 0x1008, 0, &[10, 0, 0, 0]);
+        assert_eq!(request.answer(&mut bus, &frame), None);
+        assert_eq!(request.frame[COE_HEADER], ECT_SDO_SEG_UP_REQ as u8);
+        assert!(matches!(request.step, Step::Send));
+
+        assert_eq!(request.answer(&mut bus, &segment(0x00, b"0123456")), None);
+        assert_eq!(request.frame[COE_HEADER], ECT_SDO_SEG_UP_REQ as u8 | 0x10);
+        // toggle 1, last, 4 of 7 bytes unused
+        let frame = segment(0x10 | 4 << 1 | 0x01, b"789");
+        assert_eq!(
+            request.answer(&mut bus, &frame),
+            Some(Ok(b"0123456789".to_vec()))
+        );
+    }
+
+    #[test]
+    fn upload_toggle_mismatch() {
+        let mut bus = bus(128);
+        let mut request = start(&mut bus, SdoRequest::read(1, 0x1008, 0));
+        let frame = response(0x41, 0x1008, 0, &[20, 0, 0, 0]);
+        assert_eq!(request.answer(&mut bus, &frame), None);
+        assert_eq!(
+            request.answer(&mut bus, &segment(0x10, b"0123456")),
+            Some(Err(SdoError::Protocol("segment toggle mismatch")))
+        );
+    }
+
+    #[test]
+    fn expedited_download() {
+        let mut bus = bus(128);
+        let mut request = start(&mut bus, SdoRequest::write(1, 0x6040, 0, [0x0F, 0x00]));
+        assert_eq!(request.frame[COE_HEADER], ECT_SDO_DOWN_EXP as u8 | 2 << 2);
+        assert_eq!(request.frame[SDO_DATA..SDO_DATA + 4], [0x0F, 0x00, 0, 0]);
+        assert_eq!(
+            request.answer(&mut bus, &response(0x60, 0x6040, 1, &[0; 4])),
+            None
+        );
+        assert_eq!(
+            request.answer(&mut bus, &response(0x60, 0x6040, 0, &[0; 4])),
+            Some(Ok(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn normal_download() {
+        let mut bus = bus(128);
+        let mut request = start(&mut bus, SdoRequest::write(1, 0x2000, 1, [1, 2, 3, 4, 5]));
+        assert_eq!(request.frame[COE_HEADER], ECT_SDO_DOWN_INIT as u8);
+        assert_eq!(
+            request.frame[SDO_DATA..SDO_DATA + 9],
+            [5, 0, 0, 0, 1, 2, 3, 4, 5]
+        );
+        assert_eq!(
+            request.answer(&mut bus, &response(0x60, 0x2000, 1, &[0; 4])),
+            Some(Ok(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn segmented_download() {
+        // 26 bytes after the mailbox header: 16 data bytes in the init frame, 23
+        // in a segment
+        let mut bus = bus(32);
+        let data: Vec<u8> = (0..40).collect();
+        let mut request = start(&mut bus, SdoRequest::write(1, 0x2000, 0, data.clone()));
+        assert_eq!(request.frame[SDO_DATA..SDO_DATA + 4], [40, 0, 0, 0]);
+        assert_eq!(request.frame[SDO_DATA + 4..], data[..16]);
+
+        assert_eq!(
+            request.answer(&mut bus, &response(0x60, 0x2000, 0, &[0; 4])),
+            None
+        );
+        assert_eq!(request.frame[COE_HEADER], 0x00);
+        assert_eq!(request.frame[COE_HEADER + 1..], data[16..39]);
+
+        assert_eq!(request.answer(&mut bus, &segment(0x20, &[])), None);
+        // toggle 1, last, 6 of 7 bytes unused
+        assert_eq!(request.frame[COE_HEADER], 0x10 | 6 << 1 | 0x01);
+        assert_eq!(request.frame[COE_HEADER + 1], 39);
+
+        assert_eq!(
+            request.answer(&mut bus, &segment(0x20, &[])),
+            Some(Err(SdoError::Protocol("segment toggle mismatch")))
+        );
+    }
+
+    #[test]
+    fn segmented_download_completes() {
+        let mut bus = bus(32);
+        let data: Vec<u8> = (0..20).collect();
+        let mut request = start(&mut bus, SdoRequest::write(1, 0x2000, 0, data));
+        let frame = response(0x60, 0x2000, 0, &[0; 4]);
+        assert_eq!(request.answer(&mut bus, &frame), None);
+        assert_eq!(
+            request.answer(&mut bus, &segment(0x20, &[])),
+            Some(Ok(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn abort_and_mailbox_error() {
+        let mut bus = bus(128);
+        let mut request = start(&mut bus, SdoRequest::read(1, 0x1018, 1));
+        let frame = response(
+            ECT_SDO_ABORT as u8,
+            0x1018,
+            1,
+            &0x0602_0000u32.to_le_bytes(),
+        );
+        assert_eq!(
+            request.answer(&mut bus, &frame),
+            Some(Err(SdoError::Abort(0x0602_0000.into())))
+        );
+
+        let mut frame = response(0, 0, 0, &[]);
+        frame[5] = ECT_MBXT_ERR as u8;
+        frame[8..10].copy_from_slice(&0x0004u16.to_le_bytes());
+        assert_eq!(
+            request.answer(&mut bus, &frame),
+            Some(Err(SdoError::Mailbox(0x0004)))
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! Staged shutdown. The outputs are cleared and set to their safe values, which
+//! are sent for a few cycles before the slaves are taken down OP->SAFE_OP->PRE_OP
+//! ->INIT, so actuators stop in a defined state rather than whatever the last
+//! cycle left. `stop` and dropping a started `Fieldbus` both shut down this way.
+//!
+//! `install_shutdown_handlers` turns SIGINT, SIGTERM and panics into a shutdown
+//! request that ends `run_cyclic`; the `Fieldbus` is then shut down when dropped.
+//! Only the first signal is caught, a second one terminates the process as usual.
+//! Loops of their own should check `shutdown_requested`. Nothing runs when the
+//! process is killed or built with `panic = "abort"`, which the process data
+//! watchdogs (`WatchdogConfig`) are there for.
+
+use std::{
+    os::raw::c_int,
+    panic,
+    sync::{
+        Once,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::bindings::*;
+use crate::config::HOT_CONNECT_GROUP;
+use crate::eni::EniVariable;
+use crate::simple_ng::Fieldbus;
+
+/// Cycles sent with the safe outputs before leaving OP.
+const SAFE_OUTPUT_CYCLES: u32 = 3;
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static INSTALL: Once = Once::new();
+
+extern "C" fn on_signal(_: c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Request a shutdown on SIGINT, SIGTERM or a panic in any thread. The previous
+/// panic hook still runs. The signal handlers are reset to the default when they
+/// run, so a second Ctrl-C kills a process stuck in `start` or a loop of its own.
+pub fn install_shutdown_handlers() {
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            SHUTDOWN.store(true, Ordering::SeqCst);
+            previous(info);
+        }));
+        let handler: extern "C" fn(c_int) = on_signal;
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            for signum in [libc::SIGINT, libc::SIGTERM] {
+                libc::sigaction(signum, &action, std::ptr::null_mut());
+            }
+        }
+    });
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Request a shutdown from the application, the same as a signal.
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Value put into the outputs on shutdown, at a bit offset in the IOmap.
+pub(crate) struct SafeValue {
+    bit_offset: u32,
+    bit_size: u32,
+    value: u64,
+}
+
+impl SafeValue {
+    fn write(&self, map: &mut [u8]) {
+        for bit in 0..self.bit_size.min(64) {
+            let Some(at) = self.bit_offset.checked_add(bit) else {
+                return;
+            };
+            let at = at as usize;
+            let Some(byte) = map.get_mut(at / 8) else {
+                return;
+            };
+            let mask = 1 << (at % 8);
+            if self.value >> bit & 1 != 0 {
+                *byte |= mask;
+            } else {
+                *byte &= !mask;
+            }
+        }
+    }
+}
+
+impl Fieldbus {
+    /// Set `bit_size` bits at `bit_offset` in the outputs of `slave` to `value` on
+    /// shutdown instead of 0. Call after `start`. Returns false if that is outside
+    /// the slave's outputs.
+    pub fn set_safe_output(
+        &mut self,
+        slave: u16,
+        bit_offset: u32,
+        bit_size: u32,
+        value: u64,
+    ) -> bool {
+        let s = &self.context.slavelist[slave as usize];
+        let end = bit_offset.checked_add(bit_size);
+        if s.outputs.is_null() || end.is_none_or(|end| end > s.Obits as u32) {
+            return false;
+        }
+        let start =
+            (s.outputs as usize - self.map.as_ptr() as usize) as u32 * 8 + s.Ostartbit as u32;
+        self.set_safe_value(start + bit_offset, bit_size, value);
+        true
+    }
+
+    /// Set an output variable to `value` on shutdown instead of 0, by its name in
+    /// the attached ENI or else in `export_eni`. Call after `start`. Returns false
+    /// if there is no such output.
+    pub fn set_safe_variable(&mut self, name: &str, value: u64) -> bool {
+        let find = |outputs: &[EniVariable]| {
+            outputs
+                .iter()
+                .find(|v| v.name == name)
+                .map(|v| (v.bit_offset, v.bit_size))
+        };
+        let variable = match &self.eni {
+            Some(table) => find(&table.eni().outputs),
+            None => find(&self.export_eni().outputs),
+        };
+        let Some((bit_offset, bit_size)) = variable else {
+            return false;
+        };
+        self.set_safe_value(bit_offset, bit_size, value);
+        true
+    }
+
+    fn set_safe_value(&mut self, bit_offset: u32, bit_size: u32, value: u64) {
+        self.safe_values
+            .retain(|v| (v.bit_offset, v.bit_size) != (bit_offset, bit_size));
+        self.safe_values.push(SafeValue {
+            bit_offset,
+            bit_size,
+            value,
+        });
+    }
+
+    /// Clear the outputs and apply the safe values.
+    fn write_safe_outputs(&mut self) {
+        for group in [self.group, HOT_CONNECT_GROUP] {
+            let group = &self.context.grouplist[group as usize];
+            if !group.outputs.is_null() {
+                unsafe { std::ptr::write_bytes(group.outputs, 0, group.Obytes as usize) };
+            }
+        }
+        for value in &self.safe_values {
+            value.write(&mut self.map[..]);
+        }
+    }
+
+    /// Send the safe outputs, then take the slaves down state by state, waiting at
+    /// most `timeout` for each, and close the socket. Returns whether all slaves
+    /// reached INIT.
+    pub fn shutdown(&mut self, timeout: Duration) -> bool {
+        if !self.running {
+            return true;
+        }
+        // queued mailbox requests complete as closed
+        self.mailbox_queue = None;
+        self.mailbox_inbox.clear();
+        self.disable_cyclic_mailbox();
+        let timeout = timeout.as_micros().min(i32::MAX as u128) as i32;
+
+        log::info!("Writing safe outputs");
+        self.write_safe_outputs();
+        for _ in 0..SAFE_OUTPUT_CYCLES {
+            self.roundtrip();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut ok = true;
+        for (transition, state, name) in [
+            (
+                ECT_ESMTRANS_OS,
+                ec_state_EC_STATE_SAFE_OP,
+                "safe operational",
+            ),
+            (ECT_ESMTRANS_SP, ec_state_EC_STATE_PRE_OP, "pre operational"),
+        ] {
+            ok &= self.eni_initcmds(transition);
+            self.request_state(state as u16);
+            // keep the slaves that are still in OP supplied until they have left it
+            self.roundtrip();
+            if !self.wait_state(state as u16, timeout) {
+                log::warn!("Not all slaves reached {} state", name);
+                ok = false;
+            }
+        }
+
+        ok &= self.eni_initcmds(ECT_ESMTRANS_PI);
+        log::info!("Requesting init state on all slaves");
+        let context = &mut *self.context as *mut ecx_contextt;
+        self.context.slavelist[0].state = ec_state_EC_STATE_INIT as u16;
+        unsafe {
+            ecx_writestate(context, 0);
+            ecx_statecheck(context, 0, ec_state_EC_STATE_INIT as u16, timeout);
+        }
+        if self.context.slavelist[0].state != ec_state_EC_STATE_INIT as u16 {
+            log::warn!("Not all slaves reached init state");
+            ok = false;
+        }
+
+        log::info!("Closing socket");
+        unsafe { ecx_close(context) };
+        self.running = false;
+        ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_value() {
+        let mut map = [0xFFu8; 3];
+        SafeValue {
+            bit_offset: 4,
+            bit_size: 12,
+            value: 0x5A5,
+        }
+        .write(&mut map);
+        assert_eq!(map, [0x5F, 0x5A, 0xFF]);
+
+        // bits beyond the map or the bit range are left alone
+        SafeValue {
+            bit_offset: u32::MAX - 1,
+            bit_size: 8,
+            value: 0,
+        }
+        .write(&mut map);
+        SafeValue {
+            bit_offset: 20,
+            bit_size: 8,
+            value: 0,
+        }
+        .write(&mut map);
+        assert_eq!(map, [0x5F, 0x5A, 0x0F]);
+    }
+}
@@ -0,0 +1,965 @@
+//! Offline parsing and building of Slave Information Interface (SII) EEPROM images.
+//!
+//! This mirrors what `ecx_siifind`, `ecx_siistring`, `ecx_siiSM` and `ecx_siiPDO` read
+//! from a live slave, but works on a binary dump so images can be inspected and edited
+//! without a bus.
+
+use std::fmt;
+
+/// Word address where the category area starts.
+pub const SII_CATEGORY_START: usize = 0x0040;
+/// Word address of the station alias in the config area.
+pub const SII_ALIAS_WORD: u16 = 0x0004;
+/// Word address of the config area checksum.
+pub const SII_CHECKSUM_WORD: u16 = 0x0007;
+
+pub const CATEGORY_NOP: u16 = 0;
+pub const CATEGORY_STRINGS: u16 = 10;
+pub const CATEGORY_DATA_TYPES: u16 = 20;
+pub const CATEGORY_GENERAL: u16 = 30;
+pub const CATEGORY_FMMU: u16 = 40;
+pub const CATEGORY_SYNC_MANAGER: u16 = 41;
+pub const CATEGORY_TXPDO: u16 = 50;
+pub const CATEGORY_RXPDO: u16 = 51;
+pub const CATEGORY_DC: u16 = 60;
+pub const CATEGORY_END: u16 = 0xFFFF;
+
+const HEADER_BYTES: usize = SII_CATEGORY_START * 2;
+const GENERAL_BYTES: usize = 32;
+const SM_ENTRY_BYTES: usize = 8;
+const PDO_HEADER_BYTES: usize = 8;
+const PDO_ENTRY_BYTES: usize = 8;
+const DC_ENTRY_BYTES: usize = 24;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SiiError {
+    /// The image ends before the given byte offset could be read.
+    Truncated { offset: usize },
+    /// The config area checksum stored in word 7 does not match its contents.
+    Checksum { stored: u8, computed: u8 },
+    /// A category header claims more data than the image holds.
+    CategoryOverrun { category: u16, offset: usize },
+    /// A category payload is inconsistent with its declared layout.
+    MalformedCategory { category: u16 },
+    /// A string can not be stored in the strings category.
+    InvalidString(String),
+}
+
+impl fmt::Display for SiiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SiiError::Truncated { offset } => {
+                write!(f, "SII image truncated at byte {:#06x}", offset)
+            }
+            SiiError::Checksum { stored, computed } => write!(
+                f,
+                "SII config area checksum mismatch: stored {:#04x}, computed {:#04x}",
+                stored, computed
+            ),
+            SiiError::CategoryOverrun { category, offset } => write!(
+                f,
+                "SII category {} at byte {:#06x} runs past the end of the image",
+                category, offset
+            ),
+            SiiError::MalformedCategory { category } => {
+                write!(f, "SII category {} is malformed", category)
+            }
+            SiiError::InvalidString(s) => write!(f, "string {:?} can not be stored in SII", s),
+        }
+    }
+}
+
+impl std::error::Error for SiiError {}
+
+/// CRC-8 (polynomial 0x07, initial value 0xFF) used for the SII config area.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xFF;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Checksum over the first seven config words, as stored in the low byte of word 7.
+pub fn config_checksum(words: &[u16; 7]) -> u8 {
+    let mut bytes = [0u8; 14];
+    for (i, w) in words.iter().enumerate() {
+        bytes[i * 2..i * 2 + 2].copy_from_slice(&w.to_le_bytes());
+    }
+    crc8(&bytes)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, SiiError> {
+    match data.get(offset..offset + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => Err(SiiError::Truncated { offset }),
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, SiiError> {
+    match data.get(offset..offset + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(SiiError::Truncated { offset }),
+    }
+}
+
+/// ESC configuration words 0..6 of the SII.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigArea {
+    pub pdi_control: u16,
+    pub pdi_config: u16,
+    pub sync_impulse_len: u16,
+    pub pdi_config2: u16,
+    pub station_alias: u16,
+    pub reserved: [u16; 2],
+}
+
+impl ConfigArea {
+    fn words(&self) -> [u16; 7] {
+        [
+            self.pdi_control,
+            self.pdi_config,
+            self.sync_impulse_len,
+            self.pdi_config2,
+            self.station_alias,
+            self.reserved[0],
+            self.reserved[1],
+        ]
+    }
+
+    /// Checksum that belongs in word 7 for the current contents.
+    pub fn checksum(&self) -> u8 {
+        config_checksum(&self.words())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub vendor_id: u32,
+    pub product_code: u32,
+    pub revision: u32,
+    pub serial: u32,
+}
+
+/// Offset and size of a mailbox SyncManager in ESC memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MailboxArea {
+    pub offset: u16,
+    pub size: u16,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MailboxSettings {
+    pub bootstrap_rx: MailboxArea,
+    pub bootstrap_tx: MailboxArea,
+    pub standard_rx: MailboxArea,
+    pub standard_tx: MailboxArea,
+    /// Bitmask of supported protocols, see `ECT_MBXPROT_*`.
+    pub protocols: u16,
+}
+
+/// General category (30).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct General {
+    pub group_idx: u8,
+    pub image_idx: u8,
+    pub order_idx: u8,
+    pub name_idx: u8,
+    pub coe_details: u8,
+    pub foe_details: u8,
+    pub eoe_details: u8,
+    pub soe_channels: u8,
+    pub ds402_channels: u8,
+    pub sysman_class: u8,
+    pub flags: u8,
+    pub current_on_ebus: i16,
+    pub physical_port: u16,
+    pub physical_memory_address: u16,
+    /// Bytes 4, 14 and 15 plus everything after byte 19, kept verbatim.
+    pub reserved: Vec<u8>,
+}
+
+impl Default for General {
+    fn default() -> Self {
+        General::parse(&[])
+    }
+}
+
+impl General {
+    fn parse(data: &[u8]) -> General {
+        let b = |i: usize| data.get(i).copied().unwrap_or(0);
+        let mut reserved = vec![b(4), b(14), b(15)];
+        let tail = data.get(20..).unwrap_or(&[]);
+        reserved.extend_from_slice(tail);
+        if reserved.len() < GENERAL_BYTES - 17 {
+            reserved.resize(GENERAL_BYTES - 17, 0);
+        }
+        General {
+            group_idx: b(0),
+            image_idx: b(1),
+            order_idx: b(2),
+            name_idx: b(3),
+            coe_details: b(5),
+            foe_details: b(6),
+            eoe_details: b(7),
+            soe_channels: b(8),
+            ds402_channels: b(9),
+            sysman_class: b(10),
+            flags: b(11),
+            current_on_ebus: i16::from_le_bytes([b(12), b(13)]),
+            physical_port: u16::from_le_bytes([b(16), b(17)]),
+            physical_memory_address: u16::from_le_bytes([b(18), b(19)]),
+            reserved,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        let r = |i: usize| self.reserved.get(i).copied().unwrap_or(0);
+        out.extend_from_slice(&[
+            self.group_idx,
+            self.image_idx,
+            self.order_idx,
+            self.name_idx,
+            r(0),
+            self.coe_details,
+            self.foe_details,
+            self.eoe_details,
+            self.soe_channels,
+            self.ds402_channels,
+            self.sysman_class,
+            self.flags,
+        ]);
+        out.extend_from_slice(&self.current_on_ebus.to_le_bytes());
+        out.extend_from_slice(&[r(1), r(2)]);
+        out.extend_from_slice(&self.physical_port.to_le_bytes());
+        out.extend_from_slice(&self.physical_memory_address.to_le_bytes());
+        out.extend_from_slice(self.reserved.get(3..).unwrap_or(&[]));
+        if out.len() < start + GENERAL_BYTES {
+            out.resize(start + GENERAL_BYTES, 0);
+        }
+    }
+}
+
+/// FMMU usage as declared in category 40.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FmmuUsage {
+    Unused,
+    Outputs,
+    Inputs,
+    SyncManagerStatus,
+    Other(u8),
+}
+
+impl From<u8> for FmmuUsage {
+    fn from(v: u8) -> Self {
+        match v {
+            0x00 | 0xFF => FmmuUsage::Unused,
+            0x01 => FmmuUsage::Outputs,
+            0x02 => FmmuUsage::Inputs,
+            0x03 => FmmuUsage::SyncManagerStatus,
+            v => FmmuUsage::Other(v),
+        }
+    }
+}
+
+impl From<FmmuUsage> for u8 {
+    fn from(v: FmmuUsage) -> u8 {
+        match v {
+            FmmuUsage::Unused => 0x00,
+            FmmuUsage::Outputs => 0x01,
+            FmmuUsage::Inputs => 0x02,
+            FmmuUsage::SyncManagerStatus => 0x03,
+            FmmuUsage::Other(v) => v,
+        }
+    }
+}
+
+/// One entry of the SyncManager category (41).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncManager {
+    pub start_address: u16,
+    pub length: u16,
+    pub control: u8,
+    pub status: u8,
+    pub enable: u8,
+    /// 0 = unused, 1 = MbxOut, 2 = MbxIn, 3 = Outputs, 4 = Inputs.
+    pub sm_type: u8,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PdoEntry {
+    pub index: u16,
+    pub subindex: u8,
+    pub name_idx: u8,
+    pub data_type: u8,
+    pub bit_length: u8,
+    pub flags: u16,
+}
+
+/// A PDO from the TxPDO (50) or RxPDO (51) category.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pdo {
+    pub index: u16,
+    pub sync_manager: u8,
+    pub dc_sync: u8,
+    pub name_idx: u8,
+    pub flags: u16,
+    pub entries: Vec<PdoEntry>,
+}
+
+impl Pdo {
+    pub fn bit_length(&self) -> u32 {
+        self.entries.iter().map(|e| e.bit_length as u32).sum()
+    }
+}
+
+/// One entry of the distributed clock category (60).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DcSync {
+    pub cycle_time0: u32,
+    pub shift_time0: u32,
+    pub shift_time1: u32,
+    pub sync1_cycle_factor: i16,
+    pub assign_activate: u16,
+    pub sync0_cycle_factor: i16,
+    pub name_idx: u8,
+    pub desc_idx: u8,
+    pub reserved: [u8; 4],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Category {
+    Strings(Vec<String>),
+    General(General),
+    Fmmu(Vec<FmmuUsage>),
+    SyncManagers(Vec<SyncManager>),
+    TxPdo(Vec<Pdo>),
+    RxPdo(Vec<Pdo>),
+    Dc(Vec<DcSync>),
+    /// Any category this module does not decode, kept as raw words.
+    Other {
+        kind: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl Category {
+    pub fn kind(&self) -> u16 {
+        match self {
+            Category::Strings(_) => CATEGORY_STRINGS,
+            Category::General(_) => CATEGORY_GENERAL,
+            Category::Fmmu(_) => CATEGORY_FMMU,
+            Category::SyncManagers(_) => CATEGORY_SYNC_MANAGER,
+            Category::TxPdo(_) => CATEGORY_TXPDO,
+            Category::RxPdo(_) => CATEGORY_RXPDO,
+            Category::Dc(_) => CATEGORY_DC,
+            Category::Other { kind, .. } => *kind,
+        }
+    }
+
+    fn parse(kind: u16, data: &[u8]) -> Result<Category, SiiError> {
+        let malformed = SiiError::MalformedCategory { category: kind };
+        Ok(match kind {
+            CATEGORY_STRINGS => {
+                let count = *data.first().ok_or(malformed.clone())? as usize;
+                let mut strings = Vec::with_capacity(count);
+                let mut pos = 1;
+                for _ in 0..count {
+                    let len = *data.get(pos).ok_or(malformed.clone())? as usize;
+                    let bytes = data.get(pos + 1..pos + 1 + len).ok_or(malformed.clone())?;
+                    // SII strings are 8 bit, keep them lossless as Latin-1
+                    strings.push(bytes.iter().map(|&b| b as char).collect());
+                    pos += 1 + len;
+                }
+                Category::Strings(strings)
+            }
+            CATEGORY_GENERAL => Category::General(General::parse(data)),
+            CATEGORY_FMMU => Category::Fmmu(data.iter().map(|&b| FmmuUsage::from(b)).collect()),
+            CATEGORY_SYNC_MANAGER => Category::SyncManagers(
+                data.chunks_exact(SM_ENTRY_BYTES)
+                    .map(|c| SyncManager {
+                        start_address: u16::from_le_bytes([c[0], c[1]]),
+                        length: u16::from_le_bytes([c[2], c[3]]),
+                        control: c[4],
+                        status: c[5],
+                        enable: c[6],
+                        sm_type: c[7],
+                    })
+                    .collect(),
+            ),
+            CATEGORY_TXPDO | CATEGORY_RXPDO => {
+                let mut pdos = Vec::new();
+                let mut pos = 0;
+                while pos + PDO_HEADER_BYTES <= data.len() {
+                    let count = data[pos + 2] as usize;
+                    let mut pdo = Pdo {
+                        index: read_u16(data, pos)?,
+                        sync_manager: data[pos + 3],
+                        dc_sync: data[pos + 4],
+                        name_idx: data[pos + 5],
+                        flags: read_u16(data, pos + 6)?,
+                        entries: Vec::with_capacity(count),
+                    };
+                    pos += PDO_HEADER_BYTES;
+                    for _ in 0..count {
+                        let e = data
+                            .get(pos..pos + PDO_ENTRY_BYTES)
+                            .ok_or(malformed.clone())?;
+                        pdo.entries.push(PdoEntry {
+                            index: u16::from_le_bytes([e[0], e[1]]),
+                            subindex: e[2],
+                            name_idx: e[3],
+                            data_type: e[4],
+                            bit_length: e[5],
+                            flags: u16::from_le_bytes([e[6], e[7]]),
+                        });
+                        pos += PDO_ENTRY_BYTES;
+                    }
+                    pdos.push(pdo);
+                }
+                if kind == CATEGORY_TXPDO {
+                    Category::TxPdo(pdos)
+                } else {
+                    Category::RxPdo(pdos)
+                }
+            }
+            CATEGORY_DC => Category::Dc(
+                data.chunks_exact(DC_ENTRY_BYTES)
+                    .map(|c| DcSync {
+                        cycle_time0: u32::from_le_bytes([c[0], c[1], c[2], c[3]]),
+                        shift_time0: u32::from_le_bytes([c[4], c[5], c[6], c[7]]),
+                        shift_time1: u32::from_le_bytes([c[8], c[9], c[10], c[11]]),
+                        sync1_cycle_factor: i16::from_le_bytes([c[12], c[13]]),
+                        assign_activate: u16::from_le_bytes([c[14], c[15]]),
+                        sync0_cycle_factor: i16::from_le_bytes([c[16], c[17]]),
+                        name_idx: c[18],
+                        desc_idx: c[19],
+                        reserved: [c[20], c[21], c[22], c[23]],
+                    })
+                    .collect(),
+            ),
+            kind => Category::Other {
+                kind,
+                data: data.to_vec(),
+            },
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) -> Result<(), SiiError> {
+        match self {
+            Category::Strings(strings) => {
+                if strings.len() > u8::MAX as usize {
+                    return Err(SiiError::MalformedCategory {
+                        category: CATEGORY_STRINGS,
+                    });
+                }
+                out.push(strings.len() as u8);
+                for s in strings {
+                    let bytes: Vec<u8> = s
+                        .chars()
+                        .map(|c| u8::try_from(c as u32))
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| SiiError::InvalidString(s.clone()))?;
+                    if bytes.len() > u8::MAX as usize {
+                        return Err(SiiError::InvalidString(s.clone()));
+                    }
+                    out.push(bytes.len() as u8);
+                    out.extend_from_slice(&bytes);
+                }
+            }
+            Category::General(general) => general.write(out),
+            Category::Fmmu(fmmus) => out.extend(fmmus.iter().map(|&f| u8::from(f))),
+            Category::SyncManagers(sms) => {
+                for sm in sms {
+                    out.extend_from_slice(&sm.start_address.to_le_bytes());
+                    out.extend_from_slice(&sm.length.to_le_bytes());
+                    out.extend_from_slice(&[sm.control, sm.status, sm.enable, sm.sm_type]);
+                }
+            }
+            Category::TxPdo(pdos) | Category::RxPdo(pdos) => {
+                for pdo in pdos {
+                    if pdo.entries.len() > u8::MAX as usize {
+                        return Err(SiiError::MalformedCategory {
+                            category: self.kind(),
+                        });
+                    }
+                    out.extend_from_slice(&pdo.index.to_le_bytes());
+                    out.extend_from_slice(&[
+                        pdo.entries.len() as u8,
+                        pdo.sync_manager,
+                        pdo.dc_sync,
+                        pdo.name_idx,
+                    ]);
+                    out.extend_from_slice(&pdo.flags.to_le_bytes());
+                    for e in &pdo.entries {
+                        out.extend_from_slice(&e.index.to_le_bytes());
+                        out.extend_from_slice(&[e.subindex, e.name_idx, e.data_type, e.bit_length]);
+                        out.extend_from_slice(&e.flags.to_le_bytes());
+                    }
+                }
+            }
+            Category::Dc(entries) => {
+                for dc in entries {
+                    out.extend_from_slice(&dc.cycle_time0.to_le_bytes());
+                    out.extend_from_slice(&dc.shift_time0.to_le_bytes());
+                    out.extend_from_slice(&dc.shift_time1.to_le_bytes());
+                    out.extend_from_slice(&dc.sync1_cycle_factor.to_le_bytes());
+                    out.extend_from_slice(&dc.assign_activate.to_le_bytes());
+                    out.extend_from_slice(&dc.sync0_cycle_factor.to_le_bytes());
+                    out.extend_from_slice(&[dc.name_idx, dc.desc_idx]);
+                    out.extend_from_slice(&dc.reserved);
+                }
+            }
+            Category::Other { data, .. } => out.extend_from_slice(data),
+        }
+        Ok(())
+    }
+}
+
+/// A complete SII EEPROM image.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SiiImage {
+    pub config: ConfigArea,
+    pub identity: Identity,
+    pub execution_delay: u16,
+    pub port0_delay: u16,
+    pub port1_delay: u16,
+    pub mailbox: MailboxSettings,
+    /// EEPROM size in KBit minus one (word 0x3E).
+    pub eeprom_size: u16,
+    pub version: u16,
+    /// Words 0x13 and 0x1D..0x3D, kept verbatim.
+    pub reserved: Vec<u16>,
+    pub categories: Vec<Category>,
+}
+
+impl SiiImage {
+    /// Start an empty image for the given identity; the config area checksum is
+    /// recomputed on serialisation.
+    pub fn new(identity: Identity) -> Self {
+        SiiImage {
+            identity,
+            eeprom_size: 1,
+            version: 1,
+            reserved: vec![0; 34],
+            ..Default::default()
+        }
+    }
+
+    /// Parse an image and validate the config area checksum.
+    pub fn parse(data: &[u8]) -> Result<Self, SiiError> {
+        let image = Self::parse_unchecked(data)?;
+        let stored = data[SII_CHECKSUM_WORD as usize * 2];
+        let computed = image.config.checksum();
+        if stored != computed {
+            return Err(SiiError::Checksum { stored, computed });
+        }
+        Ok(image)
+    }
+
+    /// Parse an image without validating the config area checksum.
+    pub fn parse_unchecked(data: &[u8]) -> Result<Self, SiiError> {
+        if data.len() < HEADER_BYTES {
+            return Err(SiiError::Truncated { offset: data.len() });
+        }
+        let word = |w: usize| read_u16(data, w * 2);
+        let area = |w: usize| -> Result<MailboxArea, SiiError> {
+            Ok(MailboxArea {
+                offset: word(w)?,
+                size: word(w + 1)?,
+            })
+        };
+
+        let config = ConfigArea {
+            pdi_control: word(0)?,
+            pdi_config: word(1)?,
+            sync_impulse_len: word(2)?,
+            pdi_config2: word(3)?,
+            station_alias: word(4)?,
+            reserved: [word(5)?, word(6)?],
+        };
+        let identity = Identity {
+            vendor_id: read_u32(data, 0x08 * 2)?,
+            product_code: read_u32(data, 0x0A * 2)?,
+            revision: read_u32(data, 0x0C * 2)?,
+            serial: read_u32(data, 0x0E * 2)?,
+        };
+        let mailbox = MailboxSettings {
+            bootstrap_rx: area(0x14)?,
+            bootstrap_tx: area(0x16)?,
+            standard_rx: area(0x18)?,
+            standard_tx: area(0x1A)?,
+            protocols: word(0x1C)?,
+        };
+        let mut reserved = vec![word(0x13)?];
+        for w in 0x1D..0x3E {
+            reserved.push(word(w)?);
+        }
+
+        let mut categories = Vec::new();
+        let mut pos = HEADER_BYTES;
+        // Images dumped from small EEPROMs may simply stop without an end marker.
+        while pos + 4 <= data.len() {
+            let kind = read_u16(data, pos)?;
+            if kind == CATEGORY_END {
+                break;
+            }
+            let len = read_u16(data, pos + 2)? as usize * 2;
+            let payload = data
+                .get(pos + 4..pos + 4 + len)
+                .ok_or(SiiError::CategoryOverrun {
+                    category: kind,
+                    offset: pos,
+                })?;
+            categories.push(Category::parse(kind, payload)?);
+            pos += 4 + len;
+        }
+
+        Ok(SiiImage {
+            config,
+            identity,
+            execution_delay: word(0x10)?,
+            port0_delay: word(0x11)?,
+            port1_delay: word(0x12)?,
+            mailbox,
+            eeprom_size: word(0x3E)?,
+            version: word(0x3F)?,
+            reserved,
+            categories,
+        })
+    }
+
+    /// Serialise the image, recomputing the config area checksum. The result is padded
+    /// with 0xFF up to the EEPROM size declared in word 0x3E.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SiiError> {
+        let mut out = Vec::with_capacity(self.size_bytes());
+        for w in self.config.words() {
+            out.extend_from_slice(&w.to_le_bytes());
+        }
+        out.extend_from_slice(&(self.config.checksum() as u16).to_le_bytes());
+        for v in [
+            self.identity.vendor_id,
+            self.identity.product_code,
+            self.identity.revision,
+            self.identity.serial,
+        ] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        let r = |i: usize| self.reserved.get(i).copied().unwrap_or(0);
+        let mut words = vec![
+            self.execution_delay,
+            self.port0_delay,
+            self.port1_delay,
+            r(0),
+        ];
+        for a in [
+            self.mailbox.bootstrap_rx,
+            self.mailbox.bootstrap_tx,
+            self.mailbox.standard_rx,
+            self.mailbox.standard_tx,
+        ] {
+            words.push(a.offset);
+            words.push(a.size);
+        }
+        words.push(self.mailbox.protocols);
+        words.extend((1..34).map(r));
+        words.push(self.eeprom_size);
+        words.push(self.version);
+        for w in words {
+            out.extend_from_slice(&w.to_le_bytes());
+        }
+        debug_assert_eq!(out.len(), HEADER_BYTES);
+
+        for category in &self.categories {
+            let mut payload = Vec::new();
+            category.write(&mut payload)?;
+            if payload.len() % 2 != 0 {
+                payload.push(0);
+            }
+            let words =
+                u16::try_from(payload.len() / 2).map_err(|_| SiiError::MalformedCategory {
+                    category: category.kind(),
+                })?;
+            out.extend_from_slice(&category.kind().to_le_bytes());
+            out.extend_from_slice(&words.to_le_bytes());
+            out.extend_from_slice(&payload);
+        }
+        out.extend_from_slice(&CATEGORY_END.to_le_bytes());
+        if out.len() < self.size_bytes() {
+            out.resize(self.size_bytes(), 0xFF);
+        }
+        Ok(out)
+    }
+
+    /// EEPROM size in bytes as declared in word 0x3E.
+    pub fn size_bytes(&self) -> usize {
+        (self.eeprom_size as usize + 1) * 128
+    }
+
+    pub fn category(&self, kind: u16) -> Option<&Category> {
+        self.categories.iter().find(|c| c.kind() == kind)
+    }
+
+    pub fn strings(&self) -> &[String] {
+        match self.category(CATEGORY_STRINGS) {
+            Some(Category::Strings(s)) => s,
+            _ => &[],
+        }
+    }
+
+    /// Look up a string by its one-based SII index; index 0 means "no string".
+    pub fn string(&self, index: u8) -> Option<&str> {
+        let index = (index as usize).checked_sub(1)?;
+        self.strings().get(index).map(String::as_str)
+    }
+
+    /// Append a string to the strings category (creating it if needed) and return
+    /// its one-based index for use in `name_idx` style fields.
+    pub fn add_string(&mut self, s: &str) -> Result<u8, SiiError> {
+        if s.chars().count() > u8::MAX as usize || s.chars().any(|c| c as u32 > 0xFF) {
+            return Err(SiiError::InvalidString(s.to_string()));
+        }
+        if self.category(CATEGORY_STRINGS).is_none() {
+            self.categories.insert(0, Category::Strings(Vec::new()));
+        }
+        for category in &mut self.categories {
+            if let Category::Strings(strings) = category {
+                if let Some(pos) = strings.iter().position(|x| x == s) {
+                    return Ok(pos as u8 + 1);
+                }
+                if strings.len() == u8::MAX as usize {
+                    return Err(SiiError::InvalidString(s.to_string()));
+                }
+                strings.push(s.to_string());
+                return Ok(strings.len() as u8);
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn general(&self) -> Option<&General> {
+        match self.category(CATEGORY_GENERAL) {
+            Some(Category::General(g)) => Some(g),
+            _ => None,
+        }
+    }
+
+    /// Device name taken from the General category.
+    pub fn name(&self) -> Option<&str> {
+        self.general().and_then(|g| self.string(g.name_idx))
+    }
+
+    pub fn fmmus(&self) -> &[FmmuUsage] {
+        match self.category(CATEGORY_FMMU) {
+            Some(Category::Fmmu(f)) => f,
+            _ => &[],
+        }
+    }
+
+    pub fn sync_managers(&self) -> &[SyncManager] {
+        match self.category(CATEGORY_SYNC_MANAGER) {
+            Some(Category::SyncManagers(s)) => s,
+            _ => &[],
+        }
+    }
+
+    pub fn tx_pdos(&self) -> &[Pdo] {
+        match self.category(CATEGORY_TXPDO) {
+            Some(Category::TxPdo(p)) => p,
+            _ => &[],
+        }
+    }
+
+    pub fn rx_pdos(&self) -> &[Pdo] {
+        match self.category(CATEGORY_RXPDO) {
+            Some(Category::RxPdo(p)) => p,
+            _ => &[],
+        }
+    }
+
+    pub fn dc_syncs(&self) -> &[DcSync] {
+        match self.category(CATEGORY_DC) {
+            Some(Category::Dc(d)) => d,
+            _ => &[],
+        }
+    }
+
+    /// Replace the category of the same kind, or append it if the image has none.
+    pub fn set_category(&mut self, category: Category) {
+        match self
+            .categories
+            .iter_mut()
+            .find(|c| c.kind() == category.kind())
+        {
+            Some(existing) => *existing = category,
+            None => self.categories.push(category),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> SiiImage {
+        let mut image = SiiImage::new(Identity {
+            vendor_id: 0x0000_0002,
+            product_code: 0x0444_2C52,
+            revision: 0x0011_0000,
+            serial: 1234,
+        });
+        image.config.pdi_control = 0x0C80;
+        image.config.pdi_config = 0x6E00;
+        image.config.station_alias = 0x0102;
+        image.mailbox.standard_rx = MailboxArea {
+            offset: 0x1000,
+            size: 128,
+        };
+        image.mailbox.standard_tx = MailboxArea {
+            offset: 0x1080,
+            size: 128,
+        };
+        image.mailbox.protocols = 0x000C;
+        let name = image.add_string("EL1004").unwrap();
+        image.set_category(Category::General(General {
+            name_idx: name,
+            coe_details: 0x23,
+            current_on_ebus: 90,
+            ..General::default()
+        }));
+        image.set_category(Category::Fmmu(vec![FmmuUsage::Outputs, FmmuUsage::Inputs]));
+        image.set_category(Category::SyncManagers(vec![SyncManager {
+            start_address: 0x1000,
+            length: 128,
+            control: 0x26,
+            status: 0,
+            enable: 1,
+            sm_type: 1,
+        }]));
+        image.set_category(Category::TxPdo(vec![Pdo {
+            index: 0x1A00,
+            sync_manager: 3,
+            entries: vec![PdoEntry {
+                index: 0x6000,
+                subindex: 1,
+                data_type: 1,
+                bit_length: 1,
+                ..PdoEntry::default()
+            }],
+            ..Pdo::default()
+        }]));
+        image.set_category(Category::Dc(vec![DcSync {
+            cycle_time0: 1_000_000,
+            assign_activate: 0x0300,
+            ..DcSync::default()
+        }]));
+        image
+    }
+
+    #[test]
+    fn crc8_check_value() {
+        assert_eq!(crc8(b"123456789"), 0xFB);
+        assert_eq!(crc8(&[]), 0xFF);
+    }
+
+    #[test]
+    fn config_checksum_covers_first_seven_words() {
+        let words = [0x0C80, 0x6E00, 0, 0, 0, 0, 0];
+        assert_eq!(config_checksum(&words), 0x8D);
+        let mut alias = words;
+        alias[SII_ALIAS_WORD as usize] = 1;
+        assert_ne!(config_checksum(&alias), 0x8D);
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = sample();
+        let bytes = image.to_bytes().unwrap();
+        assert_eq!(bytes.len(), image.size_bytes());
+        assert_eq!(
+            bytes[SII_CHECKSUM_WORD as usize * 2],
+            image.config.checksum()
+        );
+        let parsed = SiiImage::parse(&bytes).unwrap();
+        assert_eq!(parsed, image);
+        assert_eq!(parsed.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn categories() {
+        let image = SiiImage::parse(&sample().to_bytes().unwrap()).unwrap();
+        assert_eq!(image.name(), Some("EL1004"));
+        assert_eq!(image.general().unwrap().current_on_ebus, 90);
+        assert_eq!(image.fmmus(), [FmmuUsage::Outputs, FmmuUsage::Inputs]);
+        assert_eq!(image.sync_managers()[0].start_address, 0x1000);
+        assert_eq!(image.tx_pdos()[0].bit_length(), 1);
+        assert!(image.rx_pdos().is_empty());
+        assert_eq!(image.dc_syncs()[0].assign_activate, 0x0300);
+        assert_eq!(image.string(0), None);
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut bytes = sample().to_bytes().unwrap();
+        bytes[SII_ALIAS_WORD as usize * 2] ^= 0xFF;
+        assert!(matches!(
+            SiiImage::parse(&bytes),
+            Err(SiiError::Checksum { .. })
+        ));
+        let image = SiiImage::parse_unchecked(&bytes).unwrap();
+        assert_eq!(image.config.station_alias, 0x01FD);
+    }
+
+    #[test]
+    fn truncated_and_overrun() {
+        assert_eq!(
+            SiiImage::parse(&[0; 16]),
+            Err(SiiError::Truncated { offset: 16 })
+        );
+        let mut bytes = SiiImage::new(Identity::default()).to_bytes().unwrap();
+        bytes.truncate(SII_CATEGORY_START * 2);
+        bytes.extend_from_slice(&[CATEGORY_FMMU as u8, 0, 8, 0, 1, 2]);
+        assert_eq!(
+            SiiImage::parse(&bytes),
+            Err(SiiError::CategoryOverrun {
+                category: CATEGORY_FMMU,
+                offset: SII_CATEGORY_START * 2,
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_categories_are_kept() {
+        let mut image = sample();
+        image.set_category(Category::Other {
+            kind: 0x0800,
+            data: vec![1, 2, 3, 4],
+        });
+        let parsed = SiiImage::parse(&image.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.category(0x0800), image.category(0x0800));
+    }
+
+    #[test]
+    fn strings() {
+        let mut image = SiiImage::new(Identity::default());
+        assert_eq!(image.add_string("a"), Ok(1));
+        assert_eq!(image.add_string("b"), Ok(2));
+        assert_eq!(image.add_string("a"), Ok(1));
+        assert_eq!(image.string(2), Some("b"));
+        assert!(matches!(
+            image.add_string("\u{263A}"),
+            Err(SiiError::InvalidString(_))
+        ));
+        let latin1 = "\u{E9}".repeat(200);
+        assert_eq!(image.add_string(&latin1), Ok(3));
+        assert!(image.add_string(&"a".repeat(256)).is_err());
+    }
+}
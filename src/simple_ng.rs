@@ -1,31 +1,67 @@
 use std::{
-    env,
+    collections::VecDeque,
     ffi::{CString, c_void},
     mem::MaybeUninit,
+    sync::Arc,
+    time::Duration,
 };
 
-mod bindings {
-    include!("bindings.rs");
-}
-use bindings::*;
+use crate::bindings::*;
+use crate::clock::ClockController;
+use crate::config::{BusConfig, Mismatch};
+use crate::cyclic::CyclicMailbox;
+use crate::dc::{DcMode, DcTimeOrigin};
+use crate::emcy::EmergencyHandler;
+use crate::eni::{Eni, EniTable};
+use crate::hotconnect::HotConnect;
+use crate::latch::{ArmedLatch, LatchHandler};
+use crate::mailbox::MailboxQueue;
+use crate::shutdown::SafeValue;
+use crate::statistics::FrameStatistics;
 
 pub struct Fieldbus {
-    context: ecx_contextt,
-    iface: CString,
-    group: u8,
-    roundtrip_time: i32,
-    map: [u8; 4096],
+    pub(crate) context: Box<ecx_contextt>,
+    pub(crate) iface: CString,
+    pub(crate) group: u8,
+    pub(crate) roundtrip_time: i32,
+    /// On the heap, so the pointers SOEM keeps into it survive moving the bus.
+    pub(crate) map: Box<[u8; 4096]>,
+    pub(crate) eni: Option<EniTable>,
+    pub(crate) emcy_handlers: Vec<EmergencyHandler>,
+    /// Next entry of SOEM's error ring to look at for emergencies.
+    pub(crate) emcy_cursor: i16,
+    pub(crate) mailbox: Option<CyclicMailbox>,
+    pub(crate) mailbox_queue: Option<MailboxQueue>,
+    /// Frames read from a mailbox for requests of another protocol.
+    pub(crate) mailbox_inbox: VecDeque<(u16, Vec<u8>)>,
+    pub(crate) bus_config: Option<BusConfig>,
+    pub(crate) excluded: Vec<u16>,
+    pub(crate) mismatches: Vec<Mismatch>,
+    pub(crate) alias_addressing: bool,
+    pub(crate) hot_connect: Vec<HotConnect>,
+    pub(crate) hot_connect_wkc: i32,
+    pub(crate) dc_modes: Vec<(u16, DcMode)>,
+    pub(crate) reference_clock: Option<u16>,
+    pub(crate) dc_time_origin: DcTimeOrigin,
+    pub(crate) latches: Vec<ArmedLatch>,
+    pub(crate) latch_handlers: Vec<LatchHandler>,
+    pub(crate) safe_values: Vec<SafeValue>,
+    /// The socket is open and `shutdown` has not run yet.
+    pub(crate) running: bool,
+    pub(crate) clock: Option<ClockController>,
+    pub(crate) statistics: Arc<FrameStatistics>,
+    pub(crate) frame_retries: u32,
 }
 
 /*
 How do i dynamically get offsets for terminals????
 */
 pub fn set_output(fieldbus: &mut Fieldbus, slave_index: usize,byte_offset : u16, output_bit: u8, value: bool) {
-    let context = &mut fieldbus.context;
-    let grp =  &mut (*context).grouplist[fieldbus.group as usize] ;
+    let context = &mut *fieldbus.context;
+    let grp = &mut context.grouplist[fieldbus.group as usize];
 
     // Get pointer to the start of this slave's outputs
-    let slave =   &(*context).slavelist[slave_index];
+    let slave = &context.slavelist[slave_index];
     println!("Slave {}: Vendor {}, Product {}, Rev {}\n",
        slave_index,
        slave.eep_man,
@@ -41,95 +77,222 @@ pub fn set_output(fieldbus: &mut Fieldbus, slave_index: usize,byte_offset : u16,
         }
 
         // send/receive processdata
-        ecx_send_processdata(context);
-        ecx_receive_processdata(context, EC_TIMEOUTRET.try_into().unwrap());
+        ecx_send_processdata_group(context, fieldbus.group);
+        ecx_receive_processdata_group(context, fieldbus.group, EC_TIMEOUTRET.try_into().unwrap());
     }
 }
 
 impl Fieldbus {
     pub fn new(iface: CString) -> Self {
-        let context = unsafe { MaybeUninit::zeroed().assume_init() };
+        let context = unsafe { Box::new_zeroed().assume_init() };
         Fieldbus {
             context,
             iface,
             group: 0,
             roundtrip_time: 0,
-            map: [0u8; 4096],
+            map: Box::new([0u8; 4096]),
+            eni: None,
+            emcy_handlers: Vec::new(),
+            emcy_cursor: 0,
+            mailbox: None,
+            mailbox_queue: None,
+            mailbox_inbox: VecDeque::new(),
+            bus_config: None,
+            excluded: Vec::new(),
+            mismatches: Vec::new(),
+            alias_addressing: false,
+            hot_connect: Vec::new(),
+            hot_connect_wkc: 0,
+            dc_modes: Vec::new(),
+            reference_clock: None,
+            dc_time_origin: DcTimeOrigin::Utc,
+            latches: Vec::new(),
+            latch_handlers: Vec::new(),
+            safe_values: Vec::new(),
+            running: false,
+            clock: None,
+            statistics: Arc::default(),
+            frame_retries: 0,
+        }
+    }
+
+    /// Attach an ENI so its CoE init commands are sent during state transitions.
+    /// SOEM itself runs the PRE_OP->SAFE_OP commands while mapping, the others are
+    /// sent from `start` and `stop`.
+    pub fn set_eni(&mut self, eni: &Eni) {
+        let mut table = EniTable::new(eni);
+        self.context.ENI = table.as_mut_ptr();
+        self.eni = Some(table);
+    }
+
+    pub(crate) fn eni_initcmds(&mut self, transition: u32) -> bool {
+        if self.eni.is_none() {
+            return true;
+        }
+        let context = &mut *self.context as *mut ecx_contextt;
+        let mut ok = true;
+        for slave in 1..=self.context.slavecount as u16 {
+            if self.excluded.contains(&slave) {
+                continue;
+            }
+            if unsafe { ecx_mbxENIinitcmds(context, slave, transition as u16) } == 0 {
+                log::warn!("ENI init commands failed for slave {}", slave);
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    /// Slaves whose state is changed by `start`: all of them, or one by one if
+    /// the bus configuration excluded some.
+    fn state_slaves(&self) -> Vec<u16> {
+        if self.excluded.is_empty() {
+            return vec![0];
         }
+        (1..=self.context.slavecount as u16)
+            .filter(|slave| !self.excluded.contains(slave))
+            .collect()
+    }
+
+    pub(crate) fn request_state(&mut self, state: u16) {
+        let context = &mut *self.context as *mut ecx_contextt;
+        for slave in self.state_slaves() {
+            unsafe {
+                (*context).slavelist[slave as usize].state = state;
+                ecx_writestate(context, slave);
+            }
+        }
+    }
+
+    pub(crate) fn wait_state(&mut self, state: u16, timeout: i32) -> bool {
+        let context = &mut *self.context as *mut ecx_contextt;
+        self.state_slaves().into_iter().all(|slave| {
+            unsafe { ecx_statecheck(context, slave, state, timeout) };
+            self.context.slavelist[slave as usize].state == state
+        })
     }
 
     pub fn roundtrip(&mut self) -> i32 {
-        let context = &mut self.context as *mut ecx_contextt;
-
-        unsafe {
-            let mut start = osal_current_time();
-            ecx_send_processdata(context);
-            let wkc = ecx_receive_processdata(context, EC_TIMEOUTRET.try_into().unwrap());
-            let mut end = osal_current_time();
-            let mut diff = MaybeUninit::<timespec>::zeroed().assume_init();
-            osal_time_diff(&mut start, &mut end, &mut diff);
-            self.roundtrip_time = (diff.tv_sec * 1_000_000 + diff.tv_nsec / 1000) as i32;
-            wkc
+        let wkc = self.exchange(self.group);
+        self.statistics.record_cycle(self.group, wkc == self.expected_wkc());
+        self.exchange_hot_connect();
+        wkc
+    }
+
+    /// Exchange the process data of `group`, retrying lost frames.
+    pub(crate) fn exchange(&mut self, group: u8) -> i32 {
+        let context = &mut *self.context as *mut ecx_contextt;
+        let frames = self.context.grouplist[group as usize].nsegments.max(1) as u64;
+
+        let mut attempt = 0;
+        loop {
+            let wkc = unsafe {
+                let mut start = osal_current_time();
+                ecx_send_processdata_group(context, group);
+                let wkc = ecx_receive_processdata_group(
+                    context,
+                    group,
+                    EC_TIMEOUTRET.try_into().unwrap(),
+                );
+                let mut end = osal_current_time();
+                let mut diff = MaybeUninit::<timespec>::zeroed().assume_init();
+                osal_time_diff(&mut start, &mut end, &mut diff);
+                self.roundtrip_time = (diff.tv_sec * 1_000_000 + diff.tv_nsec / 1000) as i32;
+                wkc
+            };
+            self.statistics.record_exchange(
+                frames,
+                wkc,
+                self.roundtrip_time.max(0) as u64,
+                attempt > 0,
+            );
+            if wkc != EC_NOFRAME || attempt >= self.frame_retries {
+                break wkc;
+            }
+            attempt += 1;
         }
     }
 
     pub fn start(&mut self) -> bool {
-        let context = &mut self.context as *mut ecx_contextt;
+        let context = &mut *self.context as *mut ecx_contextt;
 
         println!("Initializing SOEM on {:?}", self.iface);
         if unsafe { ecx_init(context, self.iface.as_ptr()) } == 0 {
             println!("No socket connection");
             return false;
         }
+        self.running = true;
 
         println!("Finding autoconfig slaves...");
         if unsafe { ecx_config_init(context) } <= 0 {
             println!("No slaves found");
             return false;
         }
+        self.excluded.clear();
+        self.identify_hot_connect_groups();
+        if !self.apply_bus_config() {
+            return false;
+        }
+        self.assign_groups();
+        self.apply_alias_addressing();
+        if !self.apply_watchdogs() {
+            return false;
+        }
+        if !self.eni_initcmds(ECT_ESMTRANS_IP) {
+            return false;
+        }
+
+        // slaves check their sync settings on PRE_OP->SAFE_OP, so request SAFE_OP
+        // only after the sync signals are set up
+        let dc = self.has_dc_modes();
+        self.context.manualstatechange = dc as i32;
 
         println!("Sequential mapping of I/O...");
-        unsafe {
+        let used = unsafe {
             ecx_config_map_group(
                 context,
                 self.map.as_mut_ptr() as *mut c_void,
                 self.group,
-            );
-        }
+            )
+        };
+        self.map_hot_connect_group(used.max(0) as usize);
 
         println!("Configuring distributed clock...");
         unsafe { ecx_configdc(context) };
+        if let Err(e) = self.configure_dc_clocks() {
+            log::error!("Distributed clock configuration failed: {}", e);
+            return false;
+        }
+        if dc {
+            if !self.apply_dc_modes() {
+                return false;
+            }
+            self.request_state(ec_state_EC_STATE_SAFE_OP as u16);
+        }
 
         println!("Waiting for all slaves in safe operational...");
-        unsafe {
-            ecx_statecheck(
-                context,
-                0,
-                ec_state_EC_STATE_SAFE_OP as u16,
-                (EC_TIMEOUTSTATE * 4).try_into().unwrap(),
-            )
-        };
+        self.wait_state(
+            ec_state_EC_STATE_SAFE_OP as u16,
+            (EC_TIMEOUTSTATE * 4).try_into().unwrap(),
+        );
 
         println!("Send a roundtrip to make outputs happy...");
         self.roundtrip();
 
+        if !self.eni_initcmds(ECT_ESMTRANS_SO) {
+            return false;
+        }
+
         println!("Setting operational state...");
-        let slave = unsafe { &mut (*context).slavelist[0] };
-        slave.state = ec_state_EC_STATE_OPERATIONAL as u16;
-        unsafe { ecx_writestate(context, 0) };
+        self.request_state(ec_state_EC_STATE_OPERATIONAL as u16);
 
         // Poll ten times
         for _ in 0..10 {
             self.roundtrip();
-            unsafe {
-                ecx_statecheck(
-                    context,
-                    0,
-                    ec_state_EC_STATE_OPERATIONAL as u16,
-                    (EC_TIMEOUTSTATE / 10).try_into().unwrap(),
-                )
-            };
-            if slave.state == ec_state_EC_STATE_OPERATIONAL as u16 {
+            if self.wait_state(
+                ec_state_EC_STATE_OPERATIONAL as u16,
+                (EC_TIMEOUTSTATE / 10).try_into().unwrap(),
+            ) {
                 println!("All slaves are now operational");
                 return true;
             }
@@ -140,22 +303,17 @@ impl Fieldbus {
         false
     }
 
+    /// Shut down with the safe outputs and the default state change timeout.
     pub fn stop(&mut self) {
-        let context = &mut self.context as *mut ecx_contextt;
-        let slave = unsafe { &mut (*context).slavelist[0] };
-        println!("Requesting init state on all slaves...");
-        slave.state = ec_state_EC_STATE_INIT as u16;
-        unsafe { ecx_writestate(context, 0) };
-        println!("Close socket...");
-        unsafe { ecx_close(context) };
+        self.shutdown(Duration::from_micros(EC_TIMEOUTSTATE as u64));
     }
 
     pub fn dump(&mut self) -> bool {
-        let context = &mut self.context as *mut ecx_contextt;
+        let context = &mut *self.context as *mut ecx_contextt;
         let grp = unsafe { &*context }.grouplist[self.group as usize];
 
         let wkc = self.roundtrip();
-        let expected_wkc = (grp.outputsWKC + grp.inputsWKC) as i32;
+        let expected_wkc = self.expected_wkc();
         println!("WKC: {} (expected {})", wkc, expected_wkc);
         if wkc < expected_wkc {
             println!("Mismatch");
@@ -182,6 +340,12 @@ impl Fieldbus {
     }
 }
 
+impl Drop for Fieldbus {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
 /*
 generated with: sudo ./samples/slaveinfo/slaveinfo eth0 -sdo
 
@@ -229,29 +393,3 @@ For WAGO each terminal is a module:
     0x03      ""                                            [UNSIGNED32       R_R_R_]      0x80000083 / 2147483779
 
 */
-
-fn main() {
-    let ifname = env::args().nth(1).expect("Usage: program <interface>");
-    let ifname_c = CString::new(ifname).unwrap();
-    let mut fieldbus = Fieldbus::new(ifname_c);
-
-    if fieldbus.start() {
-        for i in 1..=100000 {
-            println!("Iteration {}", i);
-            if !fieldbus.dump() {
-                // check state could be implemented
-            }
-
-                let byte_offset = 0x001C;
-
-            if i % 100 == 0 {
-                // 0x001C is the start of the 750-501 process image
-                set_output(&mut fieldbus,1,0x001C,1,true);
-            }else{
-                set_output(&mut fieldbus,1,0x001C,1,false);
-            }
-            unsafe { osal_usleep(5000) };
-        }
-        fieldbus.stop();
-    }
-}
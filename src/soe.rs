@@ -0,0 +1,338 @@
+//! Servo profile over EtherCAT (SoE) parameter access that never blocks. Like an
+//! `SdoRequest`, a request is advanced by one mailbox step each time it is polled.
+
+use std::{
+    fmt,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use crate::bindings::*;
+use crate::mailbox::{MBX_HEADER, Step};
+use crate::simple_ng::Fieldbus;
+
+/// Mailbox header and SoE header: opcode/flags/drive, element flags and IDN or
+/// fragments left.
+const SOE_HEADER: usize = MBX_HEADER + 4;
+const INCOMPLETE: u8 = 0x08;
+const ERROR: u8 = 0x10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SoeError {
+    /// The drive refused the request with this SoE error code.
+    Drive(u16),
+    /// The slave answered with a mailbox error, carrying its detail code.
+    Mailbox(u16),
+    /// No complete answer within the request timeout.
+    Timeout,
+    /// The slave's answer does not follow the SoE protocol.
+    Protocol(&'static str),
+}
+
+impl fmt::Display for SoeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SoeError::Drive(code) => write!(f, "SoE error {:04x}", code),
+            SoeError::Mailbox(detail) => write!(f, "mailbox error {:04x}", detail),
+            SoeError::Timeout => write!(f, "SoE timeout"),
+            SoeError::Protocol(what) => write!(f, "SoE protocol error: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SoeError {}
+
+/// An SoE read or write of one or more elements of an IDN, by default its value.
+/// Create it with `read` or `write` and call `poll` once per cycle until it
+/// returns `Poll::Ready`.
+#[derive(Debug)]
+pub struct SoeRequest {
+    slave: u16,
+    drive: u8,
+    idn: u16,
+    elements: u8,
+    read: bool,
+    timeout: Duration,
+    /// Read: data received so far. Write: data to send.
+    data: Vec<u8>,
+    /// Write: bytes of `data` in fragments already built.
+    offset: usize,
+    frame: Vec<u8>,
+    /// The timeout counts from here.
+    created: Instant,
+    step: Step<SoeError>,
+}
+
+impl SoeRequest {
+    pub fn read(slave: u16, drive: u8, idn: u16) -> Self {
+        Self::new(slave, drive, idn, true, Vec::new())
+    }
+
+    pub fn write(slave: u16, drive: u8, idn: u16, data: impl Into<Vec<u8>>) -> Self {
+        Self::new(slave, drive, idn, false, data.into())
+    }
+
+    fn new(slave: u16, drive: u8, idn: u16, read: bool, data: Vec<u8>) -> Self {
+        SoeRequest {
+            slave,
+            drive,
+            idn,
+            elements: EC_SOE_VALUE_B as u8,
+            read,
+            timeout: Duration::from_micros(EC_TIMEOUTRXM as u64),
+            data,
+            offset: 0,
+            frame: Vec::new(),
+            created: Instant::now(),
+            step: Step::Send,
+        }
+    }
+
+    /// Access the elements in `elements` (`EC_SOE_*_B`) instead of the value.
+    pub fn elements(mut self, elements: u8) -> Self {
+        self.elements = elements;
+        self
+    }
+
+    /// Time allowed for the whole transfer, counted from when the request is
+    /// created, so time spent queued in a `MailboxClient` counts too.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn slave(&self) -> u16 {
+        self.slave
+    }
+
+    pub fn idn(&self) -> u16 {
+        self.idn
+    }
+
+    /// When the request times out.
+    pub(crate) fn deadline(&self) -> Instant {
+        self.created + self.timeout
+    }
+
+    /// Advance the transfer. Returns the data read, or an empty vector for a
+    /// write, once the drive has answered. Polling again after that returns the
+    /// same result.
+    pub fn poll(&mut self, bus: &mut Fieldbus) -> Poll<Result<Vec<u8>, SoeError>> {
+        if let Step::Done(result) = &self.step {
+            return Poll::Ready(result.clone());
+        }
+        if self.frame.is_empty() {
+            if self.created.elapsed() > self.timeout {
+                // expired before anything was sent
+                self.step = Step::Done(Err(SoeError::Timeout));
+                return Poll::Ready(Err(SoeError::Timeout));
+            }
+            self.frame = if self.read {
+                self.frame(bus, ECT_SOE_READREQ, 0, self.idn, &[])
+            } else {
+                self.fragment(bus)
+            };
+        }
+        let answer = bus.mailbox_step(self.slave, ECT_MBXT_SOE, &self.frame, &mut self.step);
+        // fragments of a write are not answered, only the last one is
+        if answer.is_none()
+            && matches!(self.step, Step::Receive)
+            && !self.read
+            && self.offset < self.data.len()
+        {
+            self.frame = self.fragment(bus);
+            self.step = Step::Send;
+        }
+        let result = answer.and_then(|frame| self.answer(&frame));
+        let result = match result {
+            None if self.created.elapsed() > self.timeout => {
+                bus.mailbox_cancel(self.slave, &self.step);
+                Some(Err(SoeError::Timeout))
+            }
+            result => result,
+        };
+        match result {
+            Some(result) => {
+                self.step = Step::Done(result.clone());
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    fn frame(&self, bus: &mut Fieldbus, opcode: u32, flags: u8, idn: u16, data: &[u8]) -> Vec<u8> {
+        let length = SOE_HEADER - MBX_HEADER + data.len();
+        let mut frame = bus.mailbox_frame(self.slave, ECT_MBXT_SOE, length);
+        frame[MBX_HEADER] = opcode as u8 | flags | (self.drive & 0x07) << 5;
+        frame[MBX_HEADER + 1] = self.elements;
+        frame[MBX_HEADER + 2..SOE_HEADER].copy_from_slice(&idn.to_le_bytes());
+        frame[SOE_HEADER..].copy_from_slice(data);
+        frame
+    }
+
+    /// The next fragment of a write. All but the last one are flagged incomplete
+    /// and carry the number of fragments still to follow in place of the IDN.
+    fn fragment(&mut self, bus: &mut Fieldbus) -> Vec<u8> {
+        let max = bus
+            .mailbox_capacity(self.slave)
+            .saturating_sub(SOE_HEADER - MBX_HEADER)
+            .max(1);
+        let n = (self.data.len() - self.offset).min(max);
+        let rest = self.data.len() - self.offset - n;
+        let data = &self.data[self.offset..self.offset + n];
+        let frame = if rest > 0 {
+            let left = rest.div_ceil(max) as u16;
+            self.frame(bus, ECT_SOE_WRITEREQ, INCOMPLETE, left, data)
+        } else {
+            self.frame(bus, ECT_SOE_WRITEREQ, 0, self.idn, data)
+        };
+        self.offset += n;
+        frame
+    }
+
+    /// Handle a frame read from the slave. Returns the outcome, or `None` when the
+    /// transfer continues.
+    fn answer(&mut self, frame: &[u8]) -> Option<Result<Vec<u8>, SoeError>> {
+        let length = u16::from_le_bytes([frame[0], frame[1]]) as usize;
+        let frame = &frame[..(MBX_HEADER + length).min(frame.len())];
+        if frame[5] as u32 & 0x0f == ECT_MBXT_ERR {
+            if frame.len() < MBX_HEADER + 4 {
+                return None;
+            }
+            let detail = u16::from_le_bytes([frame[8], frame[9]]);
+            return Some(Err(SoeError::Mailbox(detail)));
+        }
+        if frame.len() < SOE_HEADER {
+            return None;
+        }
+        let header = frame[MBX_HEADER];
+        if (header >> 5) & 0x07 != self.drive & 0x07 {
+            return None;
+        }
+        if header & ERROR != 0 {
+            if frame.len() < SOE_HEADER + 2 {
+                return Some(Err(SoeError::Protocol("short error response")));
+            }
+            let code = u16::from_le_bytes([frame[frame.len() - 2], frame[frame.len() - 1]]);
+            return Some(Err(SoeError::Drive(code)));
+        }
+        let incomplete = header & INCOMPLETE != 0;
+        let idn = u16::from_le_bytes([frame[MBX_HEADER + 2], frame[MBX_HEADER + 3]]);
+        match header as u32 & 0x07 {
+            ECT_SOE_READRES if self.read => {
+                if !incomplete && idn != self.idn {
+                    return Some(Err(SoeError::Protocol("answer for another IDN")));
+                }
+                self.data.extend_from_slice(&frame[SOE_HEADER..]);
+                // further fragments follow without being asked for
+                (!incomplete).then(|| Ok(std::mem::take(&mut self.data)))
+            }
+            ECT_SOE_WRITERES if !self.read => {
+                if idn != self.idn {
+                    return Some(Err(SoeError::Protocol("answer for another IDN")));
+                }
+                Some(Ok(Vec::new()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    /// 26 bytes after the mailbox header, 22 data bytes per fragment.
+    fn bus() -> Fieldbus {
+        let mut bus = Fieldbus::new(CString::new("test").unwrap());
+        bus.context.slavecount = 1;
+        bus.context.slavelist[1].mbx_l = 32;
+        bus
+    }
+
+    fn response(header: u8, idn: u16, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; SOE_HEADER];
+        frame[0..2].copy_from_slice(&((SOE_HEADER - MBX_HEADER + data.len()) as u16).to_le_bytes());
+        frame[5] = ECT_MBXT_SOE as u8;
+        frame[MBX_HEADER] = header;
+        frame[MBX_HEADER + 1] = EC_SOE_VALUE_B as u8;
+        frame[MBX_HEADER + 2..SOE_HEADER].copy_from_slice(&idn.to_le_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn read() {
+        let mut request = SoeRequest::read(1, 0, 32);
+        let frame = response(ECT_SOE_READRES as u8, 32, &[1, 2, 3, 4]);
+        assert_eq!(request.answer(&frame), Some(Ok(vec![1, 2, 3, 4])));
+    }
+
+    #[test]
+    fn fragmented_read() {
+        let mut request = SoeRequest::read(1, 2, 32);
+        // answers for another drive are not ours
+        let frame = response(ECT_SOE_READRES as u8 | INCOMPLETE, 1, &[1, 2]);
+        assert_eq!(request.answer(&frame), None);
+        assert!(request.data.is_empty());
+
+        let drive = 2 << 5;
+        let frame = response(ECT_SOE_READRES as u8 | INCOMPLETE | drive, 1, &[1, 2]);
+        assert_eq!(request.answer(&frame), None);
+        let frame = response(ECT_SOE_READRES as u8 | drive, 32, &[3]);
+        assert_eq!(request.answer(&frame), Some(Ok(vec![1, 2, 3])));
+    }
+
+    #[test]
+    fn read_errors() {
+        let mut request = SoeRequest::read(1, 0, 32);
+        let frame = response(ECT_SOE_READRES as u8, 33, &[1, 2]);
+        assert_eq!(
+            request.answer(&frame),
+            Some(Err(SoeError::Protocol("answer for another IDN")))
+        );
+        let frame = response(ECT_SOE_READRES as u8 | ERROR, 32, &0x1001u16.to_le_bytes());
+        assert_eq!(request.answer(&frame), Some(Err(SoeError::Drive(0x1001))));
+        let frame = response(ECT_SOE_READRES as u8 | ERROR, 32, &[]);
+        assert_eq!(
+            request.answer(&frame),
+            Some(Err(SoeError::Protocol("short error response")))
+        );
+        let mut frame = response(0, 0, &[]);
+        frame[5] = ECT_MBXT_ERR as u8;
+        frame[8..10].copy_from_slice(&0x0002u16.to_le_bytes());
+        assert_eq!(request.answer(&frame), Some(Err(SoeError::Mailbox(0x0002))));
+    }
+
+    #[test]
+    fn fragmented_write() {
+        let mut bus = bus();
+        let data: Vec<u8> = (0..50).collect();
+        let mut request = SoeRequest::write(1, 1, 47, data.clone());
+        let frame = request.fragment(&mut bus);
+        assert_eq!(
+            frame[MBX_HEADER],
+            ECT_SOE_WRITEREQ as u8 | INCOMPLETE | 1 << 5
+        );
+        assert_eq!(frame[MBX_HEADER + 2..SOE_HEADER], 2u16.to_le_bytes());
+        assert_eq!(frame[SOE_HEADER..], data[..22]);
+        let frame = request.fragment(&mut bus);
+        assert_eq!(frame[MBX_HEADER + 2..SOE_HEADER], 1u16.to_le_bytes());
+        assert_eq!(frame[SOE_HEADER..], data[22..44]);
+        let frame = request.fragment(&mut bus);
+        assert_eq!(frame[MBX_HEADER], ECT_SOE_WRITEREQ as u8 | 1 << 5);
+        assert_eq!(frame[MBX_HEADER + 2..SOE_HEADER], 47u16.to_le_bytes());
+        assert_eq!(frame[SOE_HEADER..], data[44..]);
+        assert_eq!(request.offset, data.len());
+
+        let frame = response(ECT_SOE_WRITERES as u8 | 1 << 5, 46, &[]);
+        assert_eq!(
+            request.answer(&frame),
+            Some(Err(SoeError::Protocol("answer for another IDN")))
+        );
+        let frame = response(ECT_SOE_WRITERES as u8 | 1 << 5, 47, &[]);
+        assert_eq!(request.answer(&frame), Some(Ok(Vec::new())));
+    }
+}
@@ -0,0 +1,266 @@
+//! Frame and cycle statistics. `roundtrip` and the datagram functions count into
+//! a set of atomics that other threads read through `Fieldbus::statistics`
+//! without locking the bus.
+
+use std::{
+    array,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::bindings::*;
+use crate::simple_ng::Fieldbus;
+
+/// Width of a round trip histogram bucket in us.
+pub const HISTOGRAM_BUCKET_US: u64 = 10;
+/// Buckets up to the process data receive timeout, plus one for longer round trips.
+pub const HISTOGRAM_BUCKETS: usize = (EC_TIMEOUTRET as u64 / HISTOGRAM_BUCKET_US) as usize + 1;
+
+const GROUPS: usize = EC_MAXGROUP as usize;
+
+/// Counters shared with other threads. Each counter is updated atomically, a
+/// snapshot taken while the bus runs may mix counts of adjacent cycles.
+pub struct FrameStatistics {
+    cycles: AtomicU64,
+    frames_sent: AtomicU64,
+    frames_received: AtomicU64,
+    frames_lost: AtomicU64,
+    retries: AtomicU64,
+    wkc_mismatches: [AtomicU64; GROUPS],
+    datagrams: AtomicU64,
+    datagrams_lost: AtomicU64,
+    /// Round trip times in us.
+    rtt_min: AtomicU64,
+    rtt_max: AtomicU64,
+    rtt_sum: AtomicU64,
+    rtt_count: AtomicU64,
+    histogram: [AtomicU64; HISTOGRAM_BUCKETS],
+}
+
+impl Default for FrameStatistics {
+    fn default() -> Self {
+        FrameStatistics {
+            cycles: AtomicU64::new(0),
+            frames_sent: AtomicU64::new(0),
+            frames_received: AtomicU64::new(0),
+            frames_lost: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            wkc_mismatches: array::from_fn(|_| AtomicU64::new(0)),
+            datagrams: AtomicU64::new(0),
+            datagrams_lost: AtomicU64::new(0),
+            rtt_min: AtomicU64::new(u64::MAX),
+            rtt_max: AtomicU64::new(0),
+            rtt_sum: AtomicU64::new(0),
+            rtt_count: AtomicU64::new(0),
+            histogram: array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl FrameStatistics {
+    /// A process data exchange of `frames` frames; `wkc` as returned by SOEM and
+    /// the round trip time in us.
+    pub(crate) fn record_exchange(&self, frames: u64, wkc: i32, rtt: u64, retry: bool) {
+        self.frames_sent.fetch_add(frames, Ordering::Relaxed);
+        if retry {
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        }
+        if wkc == EC_NOFRAME {
+            self.frames_lost.fetch_add(frames, Ordering::Relaxed);
+            return;
+        }
+        self.frames_received.fetch_add(frames, Ordering::Relaxed);
+        self.rtt_min.fetch_min(rtt, Ordering::Relaxed);
+        self.rtt_max.fetch_max(rtt, Ordering::Relaxed);
+        self.rtt_sum.fetch_add(rtt, Ordering::Relaxed);
+        self.rtt_count.fetch_add(1, Ordering::Relaxed);
+        let bucket = ((rtt / HISTOGRAM_BUCKET_US) as usize).min(HISTOGRAM_BUCKETS - 1);
+        self.histogram[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The end of a cycle of `group`, after any retries.
+    pub(crate) fn record_cycle(&self, group: u8, wkc_ok: bool) {
+        self.cycles.fetch_add(1, Ordering::Relaxed);
+        self.record_group(group, wkc_ok);
+    }
+
+    /// The exchange of a further group in the same cycle.
+    pub(crate) fn record_group(&self, group: u8, wkc_ok: bool) {
+        if !wkc_ok && let Some(counter) = self.wkc_mismatches.get(group as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// An acyclic datagram; `wkc` as returned by SOEM.
+    pub(crate) fn record_datagram(&self, wkc: i32) {
+        self.datagrams.fetch_add(1, Ordering::Relaxed);
+        if wkc == EC_NOFRAME {
+            self.datagrams_lost.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let rtt_count = load(&self.rtt_count);
+        let us = |value: u64| (rtt_count > 0).then(|| Duration::from_micros(value));
+        StatisticsSnapshot {
+            cycles: load(&self.cycles),
+            frames_sent: load(&self.frames_sent),
+            frames_received: load(&self.frames_received),
+            frames_lost: load(&self.frames_lost),
+            retries: load(&self.retries),
+            wkc_mismatches: array::from_fn(|g| load(&self.wkc_mismatches[g])),
+            datagrams: load(&self.datagrams),
+            datagrams_lost: load(&self.datagrams_lost),
+            min_rtt: us(load(&self.rtt_min)),
+            max_rtt: us(load(&self.rtt_max)),
+            mean_rtt: us(load(&self.rtt_sum) / rtt_count.max(1)),
+            histogram: self.histogram.iter().map(load).collect(),
+        }
+    }
+
+    /// Start counting from zero.
+    pub fn reset(&self) {
+        let counters = [
+            &self.cycles,
+            &self.frames_sent,
+            &self.frames_received,
+            &self.frames_lost,
+            &self.retries,
+            &self.datagrams,
+            &self.datagrams_lost,
+            &self.rtt_max,
+            &self.rtt_sum,
+            &self.rtt_count,
+        ];
+        for counter in counters
+            .into_iter()
+            .chain(&self.wkc_mismatches)
+            .chain(&self.histogram)
+        {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.rtt_min.store(u64::MAX, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatisticsSnapshot {
+    /// Process data cycles, counting an exchange and its retries once.
+    pub cycles: u64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    pub frames_lost: u64,
+    /// Process data exchanges repeated after a lost frame.
+    pub retries: u64,
+    /// Cycles whose working counter differed from the expected one, per group.
+    pub wkc_mismatches: [u64; GROUPS],
+    /// Acyclic datagrams, e.g. register reads, and how many of them were lost.
+    pub datagrams: u64,
+    pub datagrams_lost: u64,
+    pub min_rtt: Option<Duration>,
+    pub max_rtt: Option<Duration>,
+    pub mean_rtt: Option<Duration>,
+    /// Process data round trips per `HISTOGRAM_BUCKET_US` wide bucket, the last
+    /// one counting all longer ones.
+    pub histogram: Vec<u64>,
+}
+
+impl StatisticsSnapshot {
+    /// Round trip time that `p` percent of the round trips did not exceed, to the
+    /// upper edge of its histogram bucket. `None` without round trips or if it
+    /// falls into the overflow bucket.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let total: u64 = self.histogram.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((p.clamp(0.0, 100.0) / 100.0 * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        let bucket = self.histogram.iter().position(|&count| {
+            seen += count;
+            seen >= rank
+        })?;
+        (bucket < HISTOGRAM_BUCKETS - 1)
+            .then(|| Duration::from_micros((bucket as u64 + 1) * HISTOGRAM_BUCKET_US))
+    }
+}
+
+impl Fieldbus {
+    /// The statistics of this bus. The handle stays valid and current for as long
+    /// as it is held and can be read from any thread.
+    pub fn statistics(&self) -> Arc<FrameStatistics> {
+        self.statistics.clone()
+    }
+
+    /// Repeat a process data exchange whose frame was lost up to `retries` times
+    /// within the same `roundtrip`. Each retry can take up to the receive timeout.
+    pub fn set_frame_retries(&mut self, retries: u32) {
+        self.frame_retries = retries;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statistics(rtts: &[(u64, u64)]) -> FrameStatistics {
+        let statistics = FrameStatistics::default();
+        for &(rtt, count) in rtts {
+            for _ in 0..count {
+                statistics.record_exchange(1, 3, rtt, false);
+            }
+        }
+        statistics
+    }
+
+    #[test]
+    fn percentiles() {
+        let snapshot = statistics(&[(15, 50), (25, 45), (3000, 5)]).snapshot();
+        let us = |us: u64| Some(Duration::from_micros(us));
+        assert_eq!(snapshot.percentile(0.0), us(20));
+        assert_eq!(snapshot.percentile(50.0), us(20));
+        assert_eq!(snapshot.percentile(50.5), us(30));
+        assert_eq!(snapshot.percentile(95.0), us(30));
+        // in the overflow bucket
+        assert_eq!(snapshot.percentile(95.1), None);
+        assert_eq!(snapshot.percentile(100.0), None);
+        assert_eq!(snapshot.percentile(-1.0), us(20));
+
+        assert_eq!(statistics(&[(0, 1)]).snapshot().percentile(99.0), us(10));
+        assert_eq!(StatisticsSnapshot::default().percentile(50.0), None);
+    }
+
+    #[test]
+    fn round_trip_times() {
+        let statistics = statistics(&[(15, 2), (45, 1)]);
+        statistics.record_exchange(2, EC_NOFRAME, 0, true);
+        statistics.record_cycle(0, false);
+        statistics.record_datagram(EC_NOFRAME);
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.frames_sent, 5);
+        assert_eq!(snapshot.frames_received, 3);
+        assert_eq!(snapshot.frames_lost, 2);
+        assert_eq!(snapshot.retries, 1);
+        assert_eq!(snapshot.cycles, 1);
+        assert_eq!(snapshot.wkc_mismatches[0], 1);
+        assert_eq!((snapshot.datagrams, snapshot.datagrams_lost), (1, 1));
+        assert_eq!(snapshot.min_rtt, Some(Duration::from_micros(15)));
+        assert_eq!(snapshot.max_rtt, Some(Duration::from_micros(45)));
+        assert_eq!(snapshot.mean_rtt, Some(Duration::from_micros(25)));
+        assert_eq!(snapshot.histogram.len(), HISTOGRAM_BUCKETS);
+
+        statistics.reset();
+        let snapshot = statistics.snapshot();
+        assert_eq!(
+            snapshot,
+            StatisticsSnapshot {
+                histogram: vec![0; HISTOGRAM_BUCKETS],
+                ..Default::default()
+            }
+        );
+    }
+}
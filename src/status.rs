@@ -0,0 +1,335 @@
+//! AL status codes (ETG.1000.6) and SDO abort codes (ETG.1000.6 / CiA 301) as
+//! Rust types, replacing `ec_ALstatuscode2string` and `ec_sdoerror2string`.
+
+use std::fmt;
+
+use crate::simple_ng::Fieldbus;
+
+/// Rough cause of an error, for deciding what to show or do about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    /// Wrong or inconsistent settings from the master: SyncManagers, mapping,
+    /// objects, values.
+    Configuration,
+    Watchdog,
+    /// Distributed clock or synchronisation problems.
+    Sync,
+    /// Mailbox and SDO protocol problems.
+    Mailbox,
+    /// The state machine was used in a way the slave does not accept.
+    State,
+    /// Hardware, firmware, EEPROM or supply problems of the slave.
+    Device,
+    General,
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Category::Configuration => "configuration",
+            Category::Watchdog => "watchdog",
+            Category::Sync => "sync",
+            Category::Mailbox => "mailbox",
+            Category::State => "state",
+            Category::Device => "device",
+            Category::General => "general",
+        })
+    }
+}
+
+/// Declare a code enum from a table of `Variant = code, category, retryable, text`.
+/// Codes not in the table map to `Unknown`.
+macro_rules! code_table {
+    (
+        $(#[$meta:meta])*
+        $name:ident($ty:ty) {
+            $($variant:ident = $code:literal, $category:ident, $retry:literal, $text:literal;)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($variant,)*
+            Unknown($ty),
+        }
+
+        impl $name {
+            pub fn code(self) -> $ty {
+                match self {
+                    $($name::$variant => $code,)*
+                    $name::Unknown(code) => code,
+                }
+            }
+
+            fn entry(self) -> Option<(Category, bool, &'static str)> {
+                match self {
+                    $($name::$variant => Some((Category::$category, $retry, $text)),)*
+                    $name::Unknown(_) => None,
+                }
+            }
+        }
+
+        impl From<$ty> for $name {
+            fn from(code: $ty) -> Self {
+                match code {
+                    $($code => $name::$variant,)*
+                    code => $name::Unknown(code),
+                }
+            }
+        }
+
+        impl From<$name> for $ty {
+            fn from(code: $name) -> Self {
+                code.code()
+            }
+        }
+    };
+}
+
+code_table! {
+    /// Value of the AL status code register (0x0134), explaining why a slave
+    /// refused or left a state.
+    AlStatusCode(u16) {
+        NoError = 0x0000, General, false, "No error";
+        UnspecifiedError = 0x0001, General, false, "Unspecified error";
+        NoMemory = 0x0002, Device, false, "No memory";
+        InvalidDeviceSetup = 0x0003, Configuration, false, "Invalid device setup";
+        InvalidRevision = 0x0004, Configuration, false, "Invalid revision";
+        SiiMismatch = 0x0006, Device, false, "SII/EEPROM information does not match firmware";
+        FirmwareUpdateFailed = 0x0007, Device, false,
+            "Firmware update not successful, old firmware still running";
+        LicenseError = 0x000E, Device, false, "License error";
+        InvalidStateChange = 0x0011, State, false, "Invalid requested state change";
+        UnknownState = 0x0012, State, false, "Unknown requested state";
+        BootstrapNotSupported = 0x0013, State, false, "Bootstrap not supported";
+        NoValidFirmware = 0x0014, Device, false, "No valid firmware";
+        InvalidMailboxConfigBoot = 0x0015, Mailbox, false, "Invalid mailbox configuration (BOOT)";
+        InvalidMailboxConfig = 0x0016, Mailbox, false, "Invalid mailbox configuration (PREOP)";
+        InvalidSyncManagerConfig = 0x0017, Configuration, false,
+            "Invalid sync manager configuration";
+        NoValidInputs = 0x0018, Watchdog, true, "No valid inputs available";
+        NoValidOutputs = 0x0019, Watchdog, true, "No valid outputs";
+        SynchronizationError = 0x001A, Sync, true, "Synchronization error";
+        SyncManagerWatchdog = 0x001B, Watchdog, true, "Sync manager watchdog";
+        InvalidSyncManagerTypes = 0x001C, Configuration, false, "Invalid sync manager types";
+        InvalidOutputConfig = 0x001D, Configuration, false, "Invalid output configuration";
+        InvalidInputConfig = 0x001E, Configuration, false, "Invalid input configuration";
+        InvalidWatchdogConfig = 0x001F, Watchdog, false, "Invalid watchdog configuration";
+        NeedsColdStart = 0x0020, State, false, "Slave needs cold start";
+        NeedsInit = 0x0021, State, true, "Slave needs INIT";
+        NeedsPreOp = 0x0022, State, true, "Slave needs PREOP";
+        NeedsSafeOp = 0x0023, State, true, "Slave needs SAFEOP";
+        InvalidInputMapping = 0x0024, Configuration, false, "Invalid input mapping";
+        InvalidOutputMapping = 0x0025, Configuration, false, "Invalid output mapping";
+        InconsistentSettings = 0x0026, Configuration, false, "Inconsistent settings";
+        FreerunNotSupported = 0x0027, Sync, false, "Freerun not supported";
+        SyncNotSupported = 0x0028, Sync, false, "Synchronization not supported";
+        FreerunNeeds3Buffer = 0x0029, Configuration, false, "Freerun needs 3-buffer mode";
+        BackgroundWatchdog = 0x002A, Watchdog, true, "Background watchdog";
+        NoValidInputsOutputs = 0x002B, Watchdog, true, "No valid inputs and outputs";
+        FatalSyncError = 0x002C, Sync, true, "Fatal sync error";
+        NoSyncError = 0x002D, Sync, true, "No sync error (sync signal not received)";
+        CycleTimeTooSmall = 0x002E, Sync, false, "Cycle time too small";
+        InvalidDcSyncConfig = 0x0030, Sync, false, "Invalid DC SYNC configuration";
+        InvalidDcLatchConfig = 0x0031, Sync, false, "Invalid DC latch configuration";
+        PllError = 0x0032, Sync, true, "PLL error";
+        DcSyncIoError = 0x0033, Sync, true, "DC sync IO error";
+        DcSyncTimeout = 0x0034, Sync, true, "DC sync timeout error";
+        DcInvalidSyncCycleTime = 0x0035, Sync, false, "DC invalid sync cycle time";
+        DcInvalidSync0CycleTime = 0x0036, Sync, false, "DC invalid sync0 cycle time";
+        DcInvalidSync1CycleTime = 0x0037, Sync, false, "DC invalid sync1 cycle time";
+        MailboxAoe = 0x0041, Mailbox, false, "Mailbox AoE error";
+        MailboxEoe = 0x0042, Mailbox, false, "Mailbox EoE error";
+        MailboxCoe = 0x0043, Mailbox, false, "Mailbox CoE error";
+        MailboxFoe = 0x0044, Mailbox, false, "Mailbox FoE error";
+        MailboxSoe = 0x0045, Mailbox, false, "Mailbox SoE error";
+        MailboxVoe = 0x004F, Mailbox, false, "Mailbox VoE error";
+        EepromNoAccess = 0x0050, Device, true, "EEPROM no access";
+        EepromError = 0x0051, Device, false, "EEPROM error";
+        ExternalHardwareNotReady = 0x0052, Device, true, "External hardware not ready";
+        RestartedLocally = 0x0060, State, true, "Slave restarted locally";
+        DeviceIdentificationUpdated = 0x0061, Configuration, true,
+            "Device identification value updated";
+        ModuleIdentListMismatch = 0x0070, Configuration, false,
+            "Detected module ident list does not match";
+        SupplyVoltageTooLow = 0x0080, Device, true, "Supply voltage too low";
+        SupplyVoltageTooHigh = 0x0081, Device, true, "Supply voltage too high";
+        TemperatureTooLow = 0x0082, Device, true, "Temperature too low";
+        TemperatureTooHigh = 0x0083, Device, true, "Temperature too high";
+        ApplicationControllerAvailable = 0x00F0, State, true, "Application controller available";
+    }
+}
+
+impl AlStatusCode {
+    /// Codes from 0x8000 on are defined by the vendor.
+    pub fn is_vendor_specific(self) -> bool {
+        self.code() >= 0x8000
+    }
+
+    pub fn description(self) -> &'static str {
+        match self.entry() {
+            Some((_, _, text)) => text,
+            None if self.is_vendor_specific() => "Vendor specific error",
+            None => "Unknown AL status code",
+        }
+    }
+
+    pub fn category(self) -> Category {
+        self.entry()
+            .map_or(Category::General, |(category, _, _)| category)
+    }
+
+    /// Whether acknowledging the error and requesting the state again may succeed
+    /// without changing the configuration.
+    pub fn is_retryable(self) -> bool {
+        self.entry().is_some_and(|(_, retry, _)| retry)
+    }
+}
+
+impl fmt::Display for AlStatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (AL status {:04x})", self.description(), self.code())
+    }
+}
+
+code_table! {
+    /// Reason given by a slave for aborting an SDO transfer.
+    SdoAbortCode(u32) {
+        NoError = 0x00000000, General, false, "No error";
+        ToggleBit = 0x05030000, Mailbox, true, "Toggle bit not changed";
+        Timeout = 0x05040000, Mailbox, true, "SDO protocol timeout";
+        InvalidCommand = 0x05040001, Mailbox, false,
+            "Client/server command specifier not valid or unknown";
+        InvalidBlockSize = 0x05040002, Mailbox, false, "Invalid block size";
+        InvalidSequenceNumber = 0x05040003, Mailbox, false, "Invalid sequence number";
+        CrcError = 0x05040004, Mailbox, true, "CRC error";
+        OutOfMemory = 0x05040005, Mailbox, true, "Out of memory";
+        UnsupportedAccess = 0x06010000, Configuration, false, "Unsupported access to an object";
+        WriteOnly = 0x06010001, Configuration, false, "Attempt to read a write only object";
+        ReadOnly = 0x06010002, Configuration, false, "Attempt to write a read only object";
+        SubindexNotWritable = 0x06010003, Configuration, false,
+            "Subindex cannot be written, SI0 must be 0 for write access";
+        CompleteAccessNotSupported = 0x06010004, Configuration, false,
+            "SDO complete access not supported for variable length objects";
+        ObjectTooLong = 0x06010005, Mailbox, false, "Object length exceeds mailbox size";
+        MappedToRxPdo = 0x06010006, State, false, "Object mapped to RxPDO, SDO download blocked";
+        ObjectDoesNotExist = 0x06020000, Configuration, false,
+            "The object does not exist in the object directory";
+        NotMappable = 0x06040041, Configuration, false, "The object cannot be mapped into the PDO";
+        PdoLengthExceeded = 0x06040042, Configuration, false,
+            "The number and length of the objects to be mapped would exceed the PDO length";
+        ParameterIncompatible = 0x06040043, Configuration, false,
+            "General parameter incompatibility reason";
+        InternalIncompatible = 0x06040047, Device, false,
+            "General internal incompatibility in the device";
+        HardwareError = 0x06060000, Device, true, "Access failed due to a hardware error";
+        LengthMismatch = 0x06070010, Configuration, false,
+            "Data type does not match, length of service parameter does not match";
+        LengthTooHigh = 0x06070012, Configuration, false,
+            "Data type does not match, length of service parameter too high";
+        LengthTooLow = 0x06070013, Configuration, false,
+            "Data type does not match, length of service parameter too low";
+        SubindexDoesNotExist = 0x06090011, Configuration, false, "Subindex does not exist";
+        ValueRangeExceeded = 0x06090030, Configuration, false,
+            "Value range of parameter exceeded (only for write access)";
+        ValueTooHigh = 0x06090031, Configuration, false, "Value of parameter written too high";
+        ValueTooLow = 0x06090032, Configuration, false, "Value of parameter written too low";
+        MaxLessThanMin = 0x06090036, Configuration, false, "Maximum value is less than minimum value";
+        GeneralError = 0x08000000, General, false, "General error";
+        TransferFailed = 0x08000020, State, true,
+            "Data cannot be transferred or stored to the application";
+        LocalControl = 0x08000021, State, true,
+            "Data cannot be transferred or stored to the application because of local control";
+        DeviceState = 0x08000022, State, true,
+            "Data cannot be transferred or stored to the application because of the present device state";
+        NoObjectDictionary = 0x08000023, Device, false,
+            "Object dictionary dynamic generation fails or no object dictionary is present";
+    }
+}
+
+impl SdoAbortCode {
+    pub fn description(self) -> &'static str {
+        self.entry()
+            .map_or("Unknown SDO abort code", |(_, _, text)| text)
+    }
+
+    pub fn category(self) -> Category {
+        self.entry()
+            .map_or(Category::General, |(category, _, _)| category)
+    }
+
+    /// Whether repeating the same transfer later may succeed.
+    pub fn is_retryable(self) -> bool {
+        self.entry().is_some_and(|(_, retry, _)| retry)
+    }
+}
+
+impl fmt::Display for SdoAbortCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (SDO abort {:08x})", self.description(), self.code())
+    }
+}
+
+impl Fieldbus {
+    /// AL status code of a slave as of the last state read (`ecx_readstate` or
+    /// `ecx_statecheck`).
+    pub fn al_status_code(&self, slave: u16) -> AlStatusCode {
+        AlStatusCode::from(self.context.slavelist[slave as usize].ALstatuscode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn al_status_codes() {
+        let code = AlStatusCode::from(0x001B);
+        assert_eq!(code, AlStatusCode::SyncManagerWatchdog);
+        assert_eq!(u16::from(code), 0x001B);
+        assert_eq!(code.category(), Category::Watchdog);
+        assert!(code.is_retryable());
+        assert_eq!(code.to_string(), "Sync manager watchdog (AL status 001b)");
+
+        let code = AlStatusCode::from(0x0017);
+        assert_eq!(code, AlStatusCode::InvalidSyncManagerConfig);
+        assert_eq!(code.category(), Category::Configuration);
+        assert!(!code.is_retryable());
+    }
+
+    #[test]
+    fn unknown_al_status_codes() {
+        let code = AlStatusCode::from(0x0008);
+        assert_eq!(code, AlStatusCode::Unknown(0x0008));
+        assert_eq!(code.code(), 0x0008);
+        assert_eq!(code.description(), "Unknown AL status code");
+        assert_eq!(code.category(), Category::General);
+        assert!(!code.is_retryable());
+        assert!(!code.is_vendor_specific());
+
+        let code = AlStatusCode::from(0x8001);
+        assert!(code.is_vendor_specific());
+        assert_eq!(code.description(), "Vendor specific error");
+    }
+
+    #[test]
+    fn sdo_abort_codes() {
+        let code = SdoAbortCode::from(0x06020000);
+        assert_eq!(code, SdoAbortCode::ObjectDoesNotExist);
+        assert_eq!(u32::from(code), 0x06020000);
+        assert_eq!(code.category(), Category::Configuration);
+        assert!(!code.is_retryable());
+
+        let code = SdoAbortCode::from(0x08000022);
+        assert_eq!(code, SdoAbortCode::DeviceState);
+        assert_eq!(code.category(), Category::State);
+        assert!(code.is_retryable());
+
+        let code = SdoAbortCode::from(0x12345678);
+        assert_eq!(code, SdoAbortCode::Unknown(0x12345678));
+        assert_eq!(
+            code.to_string(),
+            "Unknown SDO abort code (SDO abort 12345678)"
+        );
+    }
+}
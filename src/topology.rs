@@ -0,0 +1,374 @@
+//! The physical network as a graph: slaves are nodes and cables are edges between
+//! ports, built from the topology SOEM detects during `config_init` and the DL
+//! status (0x0110) of each slave.
+
+use std::{ffi::CStr, fmt::Write};
+
+use crate::registers::{DlStatus, PortStatus};
+use crate::simple_ng::Fieldbus;
+
+/// State of one ESC port.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Port {
+    /// SOEM saw a link on this port during configuration.
+    pub active: bool,
+    /// Current DL status, if the slave could be read.
+    pub status: Option<PortStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopologyNode {
+    pub slave: u16,
+    pub name: String,
+    pub station: u16,
+    pub alias: u16,
+    /// Number of active ports: 1 at the end of a line, 2 in a line, 3 or 4 at a
+    /// junction.
+    pub links: u8,
+    /// Port the frame enters from the master side.
+    pub entry_port: u8,
+    pub ports: [Port; 4],
+}
+
+impl TopologyNode {
+    pub fn is_junction(&self) -> bool {
+        self.links > 2
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.links <= 1
+    }
+}
+
+/// A cable from a port of `parent` (0 for the master) to the entry port of `slave`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopologyLink {
+    pub parent: u16,
+    pub parent_port: u8,
+    pub slave: u16,
+    pub entry_port: u8,
+    /// Both ends report a physical link; `None` if neither end could be read.
+    pub up: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Topology {
+    pub nodes: Vec<TopologyNode>,
+    pub links: Vec<TopologyLink>,
+}
+
+fn port_state(status: &Option<PortStatus>) -> &'static str {
+    match status {
+        None => "unknown",
+        Some(s) if !s.link => "down",
+        Some(s) if s.loop_closed => "closed",
+        Some(_) => "open",
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Topology {
+    pub fn node(&self, slave: u16) -> Option<&TopologyNode> {
+        self.nodes.iter().find(|n| n.slave == slave)
+    }
+
+    /// Slaves connected to ports of `slave` other than its entry port, in port
+    /// order. 0 gives the slave(s) connected to the master.
+    pub fn children(&self, slave: u16) -> Vec<&TopologyLink> {
+        let mut children: Vec<_> = self.links.iter().filter(|l| l.parent == slave).collect();
+        children.sort_by_key(|l| l.parent_port);
+        children
+    }
+
+    /// Graphviz DOT, with ports as edge labels and down links drawn dashed red.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph ethercat {\n    rankdir=LR;\n");
+        out.push_str("    master [shape=box, style=bold, label=\"master\"];\n");
+        for node in &self.nodes {
+            let ports: Vec<String> = node
+                .ports
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| p.active || p.status.is_some_and(|s| s.link))
+                .map(|(n, p)| format!("{}: {}", n, port_state(&p.status)))
+                .collect();
+            let _ = writeln!(
+                out,
+                "    slave{} [shape={}, label=\"{}\\n{}\\nstation 0x{:04x}\\n{}\"];",
+                node.slave,
+                if node.is_junction() { "diamond" } else { "box" },
+                node.slave,
+                node.name.replace('\\', "\\\\").replace('"', "\\\""),
+                node.station,
+                ports.join(", ")
+            );
+        }
+        for link in &self.links {
+            let from = if link.parent == 0 {
+                "master".to_string()
+            } else {
+                format!("slave{}", link.parent)
+            };
+            let style = match link.up {
+                Some(false) => ", style=dashed, color=red",
+                _ => "",
+            };
+            let _ = writeln!(
+                out,
+                "    {} -> slave{} [taillabel=\"{}\", headlabel=\"{}\"{}];",
+                from,
+                link.slave,
+                if link.parent == 0 {
+                    String::new()
+                } else {
+                    link.parent_port.to_string()
+                },
+                link.entry_port,
+                style
+            );
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let nodes: Vec<String> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let ports: Vec<String> = node
+                    .ports
+                    .iter()
+                    .enumerate()
+                    .map(|(n, p)| {
+                        format!(
+                            "{{\"port\":{},\"active\":{},\"state\":\"{}\"}}",
+                            n,
+                            p.active,
+                            port_state(&p.status)
+                        )
+                    })
+                    .collect();
+                format!(
+                    "{{\"slave\":{},\"name\":{},\"station\":{},\"alias\":{},\"links\":{},\
+                     \"entry_port\":{},\"ports\":[{}]}}",
+                    node.slave,
+                    json_string(&node.name),
+                    node.station,
+                    node.alias,
+                    node.links,
+                    node.entry_port,
+                    ports.join(",")
+                )
+            })
+            .collect();
+        let links: Vec<String> = self
+            .links
+            .iter()
+            .map(|link| {
+                format!(
+                    "{{\"parent\":{},\"parent_port\":{},\"slave\":{},\"entry_port\":{},\"up\":{}}}",
+                    link.parent,
+                    link.parent_port,
+                    link.slave,
+                    link.entry_port,
+                    link.up.map_or("null".to_string(), |up| up.to_string())
+                )
+            })
+            .collect();
+        format!(
+            "{{\"nodes\":[{}],\"links\":[{}]}}",
+            nodes.join(","),
+            links.join(",")
+        )
+    }
+}
+
+impl Fieldbus {
+    /// Build the topology graph from the configured slaves, reading the current DL
+    /// status of each.
+    pub fn topology(&mut self) -> Topology {
+        let mut topology = Topology::default();
+        for slave in 1..=self.context.slavecount.max(0) as u16 {
+            let status = self.read_register::<DlStatus>(slave).ok();
+            let s = &self.context.slavelist[slave as usize];
+            let name = unsafe { CStr::from_ptr(s.name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned();
+            let mut ports = [Port::default(); 4];
+            for (n, port) in ports.iter_mut().enumerate() {
+                port.active = s.activeports & (1 << n) != 0;
+                port.status = status.map(|status| status.ports[n]);
+            }
+            topology.nodes.push(TopologyNode {
+                slave,
+                name,
+                station: s.configadr,
+                alias: s.aliasadr,
+                links: s.topology,
+                entry_port: s.entryport,
+                ports,
+            });
+        }
+
+        for node in &topology.nodes {
+            let s = &self.context.slavelist[node.slave as usize];
+            let downstream = node.ports[node.entry_port as usize & 3].status;
+            let upstream = topology
+                .node(s.parent)
+                .and_then(|p| p.ports[s.parentport as usize & 3].status);
+            let up = match (downstream, upstream) {
+                (None, None) => None,
+                (d, u) => Some(d.is_none_or(|d| d.link) && u.is_none_or(|u| u.link)),
+            };
+            topology.links.push(TopologyLink {
+                parent: s.parent,
+                parent_port: s.parentport,
+                slave: node.slave,
+                entry_port: node.entry_port,
+                up,
+            });
+        }
+        topology
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(link: bool, loop_closed: bool) -> Port {
+        Port {
+            active: link,
+            status: Some(PortStatus {
+                link,
+                loop_closed,
+                communication: link,
+            }),
+        }
+    }
+
+    fn node(slave: u16, name: &str, links: u8, ports: [Port; 4]) -> TopologyNode {
+        TopologyNode {
+            slave,
+            name: name.to_string(),
+            station: 0x1000 + slave,
+            alias: 0,
+            links,
+            entry_port: 0,
+            ports,
+        }
+    }
+
+    fn link(parent: u16, parent_port: u8, slave: u16, up: Option<bool>) -> TopologyLink {
+        TopologyLink {
+            parent,
+            parent_port,
+            slave,
+            entry_port: 0,
+            up,
+        }
+    }
+
+    /// master - 1 (junction) - 2 on port 1, 3 on port 3 with its cable down.
+    fn sample() -> Topology {
+        let closed = port(false, true);
+        Topology {
+            nodes: vec![
+                node(
+                    1,
+                    "EK1122",
+                    3,
+                    [
+                        port(true, false),
+                        port(true, false),
+                        closed,
+                        port(false, true),
+                    ],
+                ),
+                node(
+                    2,
+                    "say \"hi\"\\\n",
+                    1,
+                    [port(true, false), closed, closed, closed],
+                ),
+                node(3, "EL1004", 1, [port(false, true), closed, closed, closed]),
+            ],
+            links: vec![
+                link(0, 0, 1, Some(true)),
+                link(1, 3, 3, Some(false)),
+                link(1, 1, 2, Some(true)),
+            ],
+        }
+    }
+
+    #[test]
+    fn nodes_and_children() {
+        let topology = sample();
+        assert!(topology.node(1).unwrap().is_junction());
+        assert!(topology.node(2).unwrap().is_end());
+        assert!(topology.node(4).is_none());
+        let children: Vec<u16> = topology.children(1).iter().map(|l| l.slave).collect();
+        assert_eq!(children, [2, 3]);
+        assert_eq!(topology.children(0)[0].slave, 1);
+    }
+
+    #[test]
+    fn dot() {
+        let dot = sample().to_dot();
+        assert!(dot.starts_with("digraph ethercat {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains(
+            "    slave1 [shape=diamond, label=\"1\\nEK1122\\nstation 0x1001\\n0: open, 1: open\"];"
+        ));
+        assert!(dot.contains("slave2 [shape=box, label=\"2\\nsay \\\"hi\\\"\\\\\n\\n"));
+        assert!(dot.contains("    master -> slave1 [taillabel=\"\", headlabel=\"0\"];"));
+        assert!(dot.contains("    slave1 -> slave2 [taillabel=\"1\", headlabel=\"0\"];"));
+        assert!(dot.contains(
+            "    slave1 -> slave3 [taillabel=\"3\", headlabel=\"0\", style=dashed, color=red];"
+        ));
+    }
+
+    #[test]
+    fn json() {
+        let json = sample().to_json();
+        assert!(json.starts_with("{\"nodes\":[{\"slave\":1,\"name\":\"EK1122\",\"station\":4097,"));
+        assert!(json.contains(
+            "\"ports\":[{\"port\":0,\"active\":true,\"state\":\"open\"},\
+             {\"port\":1,\"active\":true,\"state\":\"open\"},\
+             {\"port\":2,\"active\":false,\"state\":\"down\"},\
+             {\"port\":3,\"active\":false,\"state\":\"down\"}]"
+        ));
+        assert!(json.contains("\"name\":\"say \\\"hi\\\"\\\\\\u000a\""));
+        assert!(json.contains(
+            "{\"parent\":1,\"parent_port\":3,\"slave\":3,\"entry_port\":0,\"up\":false}"
+        ));
+        let mut topology = sample();
+        topology.links[0].up = None;
+        topology.nodes[0].ports[0].status = None;
+        let json = topology.to_json();
+        assert!(json.contains("\"up\":null"));
+        assert!(json.contains("{\"port\":0,\"active\":true,\"state\":\"unknown\"}"));
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string("a\"b\\c\td"), "\"a\\\"b\\\\c\\u0009d\"");
+        assert_eq!(json_string("\u{e9}"), "\"\u{e9}\"");
+    }
+}
@@ -0,0 +1,165 @@
+//! ESC watchdogs. The process data (SyncManager) watchdog switches the outputs of
+//! a slave to their safe state when no process data arrives in time, the PDI
+//! watchdog watches the slave's own application. SOEM leaves them at the slave
+//! defaults; configured times are written by `start` in PRE_OP.
+
+use std::time::Duration;
+
+use crate::datagram::DatagramError;
+use crate::registers::{PdiWatchdogTime, SmWatchdogTime, WatchdogDivider, WatchdogStatus};
+use crate::simple_ng::Fieldbus;
+
+/// Clock period the divider counts, 25 MHz.
+const WATCHDOG_CLOCK_NS: u64 = 40;
+
+/// Watchdog times; `None` keeps the slave default, zero disables the watchdog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    pub process_data: Option<Duration>,
+    pub pdi: Option<Duration>,
+    /// Resolution of both times, 100 us by default. Times are rounded up to it
+    /// and can be at most 65535 units.
+    pub unit: Duration,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            process_data: None,
+            pdi: None,
+            unit: Duration::from_micros(100),
+        }
+    }
+}
+
+impl WatchdogConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn process_data(mut self, time: Duration) -> Self {
+        self.process_data = Some(time);
+        self
+    }
+
+    pub fn pdi(mut self, time: Duration) -> Self {
+        self.pdi = Some(time);
+        self
+    }
+
+    pub fn unit(mut self, unit: Duration) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Divider register value: the unit in 40 ns clocks minus 2.
+    fn divider(&self) -> u16 {
+        let clocks = self.unit.as_nanos().min(u64::MAX as u128) as u64 / WATCHDOG_CLOCK_NS;
+        (clocks.clamp(2, u16::MAX as u64 + 2) - 2) as u16
+    }
+
+    /// A time in units of the divider.
+    fn units(&self, time: Duration) -> u16 {
+        let unit = ((self.divider() as u64 + 2) * WATCHDOG_CLOCK_NS) as u128;
+        time.as_nanos().div_ceil(unit).min(u16::MAX as u128) as u16
+    }
+}
+
+impl Fieldbus {
+    /// Write a watchdog configuration to a slave.
+    pub fn configure_watchdog(
+        &mut self,
+        slave: u16,
+        config: &WatchdogConfig,
+    ) -> Result<(), DatagramError> {
+        if config.process_data.is_none() && config.pdi.is_none() {
+            return Ok(());
+        }
+        self.write_register(slave, &WatchdogDivider(config.divider()))?;
+        if let Some(time) = config.process_data {
+            self.write_register(slave, &SmWatchdogTime(config.units(time)))?;
+        }
+        if let Some(time) = config.pdi {
+            self.write_register(slave, &PdiWatchdogTime(config.units(time)))?;
+        }
+        Ok(())
+    }
+
+    /// Write the watchdog settings of the bus configuration, per slave where set
+    /// and bus-wide otherwise. Returns false if a slave could not be configured.
+    pub(crate) fn apply_watchdogs(&mut self) -> bool {
+        let Some(bus) = self.bus_config.as_ref() else {
+            return true;
+        };
+        let default = bus.watchdog;
+        let mut plan: Vec<(u16, WatchdogConfig)> = match default {
+            Some(config) => (1..=self.context.slavecount.max(0) as u16)
+                .map(|slave| (slave, config))
+                .collect(),
+            None => Vec::new(),
+        };
+        for (slave, config) in self.slave_configs() {
+            if let Some(watchdog) = config.watchdog {
+                plan.retain(|&(s, _)| s != slave);
+                plan.push((slave, watchdog));
+            }
+        }
+        plan.retain(|(slave, _)| !self.excluded.contains(slave));
+
+        let mut ok = true;
+        for (slave, config) in plan {
+            if let Err(e) = self.configure_watchdog(slave, &config) {
+                log::warn!("Failed to configure watchdogs of slave {}: {}", slave, e);
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    pub fn watchdog_status(&mut self, slave: u16) -> Result<WatchdogStatus, DatagramError> {
+        self.read_register(slave)
+    }
+
+    /// Slaves with outputs in the process data whose process data watchdog has
+    /// expired, so their outputs are in the safe state. Costs one frame per slave.
+    pub fn expired_watchdogs(&mut self) -> Vec<(u16, WatchdogStatus)> {
+        let slaves: Vec<u16> = (1..=self.context.slavecount.max(0) as u16)
+            .filter(|&slave| {
+                self.context.slavelist[slave as usize].Obits > 0 && !self.excluded.contains(&slave)
+            })
+            .collect();
+        slaves
+            .into_iter()
+            .filter_map(|slave| {
+                self.watchdog_status(slave)
+                    .ok()
+                    .filter(|status| !status.process_data_ok)
+                    .map(|status| (slave, status))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divider() {
+        let config = WatchdogConfig::new();
+        // 100 us: 2500 clocks
+        assert_eq!(config.divider(), 2498);
+        assert_eq!(config.units(Duration::from_millis(100)), 1000);
+        assert_eq!(config.units(Duration::from_micros(150)), 2);
+        assert_eq!(config.units(Duration::ZERO), 0);
+
+        assert_eq!(config.unit(Duration::ZERO).divider(), 0);
+        assert_eq!(config.unit(Duration::from_nanos(80)).divider(), 0);
+        // the largest unit, 65537 clocks or about 2.62 ms
+        let largest = config.unit(Duration::from_nanos(65537 * 40));
+        assert_eq!(largest.divider(), u16::MAX);
+        assert_eq!(config.unit(Duration::from_millis(3)).divider(), u16::MAX);
+        assert_eq!(config.unit(Duration::from_secs(1)).divider(), u16::MAX);
+        assert_eq!(largest.units(Duration::from_secs(1000)), u16::MAX);
+    }
+}
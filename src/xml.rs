@@ -0,0 +1,269 @@
+//! Small helpers shared by the ESI and ENI readers and writers.
+
+use std::fmt;
+
+use roxmltree::Node;
+
+#[derive(Debug)]
+pub enum XmlError {
+    Parse(roxmltree::Error),
+    /// A required element or attribute is missing below the named element.
+    Missing {
+        parent: String,
+        name: String,
+    },
+    /// A value could not be interpreted as the expected type.
+    InvalidValue {
+        name: String,
+        value: String,
+    },
+}
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XmlError::Parse(e) => write!(f, "XML parse error: {}", e),
+            XmlError::Missing { parent, name } => write!(f, "<{}> is missing {}", parent, name),
+            XmlError::InvalidValue { name, value } => {
+                write!(f, "invalid value {:?} for {}", value, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for XmlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            XmlError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<roxmltree::Error> for XmlError {
+    fn from(e: roxmltree::Error) -> Self {
+        XmlError::Parse(e)
+    }
+}
+
+/// Decode file contents, falling back to ISO-8859-1 which many vendor files use.
+pub(crate) fn decode(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    }
+}
+
+/// Parse an EtherCAT XML number: decimal, `#x` prefixed hex or `0x` prefixed hex.
+pub(crate) fn parse_number(name: &str, value: &str) -> Result<i64, XmlError> {
+    let v = value.trim();
+    let parsed = if let Some(hex) = v.strip_prefix("#x").or_else(|| v.strip_prefix("0x")) {
+        i64::from_str_radix(hex, 16)
+    } else {
+        v.parse::<i64>()
+    };
+    parsed.map_err(|_| XmlError::InvalidValue {
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+}
+
+pub(crate) fn parse_hex_binary(name: &str, value: &str) -> Result<Vec<u8>, XmlError> {
+    let v: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    let invalid = || XmlError::InvalidValue {
+        name: name.to_string(),
+        value: value.to_string(),
+    };
+    if !v.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..v.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&v[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+pub(crate) fn child<'a, 'i>(node: Node<'a, 'i>, name: &str) -> Option<Node<'a, 'i>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+pub(crate) fn children<'a, 'i: 'a>(
+    node: Node<'a, 'i>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'i>> + 'a {
+    node.children().filter(move |n| n.has_tag_name(name))
+}
+
+pub(crate) fn required<'a, 'i>(node: Node<'a, 'i>, name: &str) -> Result<Node<'a, 'i>, XmlError> {
+    child(node, name).ok_or_else(|| XmlError::Missing {
+        parent: node.tag_name().name().to_string(),
+        name: format!("<{}>", name),
+    })
+}
+
+pub(crate) fn text<'a>(node: Node<'a, '_>) -> &'a str {
+    node.text().map(str::trim).unwrap_or("")
+}
+
+pub(crate) fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).map(text)
+}
+
+pub(crate) fn child_number(node: Node, name: &str) -> Result<Option<i64>, XmlError> {
+    child_text(node, name)
+        .map(|t| parse_number(name, t))
+        .transpose()
+}
+
+pub(crate) fn required_number(node: Node, name: &str) -> Result<i64, XmlError> {
+    parse_number(name, text(required(node, name)?))
+}
+
+pub(crate) fn attr_number(node: Node, name: &str) -> Result<Option<i64>, XmlError> {
+    node.attribute(name)
+        .map(|v| parse_number(name, v))
+        .transpose()
+}
+
+pub(crate) fn attr_bool(node: Node, name: &str) -> bool {
+    matches!(node.attribute(name), Some("1") | Some("true"))
+}
+
+/// Escape text for element content and attribute values.
+pub(crate) fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub(crate) fn hex_binary(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Minimal indenting XML writer used for the ENI export.
+pub(crate) struct XmlWriter {
+    out: String,
+    stack: Vec<String>,
+}
+
+impl XmlWriter {
+    pub(crate) fn new() -> Self {
+        XmlWriter {
+            out: String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n"),
+            stack: Vec::new(),
+        }
+    }
+
+    fn indent(&mut self) {
+        for _ in 0..self.stack.len() {
+            self.out.push_str("  ");
+        }
+    }
+
+    fn tag(name: &str, attrs: &[(&str, String)]) -> String {
+        let mut tag = String::from(name);
+        for (key, value) in attrs {
+            tag.push_str(&format!(" {}=\"{}\"", key, escape(value)));
+        }
+        tag
+    }
+
+    pub(crate) fn open(&mut self, name: &str, attrs: &[(&str, String)]) {
+        self.indent();
+        self.out
+            .push_str(&format!("<{}>\n", Self::tag(name, attrs)));
+        self.stack.push(name.to_string());
+    }
+
+    pub(crate) fn close(&mut self) {
+        let name = self.stack.pop().expect("unbalanced XML writer");
+        self.indent();
+        self.out.push_str(&format!("</{}>\n", name));
+    }
+
+    pub(crate) fn element(&mut self, name: &str, attrs: &[(&str, String)], text: &str) {
+        self.indent();
+        self.out.push_str(&format!(
+            "<{}>{}</{}>\n",
+            Self::tag(name, attrs),
+            escape(text),
+            name
+        ));
+    }
+
+    pub(crate) fn text(&mut self, name: &str, text: impl std::fmt::Display) {
+        self.element(name, &[], &text.to_string());
+    }
+
+    pub(crate) fn finish(mut self) -> String {
+        while !self.stack.is_empty() {
+            self.close();
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("n", "42").unwrap(), 42);
+        assert_eq!(parse_number("n", " #x1A00 ").unwrap(), 0x1A00);
+        assert_eq!(parse_number("n", "0xff").unwrap(), 255);
+        assert_eq!(parse_number("n", "-5").unwrap(), -5);
+        assert!(matches!(
+            parse_number("n", "#xZZ"),
+            Err(XmlError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn hex_binary_round_trip() {
+        let data = parse_hex_binary("Data", "01 ab\nFF").unwrap();
+        assert_eq!(data, [0x01, 0xab, 0xff]);
+        assert_eq!(hex_binary(&data), "01abff");
+        assert!(parse_hex_binary("Data", "abc").is_err());
+        assert!(parse_hex_binary("Data", "zz").is_err());
+    }
+
+    #[test]
+    fn latin1_fallback() {
+        assert_eq!(decode(b"M\xfcller".to_vec()), "Müller");
+        assert_eq!(decode("Müller".as_bytes().to_vec()), "Müller");
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape("a<b & \"c\"'"), "a&lt;b &amp; &quot;c&quot;&apos;");
+    }
+
+    #[test]
+    fn writer_output_parses() {
+        let mut w = XmlWriter::new();
+        w.open("Root", &[("Version", "1.0".to_string())]);
+        w.text("Name", "a & b");
+        w.element("Empty", &[("Id", "#x1".to_string())], "");
+        w.close();
+        let text = w.finish();
+        let doc = roxmltree::Document::parse(&text).unwrap();
+        let root = doc.root_element();
+        assert_eq!(root.attribute("Version"), Some("1.0"));
+        assert_eq!(child_text(root, "Name"), Some("a & b"));
+        assert_eq!(
+            attr_number(child(root, "Empty").unwrap(), "Id").unwrap(),
+            Some(1)
+        );
+        assert!(required(root, "Missing").is_err());
+    }
+}